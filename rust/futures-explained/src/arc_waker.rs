@@ -0,0 +1,167 @@
+// Building a Waker from an Arc with a hand-written RawWakerVTable.
+//
+// A RawWaker is just a data pointer plus a vtable of four functions, and the
+// Waker calls them without knowing what the pointer is. Here the pointer is an
+// Arc<W> turned into a raw pointer with Arc::into_raw, and every Waker owns
+// exactly one strong count of that Arc:
+// clone: a new Waker needs its own strong count, so the count is incremented.
+// wake: the Waker is consumed, so its strong count is handed over to
+// ArcWake::wake, which drops it when it is done.
+// wake_by_ref: the Waker is kept, so the Arc is only borrowed and the count
+// stays the same.
+// drop: the Waker goes away together with its strong count.
+// Getting any of these wrong either leaks the Arc or frees it while other
+// Wakers still point to it, which is what the tests below check.
+
+use std::{
+    sync::Arc, mem::ManuallyDrop,
+    task::{RawWaker, RawWakerVTable, Waker},
+};
+
+pub trait ArcWake: Send + Sync {
+    fn wake_by_ref(arc_self: &Arc<Self>);
+
+    fn wake(self: Arc<Self>) {
+        Self::wake_by_ref(&self)
+    }
+}
+
+// The vtable of each W is a constant, which is promoted to a static.
+fn vtable<W: ArcWake + 'static>() -> &'static RawWakerVTable {
+    &RawWakerVTable::new(
+        clone_raw::<W>,       // clone
+        wake_raw::<W>,        // wake
+        wake_by_ref_raw::<W>, // wake by ref
+        drop_raw::<W>,        // decrease refcount
+    )
+}
+
+unsafe fn clone_raw<W: ArcWake + 'static>(data: *const ()) -> RawWaker {
+    Arc::increment_strong_count(data as *const W);
+    RawWaker::new(data, vtable::<W>())
+}
+
+unsafe fn wake_raw<W: ArcWake + 'static>(data: *const ()) {
+    let arc = Arc::from_raw(data as *const W);
+    ArcWake::wake(arc);
+}
+
+unsafe fn wake_by_ref_raw<W: ArcWake + 'static>(data: *const ()) {
+    // Rebuild the Arc without taking over the strong count of the Waker.
+    let arc = ManuallyDrop::new(Arc::from_raw(data as *const W));
+    ArcWake::wake_by_ref(&arc);
+}
+
+unsafe fn drop_raw<W: ArcWake + 'static>(data: *const ()) {
+    drop(Arc::from_raw(data as *const W));
+}
+
+// Turn the Arc into a Waker, which takes over the strong count of the Arc.
+pub fn waker<W: ArcWake + 'static>(w: Arc<W>) -> Waker {
+    let raw_waker = RawWaker::new(Arc::into_raw(w) as *const (), vtable::<W>());
+    unsafe { Waker::from_raw(raw_waker) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts its wakeups, and how many times it is dropped.
+    struct CountingWaker {
+        wakes: AtomicUsize,
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl ArcWake for CountingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.wakes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Drop for CountingWaker {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counting_waker() -> (Arc<CountingWaker>, Arc<AtomicUsize>) {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let arc = Arc::new(CountingWaker { wakes: AtomicUsize::new(0), drops: drops.clone() });
+        (arc, drops)
+    }
+
+    // The number of live Wakers pointing to the Arc, not counting the
+    // Arc held by the test itself.
+    fn live_wakers(arc: &Arc<CountingWaker>) -> usize {
+        Arc::strong_count(arc) - 1
+    }
+
+    #[test]
+    fn clone_and_drop_balance() {
+        let (arc, drops) = counting_waker();
+        let waker = waker(arc.clone());
+        assert_eq!(live_wakers(&arc), 1);
+        let cloned = waker.clone();
+        assert_eq!(live_wakers(&arc), 2);
+        drop(waker);
+        assert_eq!(live_wakers(&arc), 1);
+        drop(cloned);
+        assert_eq!(live_wakers(&arc), 0);
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+        drop(arc);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn wake_consumes_and_wake_by_ref_borrows() {
+        let (arc, _) = counting_waker();
+        let waker = waker(arc.clone());
+        waker.wake_by_ref();
+        waker.wake_by_ref();
+        assert_eq!(live_wakers(&arc), 1);
+        let cloned = waker.clone();
+        assert_eq!(live_wakers(&arc), 2);
+        cloned.wake();
+        assert_eq!(live_wakers(&arc), 1);
+        waker.wake();
+        assert_eq!(live_wakers(&arc), 0);
+        assert_eq!(arc.wakes.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn mixed_operations_neither_leak_nor_double_drop() {
+        let (arc, drops) = counting_waker();
+        let mut wakers = vec![waker(arc.clone())];
+        let mut expected_wakes = 0;
+        // Grow and shrink the set of Wakers with every combination of the
+        // four vtable functions.
+        for round in 0..64usize {
+            let picked = round % wakers.len();
+            match round % 4 {
+                0 => wakers.push(wakers[picked].clone()),
+                1 => {
+                    wakers[picked].wake_by_ref();
+                    expected_wakes += 1;
+                }
+                2 if wakers.len() > 1 => {
+                    wakers.swap_remove(picked).wake();
+                    expected_wakes += 1;
+                }
+                _ if wakers.len() > 1 => drop(wakers.swap_remove(picked)),
+                _ => wakers.push(wakers[0].clone()),
+            }
+            assert_eq!(live_wakers(&arc), wakers.len());
+        }
+        assert_eq!(arc.wakes.load(Ordering::SeqCst), expected_wakes);
+
+        // Moving the Wakers to other threads does not change the counts.
+        let handles: Vec<_> = wakers.drain(..).map(|waker| {
+            std::thread::spawn(move || waker.wake())
+        }).collect();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+        assert_eq!(live_wakers(&arc), 0);
+        drop(arc);
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+}
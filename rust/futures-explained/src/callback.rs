@@ -1,48 +1,170 @@
 #[allow(dead_code)]
-fn program_main() {
-    println!("So we start the program here!");
+pub fn program_main() {
+    say("So we start the program here!");
     set_timeout(200, || {
-        println!("We create tasks with a callback that runs once the task finished!");
+        say("We create tasks with a callback that runs once the task finished!");
     });
     set_timeout(100, || {
-        println!("We can even chain sub-tasks...");
+        say("We can even chain sub-tasks...");
         set_timeout(50, || {
-            println!("...like this!");
-        })
+            say("...like this!");
+        });
     });
-    println!("While our tasks are executing we can do other stuff instead of waiting.");
+    say("While our tasks are executing we can do other stuff instead of waiting.");
 }
 
 #[allow(dead_code)]
 pub fn run_callback_test() {
-    RT.with(|rt| rt.run(program_main));
+    run(program_main);
+}
+
+// Cancel a pending timeout and a running interval, and report the error of
+// a failing callback to the error handler. The events are at least 100ms
+// apart, so that they arrive in order even on a busy machine.
+#[allow(dead_code)]
+fn program_main_cancel() {
+    say("So we start the program here!");
+    set_error_handler(|err| say(&format!("Error handler got: {}", err)));
+    let mut ticks = 0;
+    let interval = set_interval(200, move || {
+        ticks += 1;
+        say(&format!("Tick {}", ticks));
+    });
+    let timeout = set_timeout(900, || {
+        say("This is never printed, as the timeout is cancelled.");
+    });
+    set_timeout(700, move || {
+        say("We cancel the interval and the timeout.");
+        interval.cancel();
+        timeout.cancel();
+    });
+    set_timeout_result(300, || {
+        say("A callback can fail...");
+        Err("...like this!".into())
+    });
+    say("While our tasks are executing we can do other stuff instead of waiting.");
+}
+
+#[allow(dead_code)]
+pub fn run_cancellation_test() {
+    run(program_main_cancel);
+}
+
+// Run the program to completion, and return the lines it printed with say.
+#[allow(dead_code)]
+pub fn run(program: fn()) -> Vec<String> {
+    RT.with(|rt| rt.run(program))
+}
+
+// Print a line, and record it, so that the output of different versions
+// of a program can be compared.
+pub fn say(line: &str) {
+    println!("{}", line);
+    RT.with(|rt| rt.output.borrow_mut().push(line.to_string()));
 }
 
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::{cell::RefCell, collections::HashMap, thread};
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+use std::{cell::RefCell, collections::HashMap, error::Error, thread, time::Duration};
+use std::{marker::PhantomData, rc::Rc};
 
 thread_local! {
     static RT: Runtime = Runtime::new();
 }
 
+pub type CallbackResult = Result<(), Box<dyn Error>>;
+type ErrorHandler = Box<dyn FnMut(Box<dyn Error>)>;
+
+// A callback of a timeout runs once, and a callback of an interval runs
+// until the interval is cancelled.
+enum Callback {
+    Once(Box<dyn FnOnce()>),
+    Interval(Box<dyn FnMut()>, Arc<AtomicBool>),
+}
+
 #[allow(dead_code)]
 struct Runtime {
-    callbacks: RefCell<HashMap<usize, Box<dyn FnOnce() -> ()>>>,
+    callbacks: RefCell<HashMap<usize, Callback>>,
     next_id: RefCell<usize>,
     evt_sender: Sender<usize>,
     evt_reciever: Receiver<usize>,
+    output: RefCell<Vec<String>>,
+    error_handler: RefCell<Option<ErrorHandler>>,
+}
+
+// The handle of a timeout or an interval, which cancels it. The timer thread
+// stops at its next wakeup, and an event that is already on its way is
+// ignored, as the callback is gone. The callback lives in the runtime of the
+// thread that set the timer, so the handle can not leave that thread.
+#[derive(Clone)]
+pub struct TimerHandle {
+    id: usize,
+    cancelled: Arc<AtomicBool>,
+    _not_send: PhantomData<Rc<()>>,
 }
 
-fn set_timeout(ms: u64, cb: impl FnOnce() + 'static) {
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        RT.with(|rt| rt.callbacks.borrow_mut().remove(&self.id));
+    }
+}
+
+pub fn set_timeout(ms: u64, cb: impl FnOnce() + 'static) -> TimerHandle {
     RT.with(|rt| {
-        let id = *rt.next_id.borrow();
-        *rt.next_id.borrow_mut() += 1;
-        rt.callbacks.borrow_mut().insert(id, Box::new(cb));
+        let id = rt.add_callback(Callback::Once(Box::new(cb)));
+        let cancelled = Arc::new(AtomicBool::new(false));
         let evt_sender = rt.evt_sender.clone();
+        let thread_cancelled = cancelled.clone();
         thread::spawn(move || {
-            thread::sleep(std::time::Duration::from_millis(ms));
-            evt_sender.send(id).unwrap();
+            thread::sleep(Duration::from_millis(ms));
+            if !thread_cancelled.load(Ordering::SeqCst) {
+                let _ = evt_sender.send(id);
+            }
         });
+        TimerHandle { id, cancelled, _not_send: PhantomData }
+    })
+}
+
+// Run the callback every ms milliseconds, until the interval is cancelled.
+pub fn set_interval(ms: u64, cb: impl FnMut() + 'static) -> TimerHandle {
+    RT.with(|rt| {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let id = rt.add_callback(Callback::Interval(Box::new(cb), cancelled.clone()));
+        let evt_sender = rt.evt_sender.clone();
+        let thread_cancelled = cancelled.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(ms));
+            if thread_cancelled.load(Ordering::SeqCst) || evt_sender.send(id).is_err() {
+                break;
+            }
+        });
+        TimerHandle { id, cancelled, _not_send: PhantomData }
+    })
+}
+
+// set_timeout with a callback that can fail. The error is passed to the
+// error handler of the runtime.
+pub fn set_timeout_result(ms: u64, cb: impl FnOnce() -> CallbackResult + 'static) -> TimerHandle {
+    set_timeout(ms, move || {
+        if let Err(err) = cb() {
+            RT.with(|rt| rt.report_error(err));
+        }
+    })
+}
+
+// Set the handler receiving the errors of the callbacks, until the program
+// finishes. Without a handler, the errors are printed as uncaught.
+pub fn set_error_handler(handler: impl FnMut(Box<dyn Error>) + 'static) {
+    RT.with(|rt| *rt.error_handler.borrow_mut() = Some(Box::new(handler)));
+}
+
+// Run the callback on the next turn of the event loop, after the callbacks
+// whose events have already arrived.
+pub fn set_immediate(cb: impl FnOnce() + 'static) {
+    RT.with(|rt| {
+        let id = rt.add_callback(Callback::Once(Box::new(cb)));
+        rt.evt_sender.send(id).unwrap();
     });
 }
 
@@ -54,17 +176,77 @@ impl Runtime {
             next_id: RefCell::new(1),
             evt_sender,
             evt_reciever,
+            output: RefCell::new(Vec::new()),
+            error_handler: RefCell::new(None),
         }
     }
 
-    fn run(&self, program: fn()) {
+    fn add_callback(&self, cb: Callback) -> usize {
+        let id = *self.next_id.borrow();
+        *self.next_id.borrow_mut() += 1;
+        self.callbacks.borrow_mut().insert(id, cb);
+        id
+    }
+
+    // The handler is taken out while it runs, so that it can use the runtime,
+    // and is put back unless it has been replaced in the meantime.
+    fn report_error(&self, err: Box<dyn Error>) {
+        let handler = self.error_handler.borrow_mut().take();
+        match handler {
+            Some(mut handler) => {
+                handler(err);
+                self.error_handler.borrow_mut().get_or_insert(handler);
+            }
+            None => say(&format!("Uncaught error: {}", err)),
+        }
+    }
+
+    fn run(&self, program: fn()) -> Vec<String> {
         program();
-        for evt_id in &self.evt_reciever {
-            let cb = self.callbacks.borrow_mut().remove(&evt_id).unwrap();
-            cb();
-            if self.callbacks.borrow().is_empty() {
-                break;
+        while !self.callbacks.borrow().is_empty() {
+            let evt_id = self.evt_reciever.recv().unwrap();
+            // The callback is taken out while it runs, so that it can add
+            // and cancel callbacks. It is gone if it has been cancelled.
+            let cb = self.callbacks.borrow_mut().remove(&evt_id);
+            match cb {
+                Some(Callback::Once(cb)) => cb(),
+                Some(Callback::Interval(mut cb, cancelled)) => {
+                    cb();
+                    if !cancelled.load(Ordering::SeqCst) {
+                        self.callbacks.borrow_mut().insert(evt_id, Callback::Interval(cb, cancelled));
+                    }
+                }
+                None => {}
             }
         }
+        self.error_handler.take();
+        self.output.take()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelled_timers_never_run_and_errors_reach_the_handler() {
+        assert_eq!(run(program_main_cancel), vec![
+            "So we start the program here!",
+            "While our tasks are executing we can do other stuff instead of waiting.",
+            "Tick 1",
+            "A callback can fail...",
+            "Error handler got: ...like this!",
+            "Tick 2",
+            "Tick 3",
+            "We cancel the interval and the timeout.",
+        ]);
+    }
+
+    #[test]
+    fn errors_without_a_handler_are_uncaught() {
+        fn program() {
+            set_timeout_result(10, || Err("oops".into()));
+        }
+        assert_eq!(run(program), vec!["Uncaught error: oops"]);
+    }
+}
@@ -29,9 +29,10 @@ pub fn run() {
 
 use std::{
     future::Future, sync::{ mpsc::{channel, Sender}, Arc, Mutex, Condvar},
-    task::{Context, Poll, RawWaker, RawWakerVTable, Waker}, mem, pin::Pin,
+    task::{Context, Poll, Waker}, mem, pin::Pin,
     thread::{self, JoinHandle}, time::{Duration, Instant}, collections::HashMap
 };
+use crate::arc_waker::{waker, ArcWake};
 // ============================= EXECUTOR ====================================
 #[derive(Default)]
 struct Parker(Mutex<bool>, Condvar);
@@ -57,7 +58,7 @@ fn block_on<F: Future>(mut future: F) -> F::Output {
     let parker = Arc::new(Parker::default());
     
     let mywaker = Arc::new(MyWaker { parker: parker.clone() });
-    let waker = mywaker_into_waker(Arc::into_raw(mywaker));
+    let waker = waker(mywaker);
     
     let mut cx = Context::from_waker(&waker);
     
@@ -86,30 +87,13 @@ pub struct Task {
     data: u64,
 }
 
-fn mywaker_wake(s: &MyWaker) {
-    let waker_arc = unsafe { Arc::from_raw(s) };
-    thread_print(&format!("parker count is {}", Arc::strong_count(&waker_arc.parker)));
-    waker_arc.parker.unpark();
-}
-
-fn mywaker_clone(s: &MyWaker) -> RawWaker {
-    let arc = unsafe { Arc::from_raw(s) };
-    std::mem::forget(arc.clone()); // increase ref count
-    RawWaker::new(Arc::into_raw(arc) as *const (), &VTABLE)
-}
-
-const VTABLE: RawWakerVTable = unsafe {
-    RawWakerVTable::new(
-        |s| mywaker_clone(&*(s as *const MyWaker)),   // clone
-        |s| mywaker_wake(&*(s as *const MyWaker)),    // wake
-        |s| mywaker_wake(*(s as *const &MyWaker)),    // wake by ref
-        |s| drop(Arc::from_raw(s as *const MyWaker)), // decrease refcount
-    )
-};
-
-fn mywaker_into_waker(s: *const MyWaker) -> Waker {
-    let raw_waker = RawWaker::new(s as *const (), &VTABLE);
-    unsafe { Waker::from_raw(raw_waker) }
+// The vtable is the Arc-based one of arc_waker, where each Waker owns one
+// strong count of the Arc<MyWaker>.
+impl ArcWake for MyWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        thread_print(&format!("parker count is {}", Arc::strong_count(&arc_self.parker)));
+        arc_self.parker.unpark();
+    }
 }
 
 impl Task {
@@ -127,9 +111,9 @@ impl Future for Task {
             thread_print(&format!("task {} finishes", self.id));
             *r.tasks.get_mut(&self.id).unwrap() = TaskState::Finished;
             Poll::Ready(self.id)
-        } else if r.tasks.contains_key(&self.id) {
+        } else if let Some(state) = r.tasks.get_mut(&self.id) {
             thread_print("wtf");
-            r.tasks.insert(self.id, TaskState::NotReady(cx.waker().clone()));
+            *state = TaskState::NotReady(cx.waker().clone());
             Poll::Pending
         } else {
             thread_print(&format!("task {} registering with the reactor", self.id));
@@ -217,10 +201,7 @@ impl Reactor {
     }
 
     fn is_ready(&self, id: usize) -> bool {
-        self.tasks.get(&id).map(|state| match state {
-            TaskState::Ready => true,
-            _ => false,
-        }).unwrap_or(false)
+        matches!(self.tasks.get(&id), Some(TaskState::Ready))
     }
 }
 
@@ -0,0 +1,477 @@
+// A companion of future_executor1, turning the mini-runtime into a
+// thread-pool executor backed by a real reactor.
+//
+// future_executor1 drives a single future with block_on, and its reactor
+// spawns one OS thread per Timeout event. Here the futures are spawned as
+// tasks into a shared queue, and a fixed number of worker threads pop the
+// tasks and poll them. A single timer thread services the deadlines of all
+// the tasks: it sleeps until the earliest deadline and wakes up the tasks
+// whose deadlines have passed.
+//
+// The Parker and MyWaker design is kept. An idle worker parks on its own
+// Parker, just like block_on does, and MyWaker either unparks a thread or
+// pushes a task back into the queue.
+
+fn thread_print(s: &str) {
+    println!("thread {:?}: {}", thread::current().id(), s);
+}
+
+#[allow(dead_code)]
+pub fn run() {
+    let start = Instant::now();
+    let executor = Executor::new(4);
+    let reactor = Reactor::new();
+
+    // Eight tasks sleeping 100ms to 800ms finish after 800ms in total, as
+    // they wait for the same timer thread instead of one thread each.
+    for id in 1..=8 {
+        let reactor = reactor.clone();
+        executor.spawn(async move {
+            let deadline = Timeout::new(&reactor, Duration::from_millis(100 * (9 - id))).await;
+            thread_print(&format!("task {} wakes up at time: {:.2}.", id, (deadline - start).as_secs_f32()));
+        });
+    }
+
+    let mainfut = async {
+        Timeout::new(&reactor, Duration::from_millis(50)).await;
+        thread_print(&format!("main future wakes up at time: {:.2}.", start.elapsed().as_secs_f32()));
+    };
+    executor.block_on(mainfut);
+    executor.wait_idle();
+    thread_print(&format!("all tasks finish at time: {:.2}.", start.elapsed().as_secs_f32()));
+}
+
+use std::{
+    future::Future, sync::{Arc, Mutex, Condvar, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    task::{Context, Poll, Waker}, pin::Pin, cmp::Reverse,
+    thread::{self, JoinHandle}, time::{Duration, Instant}, collections::{VecDeque, BinaryHeap, HashMap}
+};
+use crate::arc_waker::{waker, ArcWake};
+// ============================= EXECUTOR ====================================
+#[derive(Default)]
+struct Parker(Mutex<bool>, Condvar);
+
+impl Parker {
+    fn park(&self) {
+        let mut resumable = self.0.lock().unwrap();
+        while !*resumable {
+            resumable = self.1.wait(resumable).unwrap();
+        }
+        *resumable = false;
+    }
+
+    fn unpark(&self) {
+        *self.0.lock().unwrap() = true;
+        self.1.notify_one();
+    }
+}
+
+// A spawned future. scheduled is set while the task sits in the queue, so
+// waking up a queued task multiple times only queues it once. The future is
+// taken out once it finishes.
+struct Task {
+    id: usize,
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    scheduled: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+// The state shared by the executor, its workers and the tasks.
+// queue: the tasks that are ready to be polled.
+// idle: the parkers of the workers waiting for a task.
+// pending: the number of spawned tasks that have not finished.
+// idle_parker: the parker of the thread waiting in wait_idle, if any.
+struct Shared {
+    queue: Mutex<VecDeque<Arc<Task>>>,
+    idle: Mutex<Vec<Arc<Parker>>>,
+    pending: AtomicUsize,
+    idle_parker: Mutex<Option<Arc<Parker>>>,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    // Push the task into the queue and unpark an idle worker, if any.
+    fn schedule(&self, task: Arc<Task>) {
+        self.queue.lock().unwrap().push_back(task);
+        if let Some(parker) = self.idle.lock().unwrap().pop() {
+            parker.unpark();
+        }
+    }
+
+    fn finish_task(&self) {
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Some(parker) = self.idle_parker.lock().unwrap().as_ref() {
+                parker.unpark();
+            }
+        }
+    }
+}
+
+pub struct Executor {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    next_id: AtomicUsize,
+}
+
+impl Executor {
+    fn new(num_workers: usize) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::new()),
+            idle: Mutex::new(Vec::new()),
+            pending: AtomicUsize::new(0),
+            idle_parker: Mutex::new(None),
+            shutdown: AtomicBool::new(false),
+        });
+        let workers = (0..num_workers).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || worker(shared))
+        }).collect();
+        Executor { shared, workers, next_id: AtomicUsize::new(1) }
+    }
+
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&self, future: F) {
+        let task = Arc::new(Task {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            future: Mutex::new(Some(Box::pin(future))),
+            scheduled: AtomicBool::new(true),
+            shared: self.shared.clone(),
+        });
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        self.shared.schedule(task);
+    }
+
+    // Drive the future on the current thread, exactly like block_on of
+    // future_executor1, while the workers keep running the spawned tasks.
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let parker = Arc::new(Parker::default());
+        let waker = waker(Arc::new(MyWaker::Thread(parker.clone())));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut future = Box::pin(future);
+        loop {
+            match Future::poll(future.as_mut(), &mut cx) {
+                Poll::Ready(val) => break val,
+                Poll::Pending => parker.park(),
+            };
+        }
+    }
+
+    // Block the current thread until all the spawned tasks finish.
+    fn wait_idle(&self) {
+        let parker = Arc::new(Parker::default());
+        *self.shared.idle_parker.lock().unwrap() = Some(parker.clone());
+        while self.shared.pending.load(Ordering::SeqCst) != 0 {
+            parker.park();
+        }
+        *self.shared.idle_parker.lock().unwrap() = None;
+    }
+}
+
+impl Drop for Executor {
+    // Stop the workers. The tasks that have not finished are dropped
+    // together with the queue.
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        for parker in self.shared.idle.lock().unwrap().drain(..) {
+            parker.unpark();
+        }
+        for handle in self.workers.drain(..) {
+            handle.join().unwrap();
+        }
+        self.shared.queue.lock().unwrap().clear();
+    }
+}
+
+// The main loop of a worker thread.
+fn worker(shared: Arc<Shared>) {
+    let parker = Arc::new(Parker::default());
+    loop {
+        if shared.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let task = shared.queue.lock().unwrap().pop_front();
+        let task = match task {
+            Some(task) => task,
+            None => {
+                // Register as idle before checking the queue again, so that
+                // a task scheduled in between always unparks this worker.
+                // The worker unregisters once it goes back to work, so that
+                // schedule only pops the parkers of workers that are idle.
+                shared.idle.lock().unwrap().push(parker.clone());
+                if shared.queue.lock().unwrap().is_empty() && !shared.shutdown.load(Ordering::SeqCst) {
+                    parker.park();
+                }
+                shared.idle.lock().unwrap().retain(|idle| !Arc::ptr_eq(idle, &parker));
+                continue;
+            }
+        };
+
+        // Clear the flag before polling, so that a wakeup during the poll
+        // queues the task again. Another worker may then pop the task, and
+        // waits on the lock of the future until this poll returns.
+        task.scheduled.store(false, Ordering::SeqCst);
+        let mut slot = task.future.lock().unwrap();
+        if let Some(mut future) = slot.take() {
+            let waker = waker(Arc::new(MyWaker::Task(task.clone())));
+            let mut cx = Context::from_waker(&waker);
+            match future.as_mut().poll(&mut cx) {
+                Poll::Pending => *slot = Some(future),
+                Poll::Ready(()) => {
+                    thread_print(&format!("task {} finishes", task.id));
+                    shared.finish_task();
+                }
+            }
+        }
+    }
+}
+// ====================== FUTURE IMPLEMENTATION ==============================
+// MyWaker either unparks the thread blocking on a future, or puts a task
+// back into the queue of the executor.
+enum MyWaker {
+    Thread(Arc<Parker>),
+    Task(Arc<Task>),
+}
+
+impl MyWaker {
+    fn wake(&self) {
+        match self {
+            MyWaker::Thread(parker) => parker.unpark(),
+            MyWaker::Task(task) => {
+                if !task.scheduled.swap(true, Ordering::SeqCst) {
+                    task.shared.schedule(task.clone());
+                }
+            }
+        }
+    }
+}
+
+// Each Waker owns one strong count of the Arc<MyWaker>, see arc_waker.
+impl ArcWake for MyWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        MyWaker::wake(arc_self);
+    }
+}
+
+// A future that completes at a deadline, yielding the deadline. It registers
+// its waker with the reactor on every poll until the deadline passes.
+pub struct Timeout {
+    reactor: Arc<Reactor>,
+    deadline: Instant,
+    id: Option<usize>,
+}
+
+impl Timeout {
+    fn new(reactor: &Arc<Reactor>, duration: Duration) -> Self {
+        Timeout { reactor: reactor.clone(), deadline: Instant::now() + duration, id: None }
+    }
+}
+
+impl Future for Timeout {
+    type Output = Instant;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if Instant::now() >= this.deadline {
+            return Poll::Ready(this.deadline);
+        }
+        match this.id {
+            Some(id) => this.reactor.update(id, cx.waker().clone()),
+            None => this.id = Some(this.reactor.register(this.deadline, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.reactor.cancel(id);
+        }
+    }
+}
+// =============================== REACTOR ===================================
+// deadlines: a min-heap of the registered deadlines, with their ids.
+// wakers: the wakers of the registered deadlines that have not fired.
+struct Timers {
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>,
+    wakers: HashMap<usize, Waker>,
+    next_id: usize,
+    closed: bool,
+}
+
+// The timers shared between the reactor and its timer thread.
+struct TimerQueue {
+    timers: Mutex<Timers>,
+    cond: Condvar,
+}
+
+impl TimerQueue {
+    // Wait for the earliest deadline, or for a new registration, and wake
+    // up the tasks whose deadlines have passed. Returns false once closed.
+    fn turn(&self) -> bool {
+        let mut timers = self.timers.lock().unwrap();
+        if timers.closed {
+            return false;
+        }
+        let now = Instant::now();
+        match timers.deadlines.peek() {
+            Some(Reverse((deadline, _))) if *deadline > now => {
+                let timeout = *deadline - now;
+                timers = self.cond.wait_timeout(timers, timeout).unwrap().0;
+            }
+            Some(_) => {}
+            None => timers = self.cond.wait(timers).unwrap(),
+        }
+
+        let now = Instant::now();
+        let mut expired = vec![];
+        while let Some(Reverse((deadline, id))) = timers.deadlines.peek().cloned() {
+            if deadline > now {
+                break;
+            }
+            timers.deadlines.pop();
+            // A cancelled deadline has no waker left.
+            if let Some(waker) = timers.wakers.remove(&id) {
+                expired.push(waker);
+            }
+        }
+        drop(timers);
+        // Wake up the tasks without holding the lock.
+        expired.into_iter().for_each(|waker| waker.wake());
+        true
+    }
+}
+
+pub struct Reactor {
+    queue: Arc<TimerQueue>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reactor {
+    // Start the single timer thread of the reactor.
+    fn new() -> Arc<Self> {
+        let queue = Arc::new(TimerQueue {
+            timers: Mutex::new(Timers {
+                deadlines: BinaryHeap::new(),
+                wakers: HashMap::new(),
+                next_id: 1,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+        let queue_clone = queue.clone();
+        let handle = thread::spawn(move || {
+            while queue_clone.turn() {}
+            thread_print("timer thread exits");
+        });
+        Arc::new(Reactor { queue, handle: Some(handle) })
+    }
+
+    fn register(&self, deadline: Instant, waker: Waker) -> usize {
+        let mut timers = self.queue.timers.lock().unwrap();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.deadlines.push(Reverse((deadline, id)));
+        timers.wakers.insert(id, waker);
+        // The new deadline may be earlier than the one the timer thread
+        // is waiting for.
+        self.queue.cond.notify_one();
+        id
+    }
+
+    fn update(&self, id: usize, waker: Waker) {
+        if let Some(stored) = self.queue.timers.lock().unwrap().wakers.get_mut(&id) {
+            if !stored.will_wake(&waker) {
+                *stored = waker;
+            }
+        }
+    }
+
+    fn cancel(&self, id: usize) {
+        self.queue.timers.lock().unwrap().wakers.remove(&id);
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        self.queue.timers.lock().unwrap().closed = true;
+        self.queue.cond.notify_one();
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::ThreadId;
+
+    #[test]
+    fn spawned_tasks_finish_on_multiple_workers() {
+        let executor = Executor::new(4);
+        let reactor = Reactor::new();
+        let finished = Arc::new(AtomicUsize::new(0));
+        for id in 0..100 {
+            let (reactor, finished) = (reactor.clone(), finished.clone());
+            executor.spawn(async move {
+                Timeout::new(&reactor, Duration::from_millis(id % 20)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // Every task blocks its worker until four tasks are running, which
+        // needs all the workers. The timeout only bounds a failing test.
+        let running = Arc::new((Mutex::new(0), Condvar::new()));
+        let together = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let (running, together) = (running.clone(), together.clone());
+            executor.spawn(async move {
+                let (count, cond) = &*running;
+                let mut count = count.lock().unwrap();
+                *count += 1;
+                cond.notify_all();
+                let (count, _) = cond.wait_timeout_while(count, Duration::from_secs(10), |count| *count < 4).unwrap();
+                if *count == 4 {
+                    together.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+
+        executor.wait_idle();
+        assert_eq!(finished.load(Ordering::SeqCst), 100);
+        assert_eq!(together.load(Ordering::SeqCst), 4);
+    }
+
+    // A waker recording the threads that wake it up.
+    struct RecordingWaker(Mutex<Vec<ThreadId>>);
+
+    impl ArcWake for RecordingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.lock().unwrap().push(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn single_timer_thread_serves_many_deadlines() {
+        let reactor = Reactor::new();
+        let timer_thread = reactor.handle.as_ref().unwrap().thread().id();
+        let recorder = Arc::new(RecordingWaker(Mutex::new(vec![])));
+        let waker = waker(recorder.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut timeouts: Vec<_> = (0..50).map(|ms| Timeout::new(&reactor, Duration::from_millis(ms))).collect();
+        for timeout in timeouts.iter_mut() {
+            let _ = Pin::new(timeout).poll(&mut cx);
+        }
+        let start = Instant::now();
+        while timeouts.iter_mut().any(|timeout| Pin::new(timeout).poll(&mut cx).is_pending()) {
+            assert!(start.elapsed() < Duration::from_secs(10), "the deadlines never fire");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Each registered deadline is fired once, all by the timer thread.
+        let wakers = recorder.0.lock().unwrap();
+        assert!(!wakers.is_empty() && wakers.len() <= 50);
+        assert!(wakers.iter().all(|id| *id == timer_thread));
+    }
+}
@@ -1,11 +1,11 @@
 #[allow(dead_code)]
-use std::ops::{Generator, GeneratorState};
+use std::ops::{Coroutine, CoroutineState};
 use std::pin::Pin;
 
 #[allow(dead_code)]
 pub fn run() {
     let a = 32;
-    let mut generator = || {
+    let mut generator = #[coroutine] || {
         println!("Hello");
         yield a*4;
         println!("Hello"); 
@@ -14,19 +14,19 @@ pub fn run() {
     };
 
     match Pin::new(&mut generator).resume(()) {
-        GeneratorState::Yielded(n) => {
+        CoroutineState::Yielded(n) => {
             println!("shit happens with {}", n);
         },
         _ => panic!("unexpected return from resume"),
     }
     match Pin::new(&mut generator).resume(()) {
-        GeneratorState::Yielded(777) => {
+        CoroutineState::Yielded(777) => {
             println!("shit happens ");
         },
         _ => panic!("unexpected return from resume"),
     }
     match Pin::new(&mut generator).resume(()) {
-        GeneratorState::Complete(()) => {
+        CoroutineState::Complete(()) => {
             println!("shit happens again");
         }
         _ => panic!("unexpected return from resume"),
@@ -1,5 +1,5 @@
 #[allow(dead_code)]
-use std::ops::{Generator, GeneratorState};
+use std::ops::{Coroutine, CoroutineState};
 use std::pin::Pin;
 
 #[allow(dead_code)]
@@ -11,32 +11,32 @@ pub fn run() {
         Exit(()),
     }
 
-    impl Generator<()> for GeneratorImpl {
+    impl Coroutine<()> for GeneratorImpl {
         type Yield = i32;
         
         type Return = ();
 
-        fn resume(self: Pin<&mut Self>, _: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        fn resume(self: Pin<&mut Self>, _: ()) -> CoroutineState<Self::Yield, Self::Return> {
             let inner = unsafe {self.get_unchecked_mut()};
             match std::mem::replace(inner, GeneratorImpl::Enter(0)) {
                 GeneratorImpl::Enter(a) => {
                     println!("Hello");
                     *inner = GeneratorImpl::Phase1(4 * a);
-                    GeneratorState::Yielded(4*a)
+                    CoroutineState::Yielded(4*a)
                 },
                 GeneratorImpl::Phase1(_) => {
                     println!("Hello"); 
                     *inner = GeneratorImpl::Phase2(777);
-                    GeneratorState::Yielded(777)
+                    CoroutineState::Yielded(777)
                 },
                 GeneratorImpl::Phase2(_) => {
                     println!("Hello");
                     *inner = GeneratorImpl::Exit(());
-                    GeneratorState::Complete(())
+                    CoroutineState::Complete(())
                 },
                 GeneratorImpl::Exit(_) => {
                     *inner = GeneratorImpl::Exit(());
-                    GeneratorState::Complete(())
+                    CoroutineState::Complete(())
                 }
             }
         }
@@ -47,7 +47,7 @@ pub fn run() {
     
     for _ in 0..5 {
         match Pin::new(&mut gen).resume(()) {
-            GeneratorState::Yielded(n) => {
+            CoroutineState::Yielded(n) => {
                 println!("The generator yielded {}", n);
             },
             _ => {
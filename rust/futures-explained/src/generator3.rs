@@ -1,11 +1,11 @@
 #[allow(dead_code)]
-use std::ops::{Generator, GeneratorState};
+use std::ops::{Coroutine, CoroutineState};
 use std::pin::Pin;
 
 #[allow(dead_code)]
 pub fn run1() {
     let a = 5;
-    let mut generator = || {
+    let mut generator = #[coroutine] || {
         if a > 10 {
             println!("In larger than 10 branch");
             yield 1024;
@@ -21,7 +21,7 @@ pub fn run1() {
 
     for _ in 0..5 {
         match Pin::new(&mut generator).resume(()) {
-            GeneratorState::Yielded(n) => {
+            CoroutineState::Yielded(n) => {
                 println!("The generator yields {}", n);
             },
             _ => {
@@ -43,35 +43,35 @@ pub fn run2() {
 
 
     // generating generator code requires control flow analysis
-    impl Generator<()> for GeneratorImpl {
+    impl Coroutine<()> for GeneratorImpl {
         type Yield = i32;
         
         type Return = ();
 
-        fn resume(self: Pin<&mut Self>, _: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        fn resume(self: Pin<&mut Self>, _: ()) -> CoroutineState<Self::Yield, Self::Return> {
             let inner = unsafe {self.get_unchecked_mut()};
             match std::mem::replace(inner, GeneratorImpl::Enter(0)) {
                 GeneratorImpl::Enter(a) => {
                     if a > 10 {
                         println!("In larger than 10 branch");
                         *inner = GeneratorImpl::Phase1(1024);
-                        GeneratorState::Yielded(1024)
+                        CoroutineState::Yielded(1024)
                     }
                     else {
                         println!("In smaller than 10 branch");
                         *inner = GeneratorImpl::Phase2(1026);
-                        GeneratorState::Yielded(1026)
+                        CoroutineState::Yielded(1026)
                     }
                 },
                 GeneratorImpl::Phase1(_) => {
                     println!("Back in larger than 10 branch");
                     *inner = GeneratorImpl::Phase2(1026);
-                    GeneratorState::Yielded(1026)
+                    CoroutineState::Yielded(1026)
                 },
                 GeneratorImpl::Phase2(_) => {
                     println!("Exit");
                     *inner = GeneratorImpl::Exit(());
-                    GeneratorState::Complete(())
+                    CoroutineState::Complete(())
                 },
                 GeneratorImpl::Exit(_) => {
                     panic!("Calling finished generator")
@@ -85,7 +85,7 @@ pub fn run2() {
     
     for _ in 0..5 {
         match Pin::new(&mut gen).resume(()) {
-            GeneratorState::Yielded(n) => {
+            CoroutineState::Yielded(n) => {
                 println!("The generator yielded {}", n);
             },
             _ => {
@@ -0,0 +1,237 @@
+// Driving futures with generators, the way the compiler once desugared
+// async/await.
+//
+// An async block used to be turned into a generator that yields () whenever
+// it waits, and the generator was wrapped into a Future by an adapter. The
+// adapter stores the Context of the current poll in a thread-local before
+// resuming the generator, and every await inside the generator polls its
+// future with that Context, yielding until the future is ready:
+//
+//     loop {
+//         match poll_with_tls_context(Pin::new_unchecked(&mut future)) {
+//             Poll::Ready(x) => break x,
+//             Poll::Pending => yield,
+//         }
+//     }
+//
+// The generator has to be a static one, as the future it awaits is pinned
+// inside the generator across the yields.
+//
+// The program_main of callback.rs is written here twice more, once with
+// generators and once with async/await. All three versions run on the event
+// loop of callback.rs, and print the same lines in the same order.
+
+// Await a future inside a generator wrapped by from_generator.
+macro_rules! gen_await {
+    ($e:expr) => {{
+        let mut future = $e;
+        loop {
+            // SAFETY: future is a local of a static generator, which is pinned,
+            // and it is shadowed so that it can not be moved afterwards.
+            let future = unsafe { ::std::pin::Pin::new_unchecked(&mut future) };
+            match $crate::generator_future::poll_with_tls_context(future) {
+                ::std::task::Poll::Ready(x) => break x,
+                ::std::task::Poll::Pending => yield,
+            }
+        }
+    }};
+}
+
+#[allow(dead_code)]
+fn program_main_generators() {
+    say("So we start the program here!");
+    spawn(from_generator(#[coroutine] static || {
+        gen_await!(Timeout::new(200));
+        say("We create tasks with a callback that runs once the task finished!");
+    }));
+    spawn(from_generator(#[coroutine] static || {
+        gen_await!(Timeout::new(100));
+        say("We can even chain sub-tasks...");
+        gen_await!(Timeout::new(50));
+        say("...like this!");
+    }));
+    say("While our tasks are executing we can do other stuff instead of waiting.");
+}
+
+#[allow(dead_code)]
+fn program_main_async() {
+    say("So we start the program here!");
+    spawn(async {
+        Timeout::new(200).await;
+        say("We create tasks with a callback that runs once the task finished!");
+    });
+    spawn(async {
+        Timeout::new(100).await;
+        say("We can even chain sub-tasks...");
+        Timeout::new(50).await;
+        say("...like this!");
+    });
+    say("While our tasks are executing we can do other stuff instead of waiting.");
+}
+
+// Run the three versions of program_main one after another, and check that
+// they print the same lines in the same order.
+#[allow(dead_code)]
+pub fn run() -> Vec<String> {
+    println!("===== callbacks =====");
+    let callbacks = callback::run(callback::program_main);
+    println!("===== generators =====");
+    let generators = callback::run(program_main_generators);
+    println!("===== async/await =====");
+    let async_await = callback::run(program_main_async);
+    assert_eq!(callbacks, generators);
+    assert_eq!(callbacks, async_await);
+    callbacks
+}
+
+use std::{
+    cell::{Cell, RefCell}, collections::HashMap, future::Future, rc::Rc,
+    ops::{Coroutine, CoroutineState}, pin::Pin, ptr::NonNull,
+    sync::Arc, task::{Context, Poll},
+};
+use crate::arc_waker::{waker, ArcWake};
+use crate::callback::{self, say, set_immediate, set_timeout};
+
+// ===== GENERATOR TO FUTURE =====
+thread_local! {
+    // The Context of the GenFuture being polled. The lifetime of the Context
+    // is erased, it is only valid while the GenFuture is being polled.
+    static TLS_CX: Cell<Option<NonNull<Context<'static>>>> = const { Cell::new(None) };
+}
+
+// Put the previous Context back when a poll finishes, even by a panic, so
+// that nested GenFutures restore the Context of the outer one.
+struct SetOnDrop(Option<NonNull<Context<'static>>>);
+
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        TLS_CX.with(|tls_cx| tls_cx.set(self.0.take()));
+    }
+}
+
+fn set_task_context<R>(cx: &mut Context<'_>, f: impl FnOnce() -> R) -> R {
+    let cx = NonNull::from(cx).cast::<Context<'static>>();
+    let _restore = SetOnDrop(TLS_CX.with(|tls_cx| tls_cx.replace(Some(cx))));
+    f()
+}
+
+// Poll the future with the Context of the GenFuture being polled. Panics
+// when called outside of a GenFuture.
+pub fn poll_with_tls_context<F: Future>(f: Pin<&mut F>) -> Poll<F::Output> {
+    let cx = TLS_CX.with(|tls_cx| tls_cx.get()).expect("polled outside of a GenFuture");
+    // SAFETY: the Context outlives the poll of the GenFuture, which is the
+    // only place where the generator, and so this function, runs.
+    f.poll(unsafe { &mut *cx.as_ptr() })
+}
+
+// A generator yielding () while it waits, seen as a future.
+pub struct GenFuture<G>(G);
+
+impl<G: Coroutine<Yield = ()>> Future for GenFuture<G> {
+    type Output = G::Return;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the generator is never moved out of the pinned GenFuture.
+        let generator = unsafe { self.map_unchecked_mut(|s| &mut s.0) };
+        set_task_context(cx, || match generator.resume(()) {
+            CoroutineState::Yielded(()) => Poll::Pending,
+            CoroutineState::Complete(x) => Poll::Ready(x),
+        })
+    }
+}
+
+pub fn from_generator<G: Coroutine<Yield = ()>>(generator: G) -> GenFuture<G> {
+    GenFuture(generator)
+}
+
+// ===== EXECUTOR =====
+// The tasks are polled by callbacks of the event loop of callback.rs. Waking
+// a task schedules a callback that polls it, so the wakers must be woken on
+// the thread of the event loop, which is where all the callbacks run.
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local! {
+    static TASKS: RefCell<HashMap<usize, Task>> = RefCell::new(HashMap::new());
+    static NEXT_ID: Cell<usize> = const { Cell::new(1) };
+}
+
+struct TaskWaker {
+    id: usize,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let id = arc_self.id;
+        set_immediate(move || poll_task(id));
+    }
+}
+
+fn spawn(future: impl Future<Output = ()> + 'static) {
+    let id = NEXT_ID.with(|next_id| next_id.replace(next_id.get() + 1));
+    TASKS.with(|tasks| tasks.borrow_mut().insert(id, Box::pin(future)));
+    poll_task(id);
+}
+
+// The future is taken out of TASKS while it is polled, so that it can spawn
+// other tasks.
+fn poll_task(id: usize) {
+    let future = TASKS.with(|tasks| tasks.borrow_mut().remove(&id));
+    if let Some(mut future) = future {
+        let waker = waker(Arc::new(TaskWaker { id }));
+        let mut cx = Context::from_waker(&waker);
+        if future.as_mut().poll(&mut cx).is_pending() {
+            TASKS.with(|tasks| tasks.borrow_mut().insert(id, future));
+        }
+    }
+}
+
+// ===== FUTURE IMPLEMENTATION =====
+// A future that completes after ms milliseconds, built on set_timeout.
+struct Timeout {
+    ms: u64,
+    fired: Option<Rc<Cell<bool>>>,
+}
+
+impl Timeout {
+    fn new(ms: u64) -> Self {
+        Timeout { ms, fired: None }
+    }
+}
+
+impl Future for Timeout {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &self.fired {
+            Some(fired) if fired.get() => Poll::Ready(()),
+            Some(_) => Poll::Pending,
+            None => {
+                let fired = Rc::new(Cell::new(false));
+                let waker = cx.waker().clone();
+                let fired_cb = fired.clone();
+                set_timeout(self.ms, move || {
+                    fired_cb.set(true);
+                    waker.wake();
+                });
+                self.fired = Some(fired);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn three_versions_print_the_same_lines() {
+        assert_eq!(run(), vec![
+            "So we start the program here!",
+            "While our tasks are executing we can do other stuff instead of waiting.",
+            "We can even chain sub-tasks...",
+            "...like this!",
+            "We create tasks with a callback that runs once the task finished!",
+        ]);
+    }
+}
@@ -1,4 +1,4 @@
-#![feature(generators, generator_trait)]
+#![feature(coroutines, coroutine_trait, stmt_expr_attributes)]
 
 mod play_with_threads;
 mod callback;
@@ -7,12 +7,15 @@ mod trait_object;
 mod generator1;
 mod generator2;
 mod generator3;
+mod generator_future;
 
 mod pin1;
 mod pin2;
 mod pin3;
 
+mod arc_waker;
 mod future_executor1;
+mod future_executor2;
 
 fn main() {
     future_executor1::run();
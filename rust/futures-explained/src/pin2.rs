@@ -22,11 +22,11 @@ impl Test {
         mut_ref.ptr = (&mut_ref.s) as *const String;
     }
 
-    fn peek_s<'a>(self : Pin<&'a Self>) -> &'a str {
+    fn peek_s(self : Pin<&Self>) -> &str {
         & self.get_ref().s
     }
 
-    fn peek_ptr<'a>(self : Pin<&'a Self>) -> &'a str {
+    fn peek_ptr(self : Pin<&Self>) -> &str {
         unsafe {& *self.get_ref().ptr}
     }
 }
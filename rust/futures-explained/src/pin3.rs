@@ -21,11 +21,11 @@ impl Test {
         pinned_box
     }
 
-    fn peek_s<'a>(self : Pin<&'a Test>) ->  &'a str {
+    fn peek_s(self : Pin<&Test>) ->  &str {
         &self.get_ref().s
     }
 
-    fn peek_ptr<'a>(self : Pin<&'a Test>) -> &'a str {
+    fn peek_ptr(self : Pin<&Test>) -> &str {
         unsafe{& *self.get_ref().ptr}
     }
 }
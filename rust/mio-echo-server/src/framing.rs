@@ -0,0 +1,186 @@
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// The largest frame a connection buffers. A client sending a longer frame,
+// or announcing one, is disconnected.
+pub const MAX_FRAME: usize = 8 * 1024 * 1024;
+
+// The hook that processes each frame a connection receives, and returns the
+// frame sent back as the response. It is shared by all the workers.
+pub type Handler = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+// The default handler, which sends every frame back as it is.
+pub fn echo_handler() -> Handler {
+    Arc::new(|frame: &[u8]| frame.to_vec())
+}
+
+// Splits the bytes read from a connection into frames, and encodes the
+// responses. Each connection owns its own Framer.
+pub trait Framer: Send {
+    // Find the first complete frame at the front of input. Returns the frame
+    // and the number of bytes it takes up in input, or None if the frame is
+    // not complete yet.
+    fn decode<'a>(&mut self, input: &'a [u8]) -> io::Result<Option<(&'a [u8], usize)>>;
+
+    // Append the encoded frame to output.
+    fn encode(&mut self, frame: &[u8], output: &mut Vec<u8>);
+}
+
+// Every chunk of bytes is a frame, as they arrive.
+pub struct RawFramer;
+
+impl Framer for RawFramer {
+    fn decode<'a>(&mut self, input: &'a [u8]) -> io::Result<Option<(&'a [u8], usize)>> {
+        if input.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some((input, input.len())))
+        }
+    }
+
+    fn encode(&mut self, frame: &[u8], output: &mut Vec<u8>) {
+        output.extend_from_slice(frame);
+    }
+}
+
+// A frame is a line ending with '\n', which is not part of the frame. The
+// framer remembers how much of an incomplete line it has already searched,
+// so that a long line is not scanned again on every read.
+#[derive(Default)]
+pub struct LineFramer {
+    scanned: usize,
+}
+
+impl Framer for LineFramer {
+    fn decode<'a>(&mut self, input: &'a [u8]) -> io::Result<Option<(&'a [u8], usize)>> {
+        let start = self.scanned.min(input.len());
+        match input[start..].iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                self.scanned = 0;
+                let end = start + pos;
+                Ok(Some((&input[..end], end + 1)))
+            }
+            None if input.len() > MAX_FRAME => Err(too_long(input.len())),
+            None => {
+                self.scanned = input.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn encode(&mut self, frame: &[u8], output: &mut Vec<u8>) {
+        output.extend_from_slice(frame);
+        output.push(b'\n');
+    }
+}
+
+// A frame is prefixed with its length, as a big-endian u32.
+pub struct LengthPrefixedFramer;
+
+impl Framer for LengthPrefixedFramer {
+    fn decode<'a>(&mut self, input: &'a [u8]) -> io::Result<Option<(&'a [u8], usize)>> {
+        if input.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([input[0], input[1], input[2], input[3]]) as usize;
+        if len > MAX_FRAME {
+            return Err(too_long(len));
+        }
+        if input.len() < 4 + len {
+            return Ok(None);
+        }
+        Ok(Some((&input[4..4 + len], 4 + len)))
+    }
+
+    fn encode(&mut self, frame: &[u8], output: &mut Vec<u8>) {
+        output.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        output.extend_from_slice(frame);
+    }
+}
+
+fn too_long(len: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("frame of {} bytes exceeds the limit of {} bytes", len, MAX_FRAME))
+}
+
+// The framing of the connections, selected at startup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    Raw,
+    Lines,
+    LengthPrefixed,
+}
+
+impl Framing {
+    pub fn framer(self) -> Box<dyn Framer> {
+        match self {
+            Framing::Raw => Box::new(RawFramer),
+            Framing::Lines => Box::new(LineFramer::default()),
+            Framing::LengthPrefixed => Box::new(LengthPrefixedFramer),
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Framing, String> {
+        match s {
+            "raw" => Ok(Framing::Raw),
+            "lines" => Ok(Framing::Lines),
+            "length" => Ok(Framing::LengthPrefixed),
+            _ => Err(format!("unknown framing: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decode all the complete frames, returning them and the bytes left.
+    fn decode_all(framer: &mut dyn Framer, input: &[u8]) -> io::Result<(Vec<Vec<u8>>, usize)> {
+        let mut frames = Vec::new();
+        let mut consumed = 0;
+        while let Some((frame, len)) = framer.decode(&input[consumed..])? {
+            frames.push(frame.to_vec());
+            consumed += len;
+        }
+        Ok((frames, input.len() - consumed))
+    }
+
+    #[test]
+    fn lines_split_on_newlines() {
+        let (frames, left) = decode_all(&mut LineFramer::default(), b"one\n\ntwo\r\nthr").unwrap();
+        assert_eq!(frames, vec![b"one".to_vec(), b"".to_vec(), b"two\r".to_vec()]);
+        assert_eq!(left, 3);
+    }
+
+    #[test]
+    fn lines_arriving_in_pieces_are_joined() {
+        let mut framer = LineFramer::default();
+        assert_eq!(framer.decode(b"hel").unwrap(), None);
+        assert_eq!(framer.decode(b"hello wor").unwrap(), None);
+        assert_eq!(framer.decode(b"hello world\nnext").unwrap(), Some((&b"hello world"[..], 12)));
+        assert_eq!(framer.decode(b"next\n").unwrap(), Some((&b"next"[..], 5)));
+    }
+
+    #[test]
+    fn length_prefixed_frames_round_trip() {
+        let mut output = Vec::new();
+        for frame in &[&b"hello"[..], b"", b"world"] {
+            LengthPrefixedFramer.encode(frame, &mut output);
+        }
+        let (frames, left) = decode_all(&mut LengthPrefixedFramer, &output[..output.len() - 2]).unwrap();
+        assert_eq!(frames, vec![b"hello".to_vec(), b"".to_vec()]);
+        assert_eq!(left, 7);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let header = ((MAX_FRAME + 1) as u32).to_be_bytes();
+        assert!(LengthPrefixedFramer.decode(&header).is_err());
+        assert!(LineFramer::default().decode(&vec![b'x'; MAX_FRAME + 1]).is_err());
+    }
+}
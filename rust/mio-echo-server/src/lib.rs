@@ -0,0 +1,411 @@
+mod try_read_write;
+use try_read_write::{TryRead, TryWrite};
+
+pub mod framing;
+pub use framing::{echo_handler, Framer, Framing, Handler};
+
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
+use slab::Slab;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[macro_use] extern crate log;
+
+const SERVER: Token = Token(10_000_000);
+const WAKER: Token = Token(10_000_001);
+
+// The number of bytes a connection reads at a time.
+const READ_CHUNK: usize = 16 * 1024;
+
+// The options of the server, fixed at startup. Every connection splits the
+// bytes it reads into frames with the framing, and sends back what the
+// handler returns for each frame. A connection that neither reads nor writes
+// anything for idle_timeout is closed, and the clients connecting while
+// max_connections are open are disconnected right away. None means no limit.
+#[derive(Clone)]
+pub struct Options {
+    pub threads: usize,
+    pub framing: Framing,
+    pub handler: Handler,
+    pub idle_timeout: Option<Duration>,
+    pub max_connections: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            threads: 1,
+            framing: Framing::Raw,
+            handler: echo_handler(),
+            idle_timeout: None,
+            max_connections: None,
+        }
+    }
+}
+
+// Whether a connection is still open after handling its events.
+#[derive(Debug, PartialEq)]
+enum ConnState {
+    Open,
+    Closed,
+}
+
+// The connection is either reading into input, or writing the responses in
+// output back to the client. It only asks for the event it is waiting for in
+// interest, and reregisters when that changes.
+struct EchoConn {
+    sock: TcpStream,
+    framer: Box<dyn Framer>,
+    handler: Handler,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    token: Token,
+    interest: Interest,
+    id: u64,
+    last_active: Instant,
+}
+
+impl EchoConn {
+    fn new(sock: TcpStream, token: Token, id: u64, options: &Options) -> EchoConn {
+        EchoConn {
+            sock,
+            framer: options.framing.framer(),
+            handler: options.handler.clone(),
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            token,
+            interest: Interest::READABLE,
+            id,
+            last_active: Instant::now(),
+        }
+    }
+
+    // Readiness is edge-triggered, so the socket is read and written until it
+    // would block, otherwise no further event would arrive for the bytes that
+    // are left. The pending responses are written before reading more, so a
+    // client that does not read its responses is not read either.
+    fn ready(&mut self, registry: &Registry) -> io::Result<ConnState> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            // A partial write keeps the rest of the bytes for the next round.
+            while self.written < self.output.len() {
+                match self.sock.try_write(&self.output[self.written..])? {
+                    None => {
+                        debug!("CONN : client flushing buf; WOULDBLOCK");
+                        return self.wait_for(registry, Interest::WRITABLE);
+                    }
+                    Some(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Some(r) => {
+                        debug!("CONN : we wrote {} bytes!", r);
+                        self.written += r;
+                        self.last_active = Instant::now();
+                    }
+                }
+            }
+            self.output.clear();
+            self.written = 0;
+
+            match self.sock.try_read(&mut chunk)? {
+                None => {
+                    debug!("CONN : read WOULDBLOCK");
+                    return self.wait_for(registry, Interest::READABLE);
+                }
+                Some(0) => {
+                    if !self.input.is_empty() {
+                        debug!("CONN : dropping {} bytes of an incomplete frame", self.input.len());
+                    }
+                    debug!("CONN : client closed the connection");
+                    return Ok(ConnState::Closed);
+                }
+                Some(r) => {
+                    debug!("CONN : we read {} bytes!", r);
+                    self.last_active = Instant::now();
+                    self.input.extend_from_slice(&chunk[..r]);
+                    self.process()?;
+                }
+            }
+        }
+    }
+
+    // Turn every complete frame in input into a response in output.
+    fn process(&mut self) -> io::Result<()> {
+        let mut consumed = 0;
+        while let Some((frame, len)) = self.framer.decode(&self.input[consumed..])? {
+            let response = (self.handler)(frame);
+            self.framer.encode(&response, &mut self.output);
+            consumed += len;
+        }
+        self.input.drain(..consumed);
+        Ok(())
+    }
+
+    fn wait_for(&mut self, registry: &Registry, interest: Interest) -> io::Result<ConnState> {
+        if self.interest != interest {
+            self.interest = interest;
+            registry.reregister(&mut self.sock, self.token, interest)?;
+        }
+        Ok(ConnState::Open)
+    }
+}
+
+// Poll for events, retrying when a signal interrupts the poll.
+fn poll_events(poll: &mut Poll, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+    loop {
+        match poll.poll(events, timeout) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            res => return res,
+        }
+    }
+}
+
+// A worker thread serves the connections handed over by the acceptor, with
+// its own Poll and Slab. The acceptor sends a connection through incoming
+// and wakes up the Poll of the worker with its Waker.
+// The idle deadlines of the connections are kept in deadlines, earliest
+// first, along with the token and the id of the connection, as the token is
+// reused once the connection is closed. A deadline is only moved when it is
+// reached, and the poll times out at the earliest deadline.
+// The number of open connections of all the workers is kept in active.
+struct Worker {
+    poll: Poll,
+    conns: Slab<EchoConn>,
+    incoming: Receiver<TcpStream>,
+    options: Options,
+    deadlines: BinaryHeap<Reverse<(Instant, usize, u64)>>,
+    next_conn_id: u64,
+    active: Arc<AtomicUsize>,
+}
+
+// The acceptor side of a worker.
+struct WorkerHandle {
+    sender: Sender<TcpStream>,
+    waker: Waker,
+}
+
+impl Worker {
+    fn spawn(id: usize, options: &Options, active: Arc<AtomicUsize>) -> io::Result<WorkerHandle> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        let (sender, incoming) = channel();
+        let mut worker = Worker {
+            poll,
+            conns: Slab::with_capacity(128),
+            incoming,
+            options: options.clone(),
+            deadlines: BinaryHeap::new(),
+            next_conn_id: 0,
+            active,
+        };
+        thread::Builder::new().name(format!("echo-worker-{}", id)).spawn(move || {
+            if let Err(e) = worker.run() {
+                error!("worker {} failed; err={:?}", id, e);
+            }
+        })?;
+        Ok(WorkerHandle { sender, waker })
+    }
+
+    // Serve the connections until the acceptor goes away.
+    fn run(&mut self) -> io::Result<()> {
+        // == Create storage for events
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let timeout = self.close_idle();
+            poll_events(&mut self.poll, &mut events, timeout)?;
+
+            for event in &events {
+                debug!("ready {:?} {:?}", event.token(), event);
+                match event.token() {
+                    WAKER => {
+                        if !self.register_incoming()? {
+                            return Ok(());
+                        }
+                    }
+                    tok => self.conn_ready(tok),
+                }
+            }
+        }
+    }
+
+    // Register the connections sent by the acceptor. Returns false once the
+    // acceptor has gone away.
+    fn register_incoming(&mut self) -> io::Result<bool> {
+        loop {
+            let sock = match self.incoming.try_recv() {
+                Ok(sock) => sock,
+                Err(TryRecvError::Empty) => return Ok(true),
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            };
+
+            // Register the connection
+            let conn_id = self.next_conn_id;
+            self.next_conn_id += 1;
+            let entry = self.conns.vacant_entry();
+            let tok = Token(entry.key());
+            let conn = entry.insert(EchoConn::new(sock, tok, conn_id, &self.options));
+            if let Some(idle) = self.options.idle_timeout {
+                self.deadlines.push(Reverse((conn.last_active + idle, tok.0, conn_id)));
+            }
+            if let Err(e) = self.poll.registry().register(&mut conn.sock, tok, conn.interest) {
+                self.close(tok);
+                return Err(e);
+            }
+            debug!("worker registered conn; tok={:?}", tok);
+        }
+    }
+
+    // Close the connections that have been idle for too long, and return how
+    // long the poll may wait for the next deadline.
+    fn close_idle(&mut self) -> Option<Duration> {
+        let idle = self.options.idle_timeout?;
+        let now = Instant::now();
+        while let Some(&Reverse((deadline, key, conn_id))) = self.deadlines.peek() {
+            if deadline > now {
+                return Some(deadline - now);
+            }
+            self.deadlines.pop();
+            let last_active = match self.conns.get(key) {
+                Some(conn) if conn.id == conn_id => conn.last_active,
+                // The connection is already closed.
+                _ => continue,
+            };
+            if last_active + idle <= now {
+                debug!("worker closing idle conn; tok={:?}", Token(key));
+                self.close(Token(key));
+            } else {
+                self.deadlines.push(Reverse((last_active + idle, key, conn_id)));
+            }
+        }
+        None
+    }
+
+    // An error of a connection only tears down that connection.
+    fn conn_ready(&mut self, tok: Token) {
+        debug!("worker conn ready; tok={:?}", tok);
+        let state = match self.conns.get_mut(tok.0) {
+            Some(conn) => conn.ready(self.poll.registry()),
+            None => return,
+        };
+        match state {
+            Ok(ConnState::Open) => {}
+            Ok(ConnState::Closed) => self.close(tok),
+            Err(e) => {
+                debug!("client err={:?}; tok={:?}", e, tok);
+                self.close(tok);
+            }
+        }
+    }
+
+    fn close(&mut self, tok: Token) {
+        let mut conn = self.conns.remove(tok.0);
+        let _ = self.poll.registry().deregister(&mut conn.sock);
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        debug!("worker closed conn; tok={:?}", tok);
+    }
+}
+
+// The acceptor, which accepts the connections on its own Poll and hands
+// them over to the workers in turn. It is the only one opening connections,
+// so the number of open connections in active never goes over the maximum.
+pub struct EchoServer {
+    poll: Poll,
+    sock: TcpListener,
+    workers: Vec<WorkerHandle>,
+    next_worker: usize,
+    active: Arc<AtomicUsize>,
+    max_connections: Option<usize>,
+}
+
+impl EchoServer {
+    // Bind the listener, and start the worker threads.
+    pub fn bind(addr: SocketAddr, options: Options) -> io::Result<EchoServer> {
+        let poll = Poll::new()?;
+        let mut sock = TcpListener::bind(addr)?;
+        poll.registry().register(&mut sock, SERVER, Interest::READABLE)?;
+        let active = Arc::new(AtomicUsize::new(0));
+        let workers = (0..options.threads.max(1))
+            .map(|id| Worker::spawn(id, &options, active.clone()))
+            .collect::<io::Result<_>>()?;
+        Ok(EchoServer {
+            poll,
+            sock,
+            workers,
+            next_worker: 0,
+            active,
+            max_connections: options.max_connections,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+
+    // A handle to the number of open connections, which stays valid while
+    // the server runs on another thread.
+    pub fn active_connections(&self) -> Arc<AtomicUsize> {
+        self.active.clone()
+    }
+
+    // Accept the clients until an error occurs on the poll or the listener,
+    // or a worker fails.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(128);
+
+        loop {
+            poll_events(&mut self.poll, &mut events, None)?;
+            if events.iter().any(|event| event.token() == SERVER) {
+                self.accept()?;
+            }
+        }
+    }
+
+    // Accept until the listener would block, as there is only one event for
+    // all the connections that are waiting. The connections over the limit
+    // are accepted as well, and closed right away, so that they do not wait
+    // in the backlog of the listener.
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let sock = match self.sock.accept() {
+                Ok((sock, _)) => sock,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some(max) = self.max_connections {
+                if self.active.load(Ordering::SeqCst) >= max {
+                    debug!("server rejecting socket; {} connections are open", max);
+                    continue;
+                }
+            }
+            debug!("server accepting socket; worker={}", self.next_worker);
+
+            self.active.fetch_add(1, Ordering::SeqCst);
+            let worker = &self.workers[self.next_worker];
+            self.next_worker = (self.next_worker + 1) % self.workers.len();
+            if worker.sender.send(sock).is_err() {
+                return Err(io::Error::other("a worker thread has failed"));
+            }
+            worker.waker.wake()?;
+        }
+    }
+}
+
+// Stop the workers, which find the channel disconnected once woken up.
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        for WorkerHandle { sender, waker } in self.workers.drain(..) {
+            drop(sender);
+            let _ = waker.wake();
+        }
+    }
+}
@@ -1,173 +1,78 @@
-mod try_read_write;
-use try_read_write::{TryRead, TryWrite};
-
-use mio::{Events, Poll, PollOpt, Ready, Token};
-use mio::net::{TcpListener, TcpStream};
-use bytes::{ByteBuf, MutByteBuf};
-use slab::Slab;
-use std::io;
-use std::net::SocketAddr;
+use mio_echo_server::{EchoServer, Options};
+use std::net::{IpAddr, SocketAddr};
+use std::process;
 use std::str::FromStr;
-
-#[macro_use] extern crate log;
-
-const SERVER: Token = Token(10_000_000);
-
-struct EchoConn {
-    sock: TcpStream,
-    buf: Option<ByteBuf>,
-    mut_buf: Option<MutByteBuf>,
-    token: Option<Token>,
-    interest: Ready
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &str = "usage: mio-echo-server [--address ADDR] [--port PORT] [--threads N] [--framing MODE]
+                       [--idle-timeout MS] [--max-connections N]
+
+  --address ADDR       the address to listen on (default 127.0.0.1)
+  --port PORT          the port to listen on (default 10240)
+  --threads N          the number of worker threads (default: one per CPU)
+  --framing MODE       raw, lines or length (u32 length-prefixed) (default raw)
+  --idle-timeout MS    close connections idle for MS milliseconds (default: never)
+  --max-connections N  disconnect clients beyond N open connections (default: no limit)";
+
+struct Config {
+    address: IpAddr,
+    port: u16,
+    options: Options,
 }
 
-impl EchoConn {
-    fn new(sock: TcpStream) -> EchoConn {
-        EchoConn {
-            sock,
-            buf: None,
-            mut_buf: Some(ByteBuf::mut_with_capacity(2048)),
-            token: None,
-            interest: Ready::empty(),
-        }
-    }
-
-    fn writable(&mut self, poll: &mut Poll) -> io::Result<()> {
-        let mut buf = self.buf.take().unwrap();
-
-        match self.sock.try_write_buf(&mut buf) {
-            Ok(None) => {
-                debug!("client flushing buf; WOULDBLOCK");
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("missing value for {}", flag))?;
+    value.parse().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
 
-                self.buf = Some(buf);
-                self.interest.insert(Ready::writable());
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+    let mut config = Config {
+        address: IpAddr::from([127, 0, 0, 1]),
+        port: 10240,
+        options: Options {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            ..Options::default()
+        },
+    };
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--address" => config.address = parse_value(&flag, args.next())?,
+            "--port" => config.port = parse_value(&flag, args.next())?,
+            "--threads" => config.options.threads = parse_value(&flag, args.next())?,
+            "--framing" => config.options.framing = parse_value(&flag, args.next())?,
+            "--idle-timeout" => {
+                let ms = parse_value(&flag, args.next())?;
+                config.options.idle_timeout = Some(Duration::from_millis(ms));
             }
-            Ok(Some(r)) => {
-                debug!("CONN : we wrote {} bytes!", r);
-
-                self.mut_buf = Some(buf.flip());
-
-                self.interest.insert(Ready::readable());
-                self.interest.remove(Ready::writable());
+            "--max-connections" => config.options.max_connections = Some(parse_value(&flag, args.next())?),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
             }
-            Err(e) => debug!("not implemented; client err={:?}", e),
+            _ => return Err(format!("unknown argument: {}", flag)),
         }
-
-        assert!(self.interest.is_readable() || self.interest.is_writable(), "actual={:?}", self.interest);
-        poll.reregister(&self.sock, self.token.unwrap(), self.interest,
-                              PollOpt::edge() | PollOpt::oneshot())
-    }
-
-    fn readable(&mut self, poll: &mut Poll) -> io::Result<()> {
-        let mut buf = self.mut_buf.take().unwrap();
-
-        match self.sock.try_read_buf(&mut buf) {
-            Ok(None) => {
-                debug!("CONN : spurious read wakeup");
-                self.mut_buf = Some(buf);
-            }
-            Ok(Some(r)) => {
-                debug!("CONN : we read {} bytes!", r);
-
-                // prepare to provide this to writable
-                self.buf = Some(buf.flip());
-
-                self.interest.remove(Ready::readable());
-                self.interest.insert(Ready::writable());
-            }
-            Err(e) => {
-                debug!("not implemented; client err={:?}", e);
-                self.interest.remove(Ready::readable());
-            }
-
-        };
-
-        assert!(self.interest.is_readable() || self.interest.is_writable(), "actual={:?}", self.interest);
-        poll.reregister(&self.sock, self.token.unwrap(), self.interest,
-                              PollOpt::edge())
     }
-}
-
-struct EchoServer {
-    sock: TcpListener,
-    conns: Slab<EchoConn>
-}
-
-impl EchoServer {
-    fn accept(&mut self, poll: &mut Poll) -> io::Result<()> {
-        debug!("server accepting socket");
-
-        let sock = self.sock.accept().unwrap().0;
-        let conn = EchoConn::new(sock,);
-        let tok = self.conns.insert(conn);
-
-        // Register the connection
-        self.conns[tok].token = Some(Token(tok));
-        poll.register(&self.conns[tok].sock, Token(tok), Ready::readable(),
-                                PollOpt::edge() | PollOpt::oneshot())
-            .expect("could not register socket with event loop");
-
-        Ok(())
-    }
-
-    fn conn_readable(&mut self, poll: &mut Poll,
-                     tok: Token) -> io::Result<()> {
-        debug!("server conn readable; tok={:?}", tok);
-        self.conn(tok).readable(poll)
-    }
-
-    fn conn_writable(&mut self, poll: &mut Poll,
-                     tok: Token) -> io::Result<()> {
-        debug!("server conn writable; tok={:?}", tok);
-        self.conn(tok).writable(poll)
-    }
-
-    fn conn(&mut self, tok: Token) -> &mut EchoConn {
-        &mut self.conns[tok.into()]
+    if config.options.threads == 0 {
+        return Err("--threads must be at least 1".to_string());
     }
+    Ok(config)
 }
 
 fn main() {
-    let mut poll = Poll::new().unwrap();
+    let config = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
 
     // set up the server address
-    let port_num = 10240;
-    let s = format!("127.0.0.1:{}", port_num);        
-    let srv_addr : SocketAddr = FromStr::from_str(&s).unwrap();
+    let srv_addr = SocketAddr::new(config.address, config.port);
 
     // create a server sock
-    let srv = TcpListener::bind(&srv_addr).unwrap();
-
-    println!("listen for connections");
-    poll.register(&srv, SERVER, Ready::readable(),
-                            PollOpt::edge() | PollOpt::oneshot()).unwrap();
-
-    // == Create storage for events
-    let mut events = Events::with_capacity(1024);
-
-    let mut echo_srv = EchoServer {
-        sock : srv,
-        conns: Slab::with_capacity(128),
-    };
-
-    loop {
-        poll.poll(&mut events, None).unwrap();
+    let threads = config.options.threads;
+    let framing = config.options.framing;
+    let mut echo_srv = EchoServer::bind(srv_addr, config.options).unwrap();
 
-        for event in &events {
-            debug!("ready {:?} {:?}", event.token(), event.readiness());
-            if event.readiness().is_readable() {
-                match event.token() {
-                    SERVER => echo_srv.accept(&mut poll).unwrap(),                    
-                    i => echo_srv.conn_readable(&mut poll, i).unwrap()
-                }
-            }
-
-            if event.readiness().is_writable() {
-                match event.token() {
-                    SERVER => panic!("received writable for token 0"),
-                    i => echo_srv.conn_writable(&mut poll, i).unwrap()
-                };
-            }
-        }
-    }
+    println!("listen for connections on {} with {} worker threads, framing {:?}", srv_addr, threads, framing);
+    echo_srv.run().unwrap();
 }
@@ -1,40 +1,10 @@
-use bytes::{Buf, MutBuf};
 use std::io::{self, Read, Write};
 
 pub trait TryRead {
-    fn try_read_buf<B: MutBuf>(&mut self, buf: &mut B) -> io::Result<Option<usize>>
-        where Self : Sized
-    {
-        // Reads the length of the slice supplied by buf.mut_bytes into the buffer
-        // This is not guaranteed to consume an entire datagram or segment.
-        // If your protocol is msg based (instead of continuous stream) you should
-        // ensure that your buffer is large enough to hold an entire segment (1532 bytes if not jumbo
-        // frames)
-        let res = self.try_read(unsafe { buf.mut_bytes() });
-
-        if let Ok(Some(cnt)) = res {
-            unsafe { buf.advance(cnt); }
-        }
-
-        res
-    }
-
     fn try_read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
 }
 
 pub trait TryWrite {
-    fn try_write_buf<B: Buf>(&mut self, buf: &mut B) -> io::Result<Option<usize>>
-        where Self : Sized
-    {
-        let res = self.try_write(buf.bytes());
-
-        if let Ok(Some(cnt)) = res {
-            buf.advance(cnt);
-        }
-
-        res
-    }
-
     fn try_write(&mut self, buf: &[u8]) -> io::Result<Option<usize>>;
 }
 
@@ -0,0 +1,173 @@
+use mio_echo_server::{EchoServer, Framing, Options};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn start_server(options: Options) -> SocketAddr {
+    start_server_with_count(options).0
+}
+
+// Start the server, and also return the number of its open connections.
+fn start_server_with_count(options: Options) -> (SocketAddr, Arc<AtomicUsize>) {
+    let mut server = EchoServer::bind("127.0.0.1:0".parse().unwrap(), options).unwrap();
+    let addr = server.local_addr().unwrap();
+    let active = server.active_connections();
+    thread::spawn(move || server.run().unwrap());
+    (addr, active)
+}
+
+// Send a byte and wait for its echo. Returns false if the server closes the
+// connection instead.
+fn ping(stream: &mut TcpStream) -> bool {
+    let mut byte = [0; 1];
+    stream.write_all(b"x").is_ok() && matches!(stream.read(&mut byte), Ok(1))
+}
+
+fn wait_until(what: &str, mut cond: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !cond() {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Write the payload while reading the echoes, as the server stops reading
+// when the client does not read, and return everything echoed until the
+// server closes the connection.
+fn echo(addr: SocketAddr, payload: Vec<u8>) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let handle = thread::spawn(move || {
+        writer.write_all(&payload).unwrap();
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    handle.join().unwrap();
+    echoed
+}
+
+#[test]
+fn echoes_ten_megabytes_byte_exact() {
+    let addr = start_server(Options::default());
+    let payload: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let echoed = echo(addr, payload.clone());
+    assert_eq!(echoed.len(), payload.len());
+    assert!(echoed == payload, "the echoed bytes differ from the payload");
+}
+
+#[test]
+fn broken_connection_does_not_stop_the_server() {
+    let addr = start_server(Options::default());
+    {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&[7; 64 * 1024]).unwrap();
+    }
+    let clients: Vec<_> = (0..4u8).map(|i| {
+        thread::spawn(move || echo(addr, vec![i; 100_000]))
+    }).collect();
+    for (i, client) in clients.into_iter().enumerate() {
+        assert_eq!(client.join().unwrap(), vec![i as u8; 100_000]);
+    }
+}
+
+#[test]
+fn workers_share_the_clients() {
+    let addr = start_server(Options { threads: 4, ..Options::default() });
+    let clients: Vec<_> = (0..16u8).map(|i| {
+        thread::spawn(move || echo(addr, vec![i; 1024 * 1024]))
+    }).collect();
+    for (i, client) in clients.into_iter().enumerate() {
+        assert!(client.join().unwrap() == vec![i as u8; 1024 * 1024]);
+    }
+}
+
+#[test]
+fn lines_are_handled_one_by_one() {
+    let addr = start_server(Options {
+        framing: Framing::Lines,
+        handler: Arc::new(|line: &[u8]| line.to_ascii_uppercase()),
+        ..Options::default()
+    });
+    // The last line is incomplete, and is dropped when the client closes.
+    let echoed = echo(addr, b"hello\nmio\r\n\nunfinished".to_vec());
+    assert_eq!(echoed, b"HELLO\nMIO\r\n\n".to_vec());
+}
+
+#[test]
+fn length_prefixed_frames_are_echoed() {
+    let addr = start_server(Options {
+        threads: 2,
+        framing: Framing::LengthPrefixed,
+        ..Options::default()
+    });
+    let mut payload = Vec::new();
+    for len in &[0usize, 1, 100, 64 * 1024, 3 * 1024 * 1024] {
+        payload.extend_from_slice(&(*len as u32).to_be_bytes());
+        payload.extend((0..*len).map(|i| (i % 253) as u8));
+    }
+    let echoed = echo(addr, payload.clone());
+    assert!(echoed == payload, "the echoed frames differ from the request");
+}
+
+#[test]
+fn oversized_frame_closes_the_connection() {
+    let addr = start_server(Options {
+        framing: Framing::LengthPrefixed,
+        ..Options::default()
+    });
+    let echoed = echo(addr, u32::MAX.to_be_bytes().to_vec());
+    assert!(echoed.is_empty());
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let (addr, active) = start_server_with_count(Options {
+        threads: 2,
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..Options::default()
+    });
+    let mut idle = TcpStream::connect(addr).unwrap();
+    let mut busy = TcpStream::connect(addr).unwrap();
+    assert!(ping(&mut idle));
+
+    // The busy connection stays open past the idle timeout, as it keeps
+    // sending, while the idle one is closed.
+    for _ in 0..8 {
+        assert!(ping(&mut busy));
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut rest = Vec::new();
+    assert_eq!(idle.read_to_end(&mut rest).unwrap(), 0);
+    assert!(ping(&mut busy));
+
+    drop(busy);
+    wait_until("every connection is closed", || active.load(Ordering::SeqCst) == 0);
+}
+
+#[test]
+fn clients_over_the_limit_are_rejected() {
+    let (addr, active) = start_server_with_count(Options {
+        threads: 2,
+        max_connections: Some(2),
+        ..Options::default()
+    });
+    let mut first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    assert!(ping(&mut first));
+    assert!(ping(&mut second));
+
+    let mut rejected = TcpStream::connect(addr).unwrap();
+    assert!(!ping(&mut rejected));
+    assert_eq!(active.load(Ordering::SeqCst), 2);
+
+    // Closing a connection makes room for a new client.
+    drop(first);
+    wait_until("the closed connection is counted", || active.load(Ordering::SeqCst) == 1);
+    let mut third = TcpStream::connect(addr).unwrap();
+    assert!(ping(&mut third));
+    assert!(ping(&mut second));
+}
@@ -0,0 +1,16 @@
+// Async channels for passing values between the tasks of a reactor.
+
+// The channels only rely on the Waker passed in through the Context,
+// so they work with any of the reactors in this crate. The shared state
+// of a channel is protected by a Mutex, so that the futures holding the
+// channel endpoints remain Send, as required by do_spawn. The endpoints
+// can also be moved to another thread, as long as the reactor on the
+// receiving end accepts wakeups from other threads, which reactor2 and 
+// reactor3 do. reactor1 and reactor_epoll push a woken task into the run
+// queue of the calling thread, so with them both endpoints must stay on
+// the thread of the reactor.
+// oneshot : A channel that sends a single value.
+// mpsc : A bounded multi-producer single-consumer channel. A sender waits
+// when the channel is full, which applies backpressure to the producer.
+pub mod oneshot;
+pub mod mpsc;
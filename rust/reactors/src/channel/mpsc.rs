@@ -0,0 +1,351 @@
+use std::task::{Waker, Context, Poll};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use crate::reactor2::coop;
+
+// A bounded multi-producer single-consumer channel.
+
+// The channel buffers at most capacity values. A sender that finds the
+// buffer full registers its waker in send_waiters and suspends, until the
+// receiver takes a value out of the buffer and wakes up the first waiting
+// sender. This is how the channel applies backpressure to fast producers.
+
+// The state shared by all the endpoints of the channel.
+// buffer : The values that are sent but not yet received.
+// capacity : The maximum number of values in the buffer.
+// senders : The number of alive Senders. The receiver gets None once all
+// the Senders are dropped and the buffer is drained.
+// rx_dropped : Whether the Receiver is dropped, in which case sending fails.
+// rx_waker : The waker of the task waiting on the Receiver.
+// send_waiters : The wakers of the senders waiting for a free slot, in FIFO
+// order. Each waiter is identified by an ID, so that a Send future can
+// remove itself from the queue when it is dropped.
+// next_waiter_id : A counter that is used to generate unique waiter IDs.
+struct Inner<T> {
+    buffer : VecDeque<T>,
+    capacity : usize,
+    senders : usize,
+    rx_dropped : bool,
+    rx_waker : Option<Waker>,
+    send_waiters : VecDeque<(u64, Waker)>,
+    next_waiter_id : u64,
+}
+
+impl<T> Inner<T> {
+    // Wake up the first sender waiting for a free slot.
+    fn wake_sender(&mut self) -> Option<Waker> {
+        self.send_waiters.pop_front().map(|(_, waker)| waker)
+    }
+}
+
+// The sending endpoint, which can be cloned for multiple producers.
+pub struct Sender<T> {
+    inner : Arc<Mutex<Inner<T>>>,
+}
+
+// The receiving endpoint.
+pub struct Receiver<T> {
+    inner : Arc<Mutex<Inner<T>>>,
+}
+
+// The error returned when sending to a channel whose Receiver is dropped.
+// The unsent value is handed back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+// Create a bounded channel that buffers at most capacity values.
+pub fn channel<T>(capacity : usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity of a bounded channel must be positive");
+    let inner = Arc::new(Mutex::new(Inner {
+        buffer : VecDeque::with_capacity(capacity),
+        capacity,
+        senders : 1,
+        rx_dropped : false,
+        rx_waker : None,
+        send_waiters : VecDeque::new(),
+        next_waiter_id : 0,
+    }));
+    (Sender { inner : inner.clone() }, Receiver { inner })
+}
+
+impl<T> Sender<T> {
+    // Send a value into the channel. The returned future waits until there
+    // is a free slot in the buffer.
+    pub fn send(&self, value : T) -> Send<'_, T> {
+        Send {
+            sender : self,
+            value : Some(value),
+            waiter_id : None,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.lock().unwrap().senders += 1;
+        Sender { inner : self.inner.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    // The last Sender wakes up the receiver, so that the receiver can
+    // learn that the channel is closed.
+    fn drop(&mut self) {
+        let waker = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.senders -= 1;
+            if inner.senders == 0 {
+                inner.rx_waker.take()
+            }
+            else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+// The future returned by Sender::send.
+// sender : The Sender that the value is sent through.
+// value : The value to send, which is taken once the value is sent.
+// waiter_id : The ID of this future in send_waiters, if it is waiting.
+pub struct Send<'a, T> {
+    sender : &'a Sender<T>,
+    value : Option<T>,
+    waiter_id : Option<u64>,
+}
+
+// Send is Unpin, the value is never pinned.
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let self_mut = self.get_mut();
+        let waker = {
+            let mut inner = self_mut.sender.inner.lock().unwrap();
+            if inner.rx_dropped {
+                let value = self_mut.value.take().expect("Send polled after completion");
+                return Poll::Ready(Err(SendError(value)));
+            }
+
+            if inner.buffer.len() == inner.capacity {
+                // The buffer is full. Register the waker, or update it if
+                // this future is already waiting.
+                let waker = ctx.waker().clone();
+                let waiter_id = self_mut.waiter_id;
+                let slot = waiter_id.and_then(|id| {
+                    inner.send_waiters.iter_mut().find(|(waiter, _)| *waiter == id)
+                });
+                match slot {
+                    Some(slot) => slot.1 = waker,
+                    None => {
+                        // This future is not in the queue, either because it is
+                        // polled for the first time, or because it was woken up
+                        // but another sender took the free slot first.
+                        let id = inner.next_waiter_id;
+                        inner.next_waiter_id += 1;
+                        inner.send_waiters.push_back((id, waker));
+                        self_mut.waiter_id = Some(id);
+                    },
+                }
+                return Poll::Pending;
+            }
+
+            let value = self_mut.value.take().expect("Send polled after completion");
+            inner.buffer.push_back(value);
+            // This future may be polled before being woken up, while it is
+            // still waiting in the queue.
+            if let Some(id) = self_mut.waiter_id.take() {
+                inner.send_waiters.retain(|(waiter, _)| *waiter != id);
+            }
+            inner.rx_waker.take()
+        };
+        // Wake up the receiver after releasing the lock.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Drop for Send<'_, T> {
+    // A Send future may be dropped while waiting for a free slot. If it
+    // is still in send_waiters, it simply removes itself. If it has been
+    // woken up but never got the chance to use the free slot, the wakeup
+    // is passed on to the next waiting sender, otherwise that sender may
+    // wait forever.
+    fn drop(&mut self) {
+        let id = match self.waiter_id {
+            Some(id) => id,
+            None => return,
+        };
+        let waker = {
+            let mut inner = self.sender.inner.lock().unwrap();
+            match inner.send_waiters.iter().position(|(waiter, _)| *waiter == id) {
+                Some(position) => {
+                    inner.send_waiters.remove(position);
+                    None
+                },
+                None if inner.buffer.len() < inner.capacity => inner.wake_sender(),
+                None => None,
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    // Receive the next value. The returned future resolves to None once
+    // all the Senders are dropped and the buffer is drained.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver : self }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    // Dropping the Receiver closes the channel. All the waiting senders
+    // are woken up and fail with SendError.
+    fn drop(&mut self) {
+        let waiters = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.rx_dropped = true;
+            inner.buffer.clear();
+            std::mem::take(&mut inner.send_waiters)
+        };
+        for (_, waker) in waiters {
+            waker.wake();
+        }
+    }
+}
+
+// The future returned by Receiver::recv.
+pub struct Recv<'a, T> {
+    receiver : &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let (value, waker) = {
+            let mut inner = self.receiver.inner.lock().unwrap();
+            match inner.buffer.pop_front() {
+                // A slot becomes free, wake up the first waiting sender.
+                Some(value) => (value, inner.wake_sender()),
+                None if inner.senders == 0 => return Poll::Ready(None),
+                None => {
+                    inner.rx_waker = Some(ctx.waker().clone());
+                    return Poll::Pending;
+                },
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Poll::Ready(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::flag_waker;
+
+    #[test]
+    fn full_channel_applies_backpressure() {
+        let (tx, mut rx) = channel(1);
+        let (tx_flag, tx_waker) = flag_waker();
+        let (rx_flag, rx_waker) = flag_waker();
+        let mut tx_ctx = Context::from_waker(&tx_waker);
+        let mut rx_ctx = Context::from_waker(&rx_waker);
+
+        assert!(Pin::new(&mut rx.recv()).poll(&mut rx_ctx).is_pending());
+        assert_eq!(Pin::new(&mut tx.send(1)).poll(&mut tx_ctx), Poll::Ready(Ok(())));
+        assert!(rx_flag.take());
+
+        // The buffer is full, the second send waits for a free slot.
+        let mut send = tx.send(2);
+        assert!(Pin::new(&mut send).poll(&mut tx_ctx).is_pending());
+        assert_eq!(Pin::new(&mut rx.recv()).poll(&mut rx_ctx), Poll::Ready(Some(1)));
+        assert!(tx_flag.take());
+        assert_eq!(Pin::new(&mut send).poll(&mut tx_ctx), Poll::Ready(Ok(())));
+        drop(send);
+
+        drop(tx);
+        assert_eq!(Pin::new(&mut rx.recv()).poll(&mut rx_ctx), Poll::Ready(Some(2)));
+        assert_eq!(Pin::new(&mut rx.recv()).poll(&mut rx_ctx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn dropped_waiter_passes_on_the_wakeup() {
+        let (tx, mut rx) = channel(1);
+        let (first_flag, first_waker) = flag_waker();
+        let (second_flag, second_waker) = flag_waker();
+        let noop = futures_task::noop_waker();
+
+        assert!(Pin::new(&mut tx.send(0)).poll(&mut Context::from_waker(&noop)).is_ready());
+        let mut first = tx.send(1);
+        let mut second = tx.send(2);
+        assert!(Pin::new(&mut first).poll(&mut Context::from_waker(&first_waker)).is_pending());
+        assert!(Pin::new(&mut second).poll(&mut Context::from_waker(&second_waker)).is_pending());
+
+        // The first sender is woken up but dropped before using the slot.
+        assert!(Pin::new(&mut rx.recv()).poll(&mut Context::from_waker(&noop)).is_ready());
+        assert!(first_flag.take());
+        assert!(!second_flag.take());
+        drop(first);
+        assert!(second_flag.take());
+        assert!(Pin::new(&mut second).poll(&mut Context::from_waker(&second_waker)).is_ready());
+    }
+
+    #[test]
+    fn channel_between_reactors_on_different_threads() {
+        use crate::reactor2;
+
+        // The capacity is small, so both ends keep waking up each other
+        // across the two threads.
+        let (tx, mut rx) = channel(2);
+        let producer = std::thread::spawn(move || {
+            reactor2::block_on(async move {
+                for i in 0..100 {
+                    tx.send(i).await.unwrap();
+                }
+            });
+        });
+        let sum = reactor2::block_on(async move {
+            let handle = reactor2::spawn(async move {
+                let mut sum = 0;
+                while let Some(i) = rx.recv().await {
+                    sum += i;
+                }
+                sum
+            });
+            handle.await.unwrap()
+        });
+        producer.join().unwrap();
+        assert_eq!(sum, 4950);
+    }
+
+    #[test]
+    fn send_fails_after_receiver_is_dropped() {
+        let (tx, rx) = channel(1);
+        drop(rx);
+        let noop = futures_task::noop_waker();
+        let res = Pin::new(&mut tx.send(5)).poll(&mut Context::from_waker(&noop));
+        assert_eq!(res, Poll::Ready(Err(SendError(5))));
+    }
+}
@@ -0,0 +1,139 @@
+use std::task::{Waker, Context, Poll};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use crate::reactor2::coop;
+
+// A oneshot channel, which sends a single value from a Sender to a Receiver.
+
+// The state shared by the two endpoints of the channel.
+// value : The value sent through the channel, which is taken by the receiver.
+// rx_waker : The waker of the task waiting on the Receiver.
+// tx_dropped : Whether the Sender is dropped, either after sending the value
+// or without sending anything.
+// rx_dropped : Whether the Receiver is dropped, in which case sending fails.
+struct Inner<T> {
+    value : Option<T>,
+    rx_waker : Option<Waker>,
+    tx_dropped : bool,
+    rx_dropped : bool,
+}
+
+// The sending endpoint. Sending consumes the Sender, so at most one value
+// can be sent through the channel.
+pub struct Sender<T> {
+    inner : Arc<Mutex<Inner<T>>>,
+}
+
+// The receiving endpoint. The Receiver is a future that resolves to the
+// sent value, or RecvError if the Sender is dropped without sending.
+pub struct Receiver<T> {
+    inner : Arc<Mutex<Inner<T>>>,
+}
+
+// The error returned by the Receiver if the Sender is dropped without
+// sending a value.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecvError;
+
+// Create a new oneshot channel.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        value : None,
+        rx_waker : None,
+        tx_dropped : false,
+        rx_dropped : false,
+    }));
+    (Sender { inner : inner.clone() }, Receiver { inner })
+}
+
+impl<T> Sender<T> {
+    // Send the value to the Receiver. If the Receiver has been dropped,
+    // the value is handed back in the Err.
+    pub fn send(self, value : T) -> Result<(), T> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rx_dropped {
+            return Err(value);
+        }
+        inner.value = Some(value);
+        // The Receiver is woken up when the Sender is dropped right after.
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    // Dropping the Sender wakes up the Receiver, which either finds the
+    // sent value or learns that no value will ever arrive.
+    fn drop(&mut self) {
+        let waker = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.tx_dropped = true;
+            inner.rx_waker.take()
+        };
+        // Wake up the receiver after releasing the lock.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(value) = inner.value.take() {
+            Poll::Ready(Ok(value))
+        }
+        else if inner.tx_dropped {
+            Poll::Ready(Err(RecvError))
+        }
+        else {
+            inner.rx_waker = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().rx_dropped = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::flag_waker;
+
+    #[test]
+    fn receiver_is_woken_by_send() {
+        let (tx, mut rx) = channel();
+        let (flag, waker) = flag_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut rx).poll(&mut ctx).is_pending());
+        assert_eq!(tx.send(7), Ok(()));
+        assert!(flag.take());
+        assert_eq!(Pin::new(&mut rx).poll(&mut ctx), Poll::Ready(Ok(7)));
+    }
+
+    #[test]
+    fn dropped_endpoints() {
+        let (tx, mut rx) = channel::<i32>();
+        let (flag, waker) = flag_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut rx).poll(&mut ctx).is_pending());
+        drop(tx);
+        assert!(flag.take());
+        assert_eq!(Pin::new(&mut rx).poll(&mut ctx), Poll::Ready(Err(RecvError)));
+
+        let (tx, rx) = channel();
+        drop(rx);
+        assert_eq!(tx.send(7), Err(7));
+    }
+}
@@ -28,8 +28,43 @@
 // A single-thread reactor motivated by fahrenheit, that only supports 
 // async sleep.  
 mod reactor1;
+// Reactor2:
+// The same reactor as reactor1, with the async tasks desugared into 
+// hand-written state machines.
 mod reactor2;
+// ReactorEpoll:
+// A single-thread reactor that blocks in epoll_wait, supporting both
+// async sleep and async TCP networking.
+mod reactor_epoll;
+// Reactor3:
+// A multi-thread reactor with work stealing, that only supports
+// async sleep.
+mod reactor3;
+// ReactorUring:
+// A single-thread reactor built on io_uring, supporting async sleep and
+// async TCP networking with owned buffers. Enabled by the uring feature.
+#[cfg(feature = "uring")]
+mod reactor_uring;
 
+// Async primitives built on top of the Waker, which work with all the reactors.
+mod channel;
+mod sync;
+
+#[cfg(test)]
+mod test_util;
+
+// Select the reactor to launch with the first command line argument.
 fn main() {
-    reactor2::launch();
+    match std::env::args().nth(1).as_deref() {
+        Some("reactor1") => reactor1::launch(),
+        Some("epoll") => reactor_epoll::launch(),
+        Some("reactor3") => reactor3::launch(),
+        Some("bench") => reactor2::bench::sleeping_tasks(100_000),
+        Some("sim") => reactor2::simulation(42),
+        #[cfg(feature = "uring")]
+        Some("uring") => reactor_uring::launch(),
+        #[cfg(feature = "uring")]
+        Some("uring-bench") => reactor_uring::bench::echo_throughput(8, 64 * 1024 * 1024),
+        _ => reactor2::launch(),
+    }
 }
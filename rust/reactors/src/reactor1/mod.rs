@@ -4,7 +4,8 @@ use std::collections::{VecDeque, BTreeMap, BinaryHeap};
 use std::cell::{RefCell, Cell};
 use std::pin::Pin;
 use std::future::Future;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
 use std::cmp::{PartialEq, Eq, Ord, PartialOrd, Ordering, Reverse};
 use futures_task::{ArcWake, FutureObj};
 
@@ -18,12 +19,21 @@ use futures_task::{ArcWake, FutureObj};
 // run_queue : A queue for storing tasks that are about to be waken up.
 // task_map : A tree-map for maintaining tasks alive.
 // id_counter : A counter that is used to generate unique IDs for tasks.
+// It also keeps track of the tasks that are aborted through their JoinHandles.
+// polling : The IDs of the tasks being polled. A task spawned by another task
+// is polled inside the poll of the spawning task, so there may be several.
+// aborting : The IDs of the tasks aborted while being polled, which are 
+// dropped once their poll returns.
+// aborts : The queue of the tasks aborted from other threads.
 struct Reactor {
     start_time : Instant,
     timer_heap : RefCell<BinaryHeap<Reverse<Timer>>>,
     run_queue : RefCell<VecDeque<NeedRun>>,
     task_map : RefCell<BTreeMap<usize, Task>>,
     id_counter : Cell<usize>,
+    polling : RefCell<Vec<usize>>,
+    aborting : RefCell<Vec<usize>>,
+    aborts : Arc<AbortQueue>,
 }
 
 impl Reactor {
@@ -37,13 +47,21 @@ impl Reactor {
             run_queue : RefCell::new(VecDeque::default()),
             task_map : RefCell::new(BTreeMap::default()),
             id_counter : Cell::new(1),
+            polling : RefCell::new(Vec::new()),
+            aborting : RefCell::new(Vec::new()),
+            aborts : Arc::new(AbortQueue {
+                task_ids : Mutex::new(Vec::new()),
+                thread : thread::current(),
+            }),
         }
     }
 
     // Spawn a new task based on a new Future trait object.
     // Currently, the Future trait object must have 'static lifetime and 
     // supports send.
-    fn do_spawn<F : Future<Output = ()> + 'static + Send>(&self, f : F) {
+    // The ID of the new task is returned, so that the JoinHandle of the 
+    // task can locate the task inside the task_map.
+    fn do_spawn<F : Future<Output = ()> + 'static + Send>(&self, f : F) -> usize {
         // Generate an unique ID for the new task.
         let task_id = self.next_task_id();
         // Create a new waker based on the generated ID. 
@@ -51,14 +69,34 @@ impl Reactor {
         // through the use of the ID number. This does incur additional overhead for 
         // retriving and inserting the task object from the task_map, consider 
         // improving this in the future.
-        let waker = futures_task::waker(Arc::new(WakerImpl{task_id : task_id}));
+        let waker = futures_task::waker(Arc::new(WakerImpl{task_id}));
         // Construct the task object associated with the async-operation.
         // The actual async-task used in this implementation contains a future object
         // that is stored on the heap. This makes the task freely movable.
-        let mut task = Task{task : FutureObj::new(Box::new(f))};
+        let task = Task{task : FutureObj::new(Box::new(f))};
 
-        // Poll the task and get the polling result.
+        // Poll the task right away.
+        self.poll_task(task_id, task, waker);
+        task_id
+    }
+
+    // Poll a task that has been taken out of the task_map.
+    fn poll_task(&self, task_id : usize, mut task : Task, waker : Waker) {
+        self.polling.borrow_mut().push(task_id);
         let res = task.poll(waker);
+        self.polling.borrow_mut().pop();
+
+        // The task may have aborted itself, or been aborted by a task it
+        // spawned, during the poll.
+        let mut aborting = self.aborting.borrow_mut();
+        if let Some(pos) = aborting.iter().position(|id| *id == task_id) {
+            aborting.swap_remove(pos);
+            drop(aborting);
+            drop(task);
+            return;
+        }
+        drop(aborting);
+
         match res {
             Poll::Pending => {
                 // If the task returns Pending, then the async task falls 
@@ -73,7 +111,31 @@ impl Reactor {
                 // we don't have to track the task and can directly drop the task.
                 println!("the task finishes");
             },
-            
+        }
+    }
+
+    // Abort a task of this reactor. A task waiting for a wakeup is removed
+    // from the task_map and dropped right away. The RefMut to the task_map 
+    // is released before dropping the task, as dropping the task wakes up 
+    // the task waiting on its JoinHandle. A task being polled is dropped 
+    // once its poll returns. Aborting a finished task has no effect.
+    fn abort(&self, task_id : usize) {
+        let task = self.task_map.borrow_mut().remove(&task_id);
+        match task {
+            Some(task) => drop(task),
+            None => {
+                if self.polling.borrow().contains(&task_id) {
+                    self.aborting.borrow_mut().push(task_id);
+                }
+            },
+        }
+    }
+
+    // Abort the tasks whose JoinHandles are aborted on other threads.
+    fn drain_aborts(&self) {
+        let task_ids = std::mem::take(&mut *self.aborts.task_ids.lock().unwrap());
+        for task_id in task_ids {
+            self.abort(task_id);
         }
     }
 
@@ -90,6 +152,8 @@ impl Reactor {
         self.do_spawn(f);
 
         loop {
+            self.drain_aborts();
+
             // Obtain the current eventloop running time.
             let event_loop_tick = Instant::now();
             // Calculate the duration from the current time to 
@@ -109,14 +173,19 @@ impl Reactor {
             // stores the timer that is the first to expire.
             // If that timer is not expired, we will sleep 
             // until it expires.
+            // Note that a finished task may wake up the task waiting on its
+            // JoinHandle. In this case the run_queue is not empty and we
+            // should not sleep at all.
+            // The thread is parked instead of sleeping, so that a JoinHandle
+            // aborting a task from another thread can unpark it.
             let mut timer_heap = self.timer_heap.borrow_mut();
-            timer_heap.peek().map(|next_timer| {
-                if *expire < next_timer.0.wakeup_duration {
-                    std::thread::sleep(next_timer.0.wakeup_duration - *expire);
+            if let Some(next_timer) = timer_heap.peek() {
+                if *expire < next_timer.0.wakeup_duration && self.run_queue.borrow().is_empty() {
+                    thread::park_timeout(next_timer.0.wakeup_duration - *expire);
                 }
-            });
+            }
             // Iterate through all the expired timers.
-            while timer_heap.peek().map_or(false, |next_timer| {
+            while timer_heap.peek().is_some_and(|next_timer| {
                 *expire > next_timer.0.wakeup_duration
             }) {
                 // Wake up the task associated with the expired timer 
@@ -140,19 +209,25 @@ impl Reactor {
             // Iterate through the run_queue.
             // Each item of the run_queue contains an ID which is 
             // linked to a task that should be resumed.  
-            let mut run_queue = self.run_queue.borrow_mut();
-            for _ in 0..run_queue.len() {
-                // Remove the task from the task_map
-                let needrun = run_queue.pop_front().unwrap();
-                let mut task = self.task_map.borrow_mut().remove(&needrun.task_id).unwrap();
+            let len = self.run_queue.borrow().len();
+            for _ in 0..len {
+                // Remove the task from the task_map. The task may have been 
+                // aborted after being woken up, e.g. by its timer, in which 
+                // case we just skip it.
+                let needrun = self.run_queue.borrow_mut().pop_front().unwrap();
+                let task = match self.task_map.borrow_mut().remove(&needrun.task_id) {
+                    Some(task) => task,
+                    None => continue,
+                };
                 // Resume the task by polling.
-                // Note that a task may do the following two things when being executed:
+                // Note that a task may do the following three things when being executed:
                 // 1. Append a new timer to the timer_heap and wait for some time
                 // 2. Spawn a new async task and insert the task inside the task_map.
+                // 3. Finish and wake up the task waiting on its JoinHandle.
                 // Whether using a single RefMut to access the core data structures 
                 // depends on the following analysis:
-                // 1. The run_queue of reactor is not touched by task.poll, so holding a 
-                // RefMut to the run_queue is fine.
+                // 1. The run_queue may be modified by task.poll, so we only create
+                // temporary RefMut to the run_queue when popping the NeedRun.
                 // 2. The timer_heap may be modified by task.poll, so holding a RefMut 
                 // to timer_heap when executing task.poll will panic the program. 
                 // Since we drop the RefMut to timer_heap  at the end of the reactor part, 
@@ -160,23 +235,12 @@ impl Reactor {
                 // 3. The task_map may be modified by task.poll, so we can't hold a RefMut
                 // to the task_map when executing task.poll. This is why we create temporary
                 // RefMut to task_map before and after the call to task.poll
-                match task.poll(needrun.waker) {
-                    Poll::Pending => {
-                        // If the task blocks again, insert the task back into the 
-                        // task_map and wait for later wakeup.
-                        self.task_map.borrow_mut().insert(needrun.task_id, task);
-                    },
-                    _ => {
-                        // If the task finishes, just continue and drops the finished
-                        // task.
-                        println!("task finishes");
-                    }
-                }
+                self.poll_task(needrun.task_id, task, needrun.waker);
             }
 
             // Check whether there are pending tasks. 
             // If not, stops the eventloop.
-            if self.task_map.borrow().len() == 0 {
+            if self.task_map.borrow().is_empty() {
                 break;
             }
         }
@@ -201,7 +265,7 @@ impl ArcWake for WakerImpl {
     fn wake_by_ref(arc_self : &Arc<Self>) {
         // Construct a new NeedRun.
         let next_need_run = NeedRun {
-            task_id : (**arc_self).task_id,
+            task_id : arc_self.task_id,
             waker : futures_task::waker(arc_self.clone()),
         };
         // Insert the NeedRun into the run queue. 
@@ -252,22 +316,14 @@ impl Eq for Timer {}
 // Implementation of the PartialOrder trait for Timer
 impl PartialOrd<Timer> for Timer {
     fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
-        if self.wakeup_duration == other.wakeup_duration {
-            Some(Ordering::Equal)
-        }
-        else if self.wakeup_duration > other.wakeup_duration {
-            Some(Ordering::Greater)
-        }
-        else {
-            Some(Ordering::Less)
-        }
+        Some(self.cmp(other))
     }
 }
 
 // Implementation of the Order trait for Timer
 impl Ord for Timer {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.wakeup_duration.cmp(&other.wakeup_duration)
     }
 }
 
@@ -298,7 +354,7 @@ impl Unpin for Timeout {}
 impl Timeout {
     fn new(duration : Duration) -> Self {
         Timeout {
-            duration : duration,
+            duration,
         }
     }
 }
@@ -319,7 +375,7 @@ impl Future for Timeout {
         }
         else {
             // Prepare the variables.
-            let duration = self.duration.clone();
+            let duration = self.duration;
             self.get_mut().duration = Duration::new(0, 0);
             let waker = ctx.waker().clone();
             
@@ -327,7 +383,7 @@ impl Future for Timeout {
                 // Construct a timer.
                 let timer = Timer {
                     wakeup_duration : (Instant::now() - reactor.start_time) + duration,
-                    waker : waker
+                    waker
                 };
                 // Insert the timer into the reactor.
                 reactor.timer_heap.borrow_mut().push(Reverse(timer));
@@ -338,6 +394,135 @@ impl Future for Timeout {
     }
 }
 
+// The tasks aborted through JoinHandles on other threads, which are 
+// aborted by the reactor at the start of the next round of the event loop.
+// task_ids : The IDs of the aborted tasks.
+// thread : The thread running the reactor, which is unparked so that the
+// aborted tasks are dropped right away.
+struct AbortQueue {
+    task_ids : Mutex<Vec<usize>>,
+    thread : Thread,
+}
+
+// The state shared between a spawned task and its JoinHandle.
+// output : The output of the task, which is set when the task finishes.
+// waker : The waker of the task that waits on the JoinHandle.
+// aborted : Whether the task is dropped before it finishes.
+struct JoinState<T> {
+    output : Option<T>,
+    waker : Option<Waker>,
+    aborted : bool,
+}
+
+// The future that is actually stored in the task_map when spawning a
+// future with an arbitrary output type. It polls the user future and
+// hands over the output to the JoinHandle when the user future finishes.
+// future : The user future, pinned on the heap so that JoinTask is Unpin.
+// It is set to None when the user future finishes.
+// state : The state shared with the JoinHandle.
+struct JoinTask<F : Future> {
+    future : Option<Pin<Box<F>>>,
+    state : Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F : Future> Future for JoinTask<F> {
+    type Output = ();
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        match self_mut.future.as_mut().unwrap().as_mut().poll(ctx) {
+            Poll::Ready(output) => {
+                self_mut.future = None;
+                // Store the output and wake up the task waiting on the 
+                // JoinHandle. The waker is called after releasing the lock.
+                let waker = {
+                    let mut state = self_mut.state.lock().unwrap();
+                    state.output = Some(output);
+                    state.waker.take()
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// A JoinTask dropped before the user future finishes has been aborted.
+// The task waiting on the JoinHandle is woken up to learn that no output 
+// will ever arrive.
+impl<F : Future> Drop for JoinTask<F> {
+    fn drop(&mut self) {
+        if self.future.is_none() {
+            return;
+        }
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.aborted = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+// The error returned by awaiting a JoinHandle, if the task is aborted 
+// before it finishes.
+#[derive(Debug, PartialEq, Eq)]
+enum JoinError {
+    Aborted,
+}
+
+// A handle to a spawned task. Awaiting the handle yields the output of 
+// the task. Dropping the handle detaches the task, which keeps running
+// in the reactor.
+// task_id : The ID of the spawned task, used to locate the task in task_map.
+// state : The state shared with the JoinTask.
+// aborts : The abort queue of the reactor running the task.
+struct JoinHandle<T> {
+    task_id : usize,
+    state : Arc<Mutex<JoinState<T>>>,
+    aborts : Arc<AbortQueue>,
+}
+
+impl<T> JoinHandle<T> {
+    // Abort the task. The FutureObj of the task is dropped, so that whatever 
+    // the task holds is dropped as well. On another thread than the one of 
+    // the reactor, the task is handed over to the reactor instead.
+    fn abort(&self) {
+        if thread::current().id() != self.aborts.thread.id() {
+            self.aborts.task_ids.lock().unwrap().push(self.task_id);
+            self.aborts.thread.unpark();
+            return;
+        }
+        REACTOR.with(|reactor| reactor.abort(self.task_id));
+    }
+}
+
+// JoinHandle is Unpin, the shared state is stored on the heap.
+impl<T> Unpin for JoinHandle<T> {}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(output) = state.output.take() {
+            Poll::Ready(Ok(output))
+        }
+        else if state.aborted {
+            Poll::Ready(Err(JoinError::Aborted))
+        }
+        else {
+            state.waker = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
 // The entry point of the async eventloop.
 fn run<F : Future<Output = ()> + 'static + Send>(f : F) {
     REACTOR.with(|reactor| {
@@ -346,10 +531,27 @@ fn run<F : Future<Output = ()> + 'static + Send>(f : F) {
 }
 
 // Spawning a new Future task inside the eventloop.
-fn spawn<F : Future<Output = ()> + 'static + Send>(f : F) {
+// The returned JoinHandle can be used to retrieve the output of the task
+// or to abort the task.
+fn spawn<F>(f : F) -> JoinHandle<F::Output> 
+    where F : Future + 'static + Send, F::Output : Send + 'static
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output : None,
+        waker : None,
+        aborted : false,
+    }));
+    let join_task = JoinTask {
+        future : Some(Box::pin(f)),
+        state : state.clone(),
+    };
     REACTOR.with(|reactor| {
-        reactor.do_spawn(f);
-    });
+        JoinHandle {
+            task_id : reactor.do_spawn(join_task),
+            state,
+            aborts : reactor.aborts.clone(),
+        }
+    })
 }
 
 async fn sleep_sub_task(id : i32) {
@@ -368,5 +570,94 @@ async fn sleep_task() {
 }
 
 pub fn launch() {
-    run(sleep_task());
+    run(async {
+        // A watchdog task that would keep the reactor alive for 60s.
+        // It is aborted through its JoinHandle once the sleep_task finishes.
+        let watchdog = spawn(Timeout::new(Duration::from_secs(60)));
+        spawn(sleep_task()).await.unwrap();
+        watchdog.abort();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering as AtomicOrdering;
+    use crate::test_util::drop_flag;
+
+    #[test]
+    fn join_handle_yields_output() {
+        run(async {
+            let handle = spawn(async {
+                Timeout::new(Duration::from_millis(10)).await;
+                42
+            });
+            assert_eq!(handle.await, Ok(42));
+        });
+    }
+
+    #[test]
+    fn abort_sleeping_task() {
+        let (dropped, flag) = drop_flag();
+        let start = Instant::now();
+
+        run(async move {
+            let handle = spawn(async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+                1
+            });
+            Timeout::new(Duration::from_millis(10)).await;
+            handle.abort();
+            assert!(dropped.load(AtomicOrdering::SeqCst));
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+        });
+        // The reactor stops without waiting for the timer of the aborted task.
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn task_aborting_itself_is_dropped_after_its_poll() {
+        let (dropped, flag) = drop_flag();
+        run(async move {
+            let handle = Arc::new(Mutex::new(None::<JoinHandle<()>>));
+            let own = handle.clone();
+            let task = spawn(async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_millis(10)).await;
+                own.lock().unwrap().as_ref().unwrap().abort();
+                Timeout::new(Duration::from_secs(10)).await;
+            });
+            let task_id = task.task_id;
+            *handle.lock().unwrap() = Some(task);
+            Timeout::new(Duration::from_millis(20)).await;
+            assert!(dropped.load(AtomicOrdering::SeqCst));
+            REACTOR.with(|reactor| assert!(!reactor.task_map.borrow().contains_key(&task_id)));
+        });
+    }
+
+    #[test]
+    fn abort_from_another_thread() {
+        let (dropped, flag) = drop_flag();
+        let start = Instant::now();
+
+        run(async move {
+            let handle = spawn(async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+                1
+            });
+            let (tx, rx) = std::sync::mpsc::channel();
+            thread::spawn(move || {
+                handle.abort();
+                tx.send(handle).unwrap();
+            });
+            // The task is dropped by the reactor in the next round, which 
+            // wakes up the JoinHandle.
+            let handle = rx.recv().unwrap();
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+            assert!(dropped.load(AtomicOrdering::SeqCst));
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
 }
\ No newline at end of file
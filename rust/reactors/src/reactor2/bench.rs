@@ -0,0 +1,23 @@
+use std::time::{Instant, Duration};
+use super::{run, spawn, timer_stats, Timeout};
+
+// A benchmark of the reactor with a large number of concurrent sleeping tasks.
+
+// The main task spawns tasks_num tasks at once. Each task sleeps for a 
+// duration between 0 and 999ms, so the wakeups are spread over many 
+// iterations of the event loop. The benchmark reports the time spent on 
+// spawning the tasks and the total time to run all of them. Run it in 
+// release mode with `cargo run --release -- bench`.
+pub fn sleeping_tasks(tasks_num : usize) {
+    let start = Instant::now();
+    run(async move {
+        for i in 0..tasks_num {
+            spawn(async move {
+                Timeout::new(Duration::from_millis((i % 1000) as u64)).await;
+            });
+        }
+        println!("spawn {} tasks in {:?}", tasks_num, start.elapsed());
+    });
+    println!("run {} sleeping tasks in {:?}", tasks_num, start.elapsed());
+    println!("{:?}", timer_stats());
+}
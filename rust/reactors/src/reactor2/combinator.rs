@@ -0,0 +1,283 @@
+use std::time::Duration;
+use std::task::{Context, Poll};
+use std::pin::Pin;
+use std::future::Future;
+use std::mem;
+use super::Timeout;
+
+// Combinators for running several futures within a single task.
+
+// Like SleepSubTask, each combinator is a hand-written state machine. The
+// futures are stored inside the state machine and polled in place through
+// Pin::new_unchecked. They are never moved after the first poll: a future
+// either stays where it is, or is dropped in place by switching the state
+// machine to a new state. This is why the losing futures of select are
+// dropped as soon as a winner is found, instead of when the combinator
+// itself is dropped.
+
+// The output of select, telling which of the two futures finishes first.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+// The error returned by with_timeout, if the future does not finish
+// before the deadline.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Elapsed;
+
+// Race two futures. The output of the first finished future is returned,
+// and the other future is dropped. If both futures are ready, the left
+// one wins.
+pub fn select<A : Future, B : Future>(a : A, b : B) -> Select<A, B> {
+    Select::Racing(a, b)
+}
+
+pub enum Select<A, B> {
+    Racing(A, B),
+    Done,
+}
+
+impl<A : Future, B : Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = unsafe {Pin::get_unchecked_mut(self)};
+        let res = match self_mut {
+            Select::Racing(a, b) => {
+                // Poll the futures in place, neither of them is moved.
+                if let Poll::Ready(out) = unsafe {Pin::new_unchecked(a)}.poll(ctx) {
+                    Either::Left(out)
+                }
+                else if let Poll::Ready(out) = unsafe {Pin::new_unchecked(b)}.poll(ctx) {
+                    Either::Right(out)
+                }
+                else {
+                    return Poll::Pending;
+                }
+            },
+            Select::Done => panic!("Select polled after completion"),
+        };
+        // Switching to Done drops the losing future in place.
+        *self_mut = Select::Done;
+        Poll::Ready(res)
+    }
+}
+
+// Race a group of futures. The output of the first finished future is
+// returned together with its index, and all the other futures are dropped.
+pub fn select_all<F : Future>(futures : Vec<F>) -> SelectAll<F> {
+    assert!(!futures.is_empty(), "select_all needs at least one future");
+    SelectAll::Racing(futures)
+}
+
+pub enum SelectAll<F> {
+    // The Vec is never resized, so the futures are not moved in memory.
+    Racing(Vec<F>),
+    Done,
+}
+
+impl<F : Future> Future for SelectAll<F> {
+    type Output = (F::Output, usize);
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = unsafe {Pin::get_unchecked_mut(self)};
+        let res = match self_mut {
+            SelectAll::Racing(futures) => {
+                let ready = futures.iter_mut().enumerate().find_map(|(i, f)| {
+                    match unsafe {Pin::new_unchecked(f)}.poll(&mut *ctx) {
+                        Poll::Ready(out) => Some((out, i)),
+                        Poll::Pending => None,
+                    }
+                });
+                match ready {
+                    Some(res) => res,
+                    None => return Poll::Pending,
+                }
+            },
+            SelectAll::Done => panic!("SelectAll polled after completion"),
+        };
+        // Switching to Done drops all the losing futures in place.
+        *self_mut = SelectAll::Done;
+        Poll::Ready(res)
+    }
+}
+
+// The state of a single future in JoinAll.
+enum MaybeDone<F : Future> {
+    Running(F),
+    Done(F::Output),
+    Taken,
+}
+
+// Wait for a group of futures to finish. The outputs are returned in the
+// same order as the futures.
+pub fn join_all<F : Future>(futures : Vec<F>) -> JoinAll<F> {
+    JoinAll {
+        futures : futures.into_iter().map(MaybeDone::Running).collect(),
+    }
+}
+
+pub struct JoinAll<F : Future> {
+    futures : Vec<MaybeDone<F>>,
+}
+
+impl<F : Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = unsafe {Pin::get_unchecked_mut(self)};
+        let mut all_done = true;
+        for state in self_mut.futures.iter_mut() {
+            if let MaybeDone::Running(f) = state {
+                match unsafe {Pin::new_unchecked(f)}.poll(&mut *ctx) {
+                    // A finished future is dropped in place, only its
+                    // output is kept.
+                    Poll::Ready(out) => *state = MaybeDone::Done(out),
+                    Poll::Pending => all_done = false,
+                }
+            }
+        }
+        if !all_done {
+            return Poll::Pending;
+        }
+        // The outputs are not pinned, so they can be moved out.
+        let outputs = self_mut.futures.iter_mut().map(|state| {
+            match mem::replace(state, MaybeDone::Taken) {
+                MaybeDone::Done(out) => out,
+                _ => panic!("JoinAll polled after completion"),
+            }
+        }).collect();
+        Poll::Ready(outputs)
+    }
+}
+
+// Bound the execution of a future with a deadline. If the future does not
+// finish within the duration, it is dropped and Elapsed is returned.
+pub fn with_timeout<F : Future>(f : F, duration : Duration) -> WithTimeout<F> {
+    WithTimeout {
+        future : Some(f),
+        duration,
+        timeout : None,
+    }
+}
+
+// The future is kept in the same field in all the states, so it is never
+// moved once polled.
+// future : The bounded future, which is dropped in place once the
+// combinator finishes.
+// duration : The time allowed for the future to finish.
+// timeout : The Timeout, which is created on the first poll.
+pub struct WithTimeout<F> {
+    future : Option<F>,
+    duration : Duration,
+    timeout : Option<Timeout>,
+}
+
+impl<F : Future> Future for WithTimeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = unsafe {Pin::get_unchecked_mut(self)};
+        let f = match self_mut.future.as_mut() {
+            Some(f) => f,
+            None => panic!("WithTimeout polled after completion"),
+        };
+        if let Poll::Ready(out) = unsafe {Pin::new_unchecked(f)}.poll(&mut *ctx) {
+            // Dropping the Timeout cancels the timer.
+            self_mut.future = None;
+            self_mut.timeout = None;
+            return Poll::Ready(Ok(out));
+        }
+        // The timer is only started if the future is not ready right away.
+        let duration = self_mut.duration;
+        let to = self_mut.timeout.get_or_insert_with(|| Timeout::new(duration));
+        match Future::poll(Pin::new(to), &mut *ctx) {
+            Poll::Ready(()) => {
+                // The deadline is reached, drop the future in place.
+                self_mut.future = None;
+                Poll::Ready(Err(Elapsed))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{run, spawn, timer_stats};
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+    use crate::test_util::drop_flag;
+
+    #[test]
+    fn select_drops_the_losing_branch() {
+        let start = Instant::now();
+        run(async {
+            let (dropped, flag) = drop_flag();
+            let slow = async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+                1
+            };
+            let fast = async {
+                Timeout::new(Duration::from_millis(10)).await;
+                2
+            };
+            assert_eq!(select(slow, fast).await, Either::Right(2));
+            // The slow branch is dropped as soon as the fast one finishes.
+            assert!(dropped.load(Ordering::SeqCst));
+            assert_eq!(timer_stats().pending, 0);
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn select_all_drops_all_the_losers() {
+        run(async {
+            let (flags, futures) : (Vec<_>, Vec<_>) = (0..3u64).map(|i| {
+                let (dropped, flag) = drop_flag();
+                let f = async move {
+                    let _flag = flag;
+                    Timeout::new(Duration::from_millis(30 - 10 * i)).await;
+                    i
+                };
+                (dropped, Box::pin(f))
+            }).unzip();
+            assert_eq!(select_all(futures).await, (2, 2));
+            assert!(flags.iter().all(|dropped| dropped.load(Ordering::SeqCst)));
+        });
+    }
+
+    #[test]
+    fn join_all_keeps_the_order_of_outputs() {
+        run(async {
+            let futures = (0..3u64).map(|i| spawn(async move {
+                Timeout::new(Duration::from_millis(30 - 10 * i)).await;
+                i
+            })).collect();
+            let outputs : Vec<_> = join_all(futures).await.into_iter().map(Result::unwrap).collect();
+            assert_eq!(outputs, vec![0, 1, 2]);
+        });
+    }
+
+    #[test]
+    fn with_timeout_drops_the_future_on_deadline() {
+        run(async {
+            let (dropped, flag) = drop_flag();
+            let slow = async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+            };
+            assert_eq!(with_timeout(slow, Duration::from_millis(10)).await, Err(Elapsed));
+            assert!(dropped.load(Ordering::SeqCst));
+
+            let fast = Timeout::new(Duration::from_millis(10));
+            assert_eq!(with_timeout(fast, Duration::from_secs(10)).await, Ok(()));
+            // The timer of the deadline is cancelled.
+            assert_eq!(timer_stats().pending, 0);
+        });
+    }
+}
@@ -0,0 +1,75 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use super::REACTOR;
+
+// Cooperative scheduling, so that a busy task can not starve the others.
+
+// The reactor only gets control back when a task returns Pending. A task
+// looping over futures that are always ready, e.g. a zero-length Timeout or
+// the JoinHandle of a finished task, never returns Pending by itself, and
+// keeps the other tasks and the timers waiting for as long as it loops.
+// So each poll of a task gets a budget of BUDGET operations. Every poll of
+// a leaf future of the reactor, i.e. a Timeout, a JoinHandle, or one of the
+// futures of the channels and of the sync primitives, spends one unit of
+// the budget. A channel that always has a value ready would otherwise let a
+// consumer loop forever. Once the budget is used up, the leaf futures return
+// Pending and wake up the task right away, which sends the task to the back
+// of the run_queue. The budget is refilled on the next poll of the task.
+// A future can also give up its turn explicitly by awaiting yield_now.
+
+// The number of operations a task can perform in a single poll.
+pub const BUDGET : u32 = 128;
+
+// Spend one unit of the budget of the task being polled. If the budget is
+// used up, the task is woken up and Poll::Pending is returned, in which case
+// the leaf future must return Poll::Pending as well. Outside of a budgeted
+// poll, e.g. when a future is polled by hand or by another reactor, the
+// budget is unlimited.
+pub(crate) fn poll_proceed(ctx : &mut Context<'_>) -> Poll<()> {
+    let exhausted = REACTOR.with(|reactor| {
+        match reactor.budget.get() {
+            Some(0) => true,
+            Some(budget) => {
+                reactor.budget.set(Some(budget - 1));
+                false
+            },
+            None => false,
+        }
+    });
+    if exhausted {
+        ctx.waker().wake_by_ref();
+        Poll::Pending
+    }
+    else {
+        Poll::Ready(())
+    }
+}
+
+// The future returned by yield_now.
+// yielded : Whether the task has given up its turn.
+pub struct YieldNow {
+    yielded : bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.get_mut().yielded = true;
+        ctx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// Give up the turn of the current task. The task is sent to the back of the
+// run_queue, so the tasks woken up before it, and the timers that expire in
+// the meantime, are handled before the task resumes.
+pub fn yield_now() -> YieldNow {
+    YieldNow {
+        yielded : false,
+    }
+}
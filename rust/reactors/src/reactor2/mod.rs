@@ -1,293 +1,827 @@
 use std::time::{Instant, Duration};
 use std::task::{Waker, Context, Poll};
-use std::collections::{VecDeque, BTreeMap, BinaryHeap};
-use std::cell::{RefCell, Cell};
+use std::collections::VecDeque;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use std::pin::Pin;
 use std::future::Future;
-use std::sync::Arc;
-use std::cmp::{PartialEq, Eq, Ord, PartialOrd, Ordering, Reverse};
-use futures_task::{ArcWake, FutureObj};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::thread::{self, Thread};
+use futures_task::{ArcWake, LocalFutureObj};
+use slab::Slab;
+use crate::channel::{oneshot, mpsc};
+use crate::sync::{Semaphore, Notify, Barrier, Mutex as AsyncMutex};
+
+#[macro_use]
+pub mod task_local;
+#[macro_use]
+pub mod span;
+mod timer_wheel;
+use timer_wheel::{TimerWheel, TimerKey, TimerStats};
+pub mod combinator;
+pub mod task_stats;
+pub mod scoped;
+pub mod sim;
+pub mod coop;
+use sim::Clock;
+use task_local::LocalMap;
+use task_stats::{TaskStats, TaskState, TaskInfo, TaskTable};
+use combinator::{Either, select, select_all, join_all, with_timeout};
+pub mod bench;
 
 // A simple reactor that only supports timeout.
 
 // This single-threaded reactor is motivated by the design of fahrenheit.
-// The reactor contains 5 core data structures for storing and manipulating 
+// The reactor contains 4 core data structures for storing and manipulating 
 // async-tasks.
-// start_time : The instant at which the thread-local reactor instance is created.
-// timer_heap : A min-heap for storing different timers.
-// run_queue : A queue for storing tasks that are about to be waken up.
-// task_map : A tree-map for maintaining tasks alive.
-// id_counter : A counter that is used to generate unique IDs for tasks.
+// clock : The clock of the reactor, which follows either the real time or
+// a virtual time in the simulation mode, see sim.rs.
+// timer_wheel : A hierarchical timing wheel for storing different timers.
+// Compared with a min-heap, inserting and cancelling a timer are both O(1).
+// run_queue : A queue for storing the headers of tasks that are about to be waken up,
+// together with the instant at which each task is pushed into the queue.
+// task_slab : A slab for maintaining tasks alive. Each task is stored at a fixed
+// key of the slab, which is recorded in the task header, so that locating the 
+// task of a woken header is O(1).
+// id_counter : A counter that is used to generate task IDs in spawn order.
+// shutdown : The state shared with the ShutdownHandles of the reactor.
+// remote : The queue of the tasks woken up or aborted from other threads,
+// shared with the headers of the tasks.
+// event_hook : The hook that is called on the lifecycle events of the tasks.
+// running : Whether the event loop is running on this thread.
+// current_task : The ID of the task being polled, if any.
+// locals : The task-local values visible to the future being polled.
+// budget : The remaining budget of the future being polled, None if the 
+// budget is unlimited, see coop.rs.
+// nested_poll_time : The time spent polling the tasks spawned inside the 
+// poll of the current task, which is not part of the poll_time of the 
+// current task.
 struct Reactor {
-    start_time : Instant,
-    timer_heap : RefCell<BinaryHeap<Reverse<Timer>>>,
-    run_queue : RefCell<VecDeque<NeedRun>>,
-    task_map : RefCell<BTreeMap<usize, Task>>,
-    id_counter : Cell<usize>,
+    clock : RefCell<Clock>,
+    timer_wheel : RefCell<TimerWheel>,
+    run_queue : RefCell<VecDeque<(Arc<TaskHeader>, Instant)>>,
+    task_slab : RefCell<Slab<Task>>,
+    id_counter : Cell<u64>,
+    shutdown : Arc<ShutdownState>,
+    remote : Arc<RemoteQueue>,
+    event_hook : RefCell<Option<EventHook>>,
+    running : Cell<bool>,
+    current_task : Cell<Option<u64>>,
+    locals : RefCell<LocalMap>,
+    budget : Cell<Option<u32>>,
+    nested_poll_time : Cell<Duration>,
 }
 
+type EventHook = Box<dyn Fn(TaskEvent)>;
+
 impl Reactor {
     // Create a new reactor. In this module, the created reactor will be 
     // stored in a thread-local storage and accessed only through an 
     // imutable reference
     fn new() -> Self {
         Self {
-            start_time : Instant::now(),
-            timer_heap : RefCell::new(BinaryHeap::default()),
+            clock : RefCell::new(Clock::new()),
+            timer_wheel : RefCell::new(TimerWheel::new()),
             run_queue : RefCell::new(VecDeque::default()),
-            task_map : RefCell::new(BTreeMap::default()),
-            id_counter : Cell::new(1),
+            task_slab : RefCell::new(Slab::new()),
+            id_counter : Cell::new(0),
+            shutdown : Arc::new(ShutdownState {
+                requested : AtomicBool::new(false),
+                thread : thread::current(),
+            }),
+            remote : Arc::new(RemoteQueue {
+                woken : Mutex::new(Vec::new()),
+                aborted : Mutex::new(Vec::new()),
+                thread : thread::current(),
+            }),
+            event_hook : RefCell::new(None),
+            running : Cell::new(false),
+            current_task : Cell::new(None),
+            locals : RefCell::new(LocalMap::new()),
+            budget : Cell::new(None),
+            nested_poll_time : Cell::new(Duration::new(0, 0)),
+        }
+    }
+
+    // Mark the event loop as running until the returned guard is dropped.
+    // Running the event loop from inside a task would poll the other tasks
+    // in a nested loop, while the future of the current task is taken out
+    // of the task_slab, so it is not allowed.
+    fn enter(&self) -> EnterGuard<'_> {
+        assert!(!self.running.replace(true), "the reactor is already running on this thread");
+        EnterGuard {
+            reactor : self,
+        }
+    }
+
+    // Report a task event to the event hook. The hook can not replace 
+    // itself, as the Ref to the event_hook is held during the call.
+    fn emit(&self, event : TaskEvent) {
+        if let Some(hook) = self.event_hook.borrow().as_ref() {
+            hook(event);
         }
     }
 
     // Spawn a new task based on a new Future trait object.
-    // Currently, the Future trait object must have 'static lifetime and 
-    // supports send.
-    fn do_spawn<F : Future<Output = ()> + 'static + Send>(&self, f : F) {
-        // Generate an unique ID for the new task.
-        let task_id = self.next_task_id();
-        // Create a new waker based on the generated ID. 
-        // In the current implementation, the waker is linked to a particular task 
-        // through the use of the ID number. This does incur additional overhead for 
-        // retriving and inserting the task object from the task_map, consider 
-        // improving this in the future.
-        let waker = futures_task::waker(Arc::new(WakerImpl{task_id : task_id}));
-        // Construct the task object associated with the async-operation.
-        // The actual async-task used in this implementation contains a future object
-        // that is stored on the heap. This makes the task freely movable.
-        let mut task = Task{task : FutureObj::new(Box::new(f))};
-
-        // Poll the task and get the polling result.
-        let res = task.poll(waker);
+    // The reactor is thread-local, and a task never leaves the thread, so
+    // the Future trait object need not be Send. It must have 'static lifetime,
+    // since the task_slab may keep it alive for arbitrarily long. Scoped tasks
+    // erase their lifetime, see scoped.rs for why this is safe.
+    // The header of the new task is returned, so that the JoinHandle of the
+    // task can locate the task inside the task_slab.
+    // The optional name of the task shows up in dump_tasks.
+    fn do_spawn(&self, name : Option<String>, future : LocalFutureObj<'static, ()>) -> Arc<TaskHeader> {
+        // Reserve a slot in the task_slab, and create the header of the task
+        // based on the key of the slot.
+        // The waker is created from the header. Compared with looking up a task
+        // ID in a tree-map, the header directly points to the slot of the task.
+        // The header is marked as RUNNING, since the task is polled right away.
+        let id = self.id_counter.get();
+        self.id_counter.set(id + 1);
+        let header = {
+            let mut task_slab = self.task_slab.borrow_mut();
+            let entry = task_slab.vacant_entry();
+            let header = Arc::new(TaskHeader {
+                id,
+                key : entry.key(),
+                state : AtomicUsize::new(RUNNING),
+                wakeups : AtomicU64::new(0),
+                remote : self.remote.clone(),
+            });
+            entry.insert(Task {
+                header : header.clone(),
+                name,
+                future : None,
+                stats : TaskStats::default(),
+            });
+            header
+        };
+        self.emit(TaskEvent::Spawned(id));
+
+        // Poll the task and let the header decide what to do next.
+        self.poll_task(&header, future);
+        header
+    }
+
+    // Take a snapshot of the live tasks in spawn order.
+    fn tasks(&self) -> Vec<TaskInfo> {
+        let mut tasks : Vec<TaskInfo> = self.task_slab.borrow().iter().map(|(_, task)| {
+            let state = match task.header.state.load(AtomicOrdering::SeqCst) {
+                IDLE => TaskState::Idle,
+                SCHEDULED => TaskState::Scheduled,
+                RUNNING => TaskState::Running,
+                _ => TaskState::Notified,
+            };
+            TaskInfo {
+                id : task.header.id,
+                name : task.name.clone(),
+                state,
+                wakeups : task.header.wakeups.load(AtomicOrdering::Relaxed),
+                stats : task.stats,
+            }
+        }).collect();
+        tasks.sort_unstable_by_key(|task| task.id);
+        tasks
+    }
+
+    // Report the number of pending, fired and cancelled timers.
+    fn timer_stats(&self) -> TimerStats {
+        self.timer_wheel.borrow().stats()
+    }
+
+    // Poll the future of a task whose header is in the RUNNING state.
+    // Note that a task may do the following two things when being executed:
+    // 1. Append a new timer to the timer_wheel and wait for some time
+    // 2. Spawn a new async task and insert the task inside the task_slab.
+    // So we can't hold a RefMut to the task_slab or the timer_wheel when 
+    // polling the future. The future is taken out of the task_slab before
+    // the poll and put back afterwards.
+    fn poll_task(&self, header : &Arc<TaskHeader>, mut future : LocalFutureObj<'static, ()>) {
+        // The waker borrows the header, no reference count is touched 
+        // unless the task clones the waker.
+        let waker = futures_task::waker_ref(header);
+        let mut ctx = Context::from_waker(&waker);
+        // A task spawned by another task is polled inside the poll of the 
+        // spawning task. The task-local values of the spawning task are
+        // hidden during the poll, and restored afterwards.
+        let locals = std::mem::take(&mut *self.locals.borrow_mut());
+        let current_task = self.current_task.replace(Some(header.id));
+        // The time of the nested polls is subtracted from the poll_time of
+        // this task, and added to the nested time of the spawning task.
+        let outer_nested = self.nested_poll_time.replace(Duration::new(0, 0));
+        let poll_start = Instant::now();
+        let res = self.with_budget(|| Pin::new(&mut future).poll(&mut ctx));
+        let elapsed = poll_start.elapsed();
+        let poll_time = elapsed.saturating_sub(self.nested_poll_time.replace(outer_nested + elapsed));
+        self.current_task.set(current_task);
+        *self.locals.borrow_mut() = locals;
+
+        // The task is still in the task_slab, even if it is aborted during 
+        // the poll, as the task_slab is only modified below.
+        {
+            let mut task_slab = self.task_slab.borrow_mut();
+            let stats = &mut task_slab[header.key].stats;
+            stats.polls += 1;
+            stats.poll_time += poll_time;
+        }
+
         match res {
             Poll::Pending => {
-                // If the task returns Pending, then the async task falls 
-                // into asleep. The waker is added into the timer heap, and 
-                // will wake this task up when the timer expires. We must 
-                // store the task into the task_map to keep task alive while
-                // waiting for the timer to expire.
-                self.task_map.borrow_mut().insert(task_id, task);
-            }
-            _ => {
-                // If the task returns Ready(()), then the task finishes,
-                // we don't have to track the task and can directly drop the task.
-                println!("the task finishes");
+                match header.state.compare_exchange(RUNNING, IDLE, AtomicOrdering::SeqCst, AtomicOrdering::SeqCst) {
+                    Ok(_) => {
+                        // If the task returns Pending, then the async task falls 
+                        // into asleep. We must store the future back into the 
+                        // task_slab to keep it alive while waiting for the wakeup.
+                        self.task_slab.borrow_mut()[header.key].future = Some(future);
+                    },
+                    Err(NOTIFIED) => {
+                        // The task is woken up during the poll, for instance by
+                        // yielding. Schedule it to run again.
+                        header.state.store(SCHEDULED, AtomicOrdering::SeqCst);
+                        self.task_slab.borrow_mut()[header.key].future = Some(future);
+                        self.run_queue.borrow_mut().push_back((header.clone(), Instant::now()));
+                    },
+                    Err(_) => {
+                        // The task is aborted during the poll. Drop the future
+                        // after releasing the RefMut to the task_slab.
+                        self.task_slab.borrow_mut().remove(header.key);
+                        drop(future);
+                        self.emit(TaskEvent::Aborted(header.id));
+                    },
+                }
+            },
+            Poll::Ready(()) => {
+                // If the task returns Ready(()), then the task finishes.
+                // Mark the header as COMPLETE, so that later wakeups are ignored,
+                // and release the slot in the task_slab.
+                header.state.store(COMPLETE, AtomicOrdering::SeqCst);
+                self.task_slab.borrow_mut().remove(header.key);
+                drop(future);
+                self.emit(TaskEvent::Finished(header.id));
             },
-            
         }
     }
 
-    // Acquire the next unique ID for a new task.
-    fn next_task_id(&self) -> usize {
-        let current_id = self.id_counter.get();
-        self.id_counter.set(current_id + 1);
-        current_id
+    // Run a poll with a full budget. The budget of the spawning task is 
+    // restored afterwards, as a spawned task is polled inside the poll of 
+    // the spawning task.
+    fn with_budget<R, F : FnOnce() -> R>(&self, f : F) -> R {
+        let budget = self.budget.replace(Some(coop::BUDGET));
+        let res = f();
+        self.budget.set(budget);
+        res
+    }
+
+    // Cancel all the remaining tasks in the order in which they are spawned.
+    // Each task is marked as COMPLETE, so that its pending wakeups are 
+    // ignored, and removed from the task_slab. Its future is dropped after
+    // releasing the RefMut to the task_slab, as dropping the future may 
+    // access the reactor, e.g. to cancel a timer, abort another task or 
+    // even spawn a new one. The new tasks are cancelled in the next round.
+    fn cancel_all(&self) {
+        while !self.task_slab.borrow().is_empty() {
+            let mut tasks : Vec<(u64, usize)> = self.task_slab.borrow().iter()
+                .map(|(key, task)| (task.header.id, key))
+                .collect();
+            tasks.sort_unstable();
+            for (id, key) in tasks {
+                // The task may have been aborted by a task dropped before it,
+                // and its key may even be reused by a new task.
+                let task = {
+                    let mut task_slab = self.task_slab.borrow_mut();
+                    match task_slab.get(key) {
+                        Some(task) if task.header.id == id => task_slab.remove(key),
+                        _ => continue,
+                    }
+                };
+                task.header.state.store(COMPLETE, AtomicOrdering::SeqCst);
+                drop(task);
+                self.emit(TaskEvent::Cancelled(id));
+            }
+        }
+    }
+
+    // Remove the tasks aborted from other threads. The task may have been
+    // cancelled in the meantime, and its key may even be reused by a new 
+    // task, so the header in the task_slab is compared with the aborted one.
+    fn drain_aborts(&self) {
+        let headers = std::mem::take(&mut *self.remote.aborted.lock().unwrap());
+        for header in headers {
+            let task = {
+                let mut task_slab = self.task_slab.borrow_mut();
+                match task_slab.get(header.key) {
+                    Some(task) if Arc::ptr_eq(&task.header, &header) => task_slab.remove(header.key),
+                    _ => continue,
+                }
+            };
+            drop(task);
+            self.emit(TaskEvent::Aborted(header.id));
+        }
+    }
+
+    // A single round of the event loop.
+    // may_sleep : Whether the reactor may wait for an event when there is no
+    // task to run. It is false if the root future of block_on is woken up.
+    // Without any task in the task_slab, only the root future of block_on 
+    // can be waiting for an event, so run_until_idle never lets the reactor
+    // sleep with an empty task_slab.
+    fn turn(&self, may_sleep : bool) {
+        // Obtain the duration from the start of the reactor to the 
+        // current time.
+        let expire = &self.clock.borrow().now();
+
+        // The event loop is separated into the following two parts.
+
+        // 1: The first part is the so-called reactor, which
+        // checks whether certain event happens and if so,
+        // wakes up the task associated with the event.
+        // In this implementation, the only event that may happen
+        // is timeout, which is generated by checking the timers stored
+        // in the timer_wheel. 
+
+        // Ask the timer_wheel for the deadline of the next timer.
+        // If that timer is not expired, we will sleep 
+        // until it expires.
+        // Note that a finished task may wake up the task waiting on its
+        // JoinHandle. In this case the run_queue is not empty and we
+        // should not sleep at all.
+        // The thread is parked instead of sleeping, so that it can be 
+        // unparked from another thread, either by a ShutdownHandle or by 
+        // waking up the root future of block_on. Without any timer, the 
+        // thread is parked until it is unparked. A spurious wakeup just
+        // starts another round.
+        // In the simulation mode, the virtual clock jumps to the deadline
+        // of the next timer instead. Without any timer, no task will ever
+        // be woken up in the simulation.
+        let mut timer_wheel = self.timer_wheel.borrow_mut();
+        if may_sleep && self.run_queue.borrow().is_empty() && !self.shutdown.is_requested() {
+            let simulated = self.clock.borrow().is_simulated();
+            match timer_wheel.next_expire() {
+                Some(next_expire) if simulated => self.clock.borrow_mut().advance_to(next_expire),
+                Some(next_expire) if *expire < next_expire => {
+                    thread::park_timeout(next_expire - *expire);
+                },
+                Some(_) => {},
+                None if simulated => panic!("the simulation is deadlocked, no task can be woken up"),
+                None => thread::park(),
+            }
+        }
+        // Advance the timer_wheel to the current time and iterate 
+        // through all the expired timers.
+        let now = self.clock.borrow().now();
+        for waker in timer_wheel.advance(now) {
+            // Wake up the task associated with the expired timer 
+            // by calling wake. The waker contains the header of the 
+            // task associated with this timer. The wake call
+            // will add the header into the run_queue.
+            waker.wake();
+        }
+        // Destroy the RefMut when we finish proessing the timer_wheel.
+        // Note: this is compulsory, as the executor part of the reactor
+        // may add new timers into the timer_wheel in case that the tasks
+        // need to sleep for a certain amount of time. If we keep this 
+        // RefMut alive, we will panic the program.
+        drop(timer_wheel);
+
+        // The thread may also be unparked by a task woken up from another
+        // thread, which is moved into the run_queue, or by a JoinHandle 
+        // aborting a task from another thread. The aborted tasks are dropped
+        // here, as they may cancel their timers.
+        let woken = std::mem::take(&mut *self.remote.woken.lock().unwrap());
+        self.run_queue.borrow_mut().extend(woken.into_iter().map(|header| (header, Instant::now())));
+        self.drain_aborts();
+
+        // 2. The second part is the executor. In this part, the reactor
+        // will schedule every resumable tasks stored in the run_queue
+        // to run again. 
+
+        // Iterate through the run_queue.
+        // Each item of the run_queue is the header of a task that 
+        // should be resumed. The run_queue of reactor may be modified 
+        // when polling the task, as a finished task wakes up the task 
+        // waiting on its JoinHandle. So we only create temporary RefMut 
+        // to the run_queue when popping the header.
+        // In the simulation mode, the run_queue is shuffled by the seeded
+        // scheduler before the tasks are resumed.
+        self.clock.borrow_mut().shuffle(&mut self.run_queue.borrow_mut());
+        let len = self.run_queue.borrow().len();
+        for _ in 0..len {
+            let (header, queued_at) = self.run_queue.borrow_mut().pop_front().unwrap();
+            // Only a SCHEDULED task can be resumed. The task may have been
+            // aborted through its JoinHandle after being scheduled, in 
+            // which case the header is COMPLETE and we just skip it.
+            if header.state.compare_exchange(SCHEDULED, RUNNING, AtomicOrdering::SeqCst, AtomicOrdering::SeqCst).is_err() {
+                continue;
+            }
+            // Take the future out of the task_slab and resume the task by polling.
+            let future = {
+                let mut task_slab = self.task_slab.borrow_mut();
+                let task = &mut task_slab[header.key];
+                task.stats.queue_time += queued_at.elapsed();
+                task.future.take().unwrap()
+            };
+            self.poll_task(&header, future);
+        }
     }
 
     // The actual event loop that keeps everything running.
-    fn run<F : Future<Output = ()> + 'static + Send>(&self, f:F) {
+    fn run<F : Future<Output = ()> + 'static>(&self, f:F) {
+        let _guard = self.enter();
         // Spawn the initial task.
-        self.do_spawn(f);
+        // The future object is stored on the heap, this makes the task freely movable.
+        self.do_spawn(None, LocalFutureObj::new(Box::new(f)));
+        self.run_until_idle();
+    }
 
-        loop {
-            // Obtain the current eventloop running time.
-            let event_loop_tick = Instant::now();
-            // Calculate the duration from the current time to 
-            // the start of the event loop.
-            let expire = &(event_loop_tick - self.start_time);
-
-            // The event loop is separated into the following two parts.
-
-            // 1: The first part is the so-called reactor, which
-            // checks whether certain event happens and if so,
-            // wakes up the task associated with the event.
-            // In this implementation, the only event that may happen
-            // is timeout, which is generated by checking the timers stored
-            // in the timer_heap. 
-
-            // Check the first element of the timer_heap, which 
-            // stores the timer that is the first to expire.
-            // If that timer is not expired, we will sleep 
-            // until it expires.
-            let mut timer_heap = self.timer_heap.borrow_mut();
-            timer_heap.peek().map(|next_timer| {
-                if *expire < next_timer.0.wakeup_duration {
-                    std::thread::sleep(next_timer.0.wakeup_duration - *expire);
-                }
-            });
-            // Iterate through all the expired timers.
-            while timer_heap.peek().map_or(false, |next_timer| {
-                *expire > next_timer.0.wakeup_duration
-            }) {
-                // Wake up the task associated with the expired timer 
-                // by calling wake_by_ref. The waker contains the task 
-                // ID associated with this timer. The wake_by_ref call
-                // will add the task ID into the run_queue.
-                let timer = timer_heap.pop().unwrap();
-                timer.0.waker.wake_by_ref();
+    // Run the event loop until all the tasks finish, or until the shutdown 
+    // is requested, in which case the remaining tasks are cancelled.
+    // Whether there are pending tasks is checked before each round, as the
+    // tasks may all finish as soon as they are spawned. A round with an 
+    // empty task_slab and no timer would park the thread forever.
+    fn run_until_idle(&self) {
+        while !self.task_slab.borrow().is_empty() {
+            self.turn(true);
+
+            // Check whether a shutdown is requested.
+            if self.shutdown.is_requested() {
+                self.cancel_all();
             }
-            // Destroy the RefMut when we finish proessing the timer_heap.
-            // Note: this is compulsory, as the executor part of the reactor
-            // may add new timers into the timer_heap in case that the tasks
-            // need to sleep for a certain amount of time. If we keep this 
-            // RefMut alive, we will panic the program.
-            drop(timer_heap);
-
-            // 2. The second part is the executor. In this part, the reactor
-            // will schedule every resumable tasks stored in the run_queue
-            // to run again. 
-
-            // Iterate through the run_queue.
-            // Each item of the run_queue contains an ID which is 
-            // linked to a task that should be resumed.  
-            let mut run_queue = self.run_queue.borrow_mut();
-            for _ in 0..run_queue.len() {
-                // Remove the task from the task_map
-                let needrun = run_queue.pop_front().unwrap();
-                let mut task = self.task_map.borrow_mut().remove(&needrun.task_id).unwrap();
-                // Resume the task by polling.
-                // Note that a task may do the following two things when being executed:
-                // 1. Append a new timer to the timer_heap and wait for some time
-                // 2. Spawn a new async task and insert the task inside the task_map.
-                // Whether using a single RefMut to access the core data structures 
-                // depends on the following analysis:
-                // 1. The run_queue of reactor is not touched by task.poll, so holding a 
-                // RefMut to the run_queue is fine.
-                // 2. The timer_heap may be modified by task.poll, so holding a RefMut 
-                // to timer_heap when executing task.poll will panic the program. 
-                // Since we drop the RefMut to timer_heap  at the end of the reactor part, 
-                // this works fine too.
-                // 3. The task_map may be modified by task.poll, so we can't hold a RefMut
-                // to the task_map when executing task.poll. This is why we create temporary
-                // RefMut to task_map before and after the call to task.poll
-                match task.poll(needrun.waker) {
-                    Poll::Pending => {
-                        // If the task blocks again, insert the task back into the 
-                        // task_map and wait for later wakeup.
-                        self.task_map.borrow_mut().insert(needrun.task_id, task);
-                    },
-                    _ => {
-                        // If the task finishes, just continue and drops the finished
-                        // task.
-                        println!("task finishes");
-                    }
+        }
+    }
+
+    // Run the event loop until the root future completes, and return the 
+    // output of the root future.
+    // Unlike the tasks, the root future is not stored in the task_slab. It 
+    // stays pinned on the stack of block_on, so it can borrow local variables
+    // and need not be Send. The root future is woken up through a RootWaker,
+    // and polled at the start of a round only if it has been woken up.
+    // The tasks that are still alive when the root future completes are 
+    // cancelled. A shutdown cancels the tasks, but not the root future, which
+    // can learn about the shutdown through the JoinHandles of its tasks.
+    fn block_on<F : Future>(&self, f : F) -> F::Output {
+        let _guard = self.enter();
+        let root = Arc::new(RootWaker {
+            woken : AtomicBool::new(true),
+            thread : thread::current(),
+        });
+        let waker = futures_task::waker(root.clone());
+        let mut ctx = Context::from_waker(&waker);
+        let mut f = std::pin::pin!(f);
+
+        loop {
+            if root.woken.swap(false, AtomicOrdering::SeqCst) {
+                if let Poll::Ready(output) = self.with_budget(|| f.as_mut().poll(&mut ctx)) {
+                    self.cancel_all();
+                    return output;
                 }
             }
 
-            // Check whether there are pending tasks. 
-            // If not, stops the eventloop.
-            if self.task_map.borrow().len() == 0 {
-                break;
+            if self.shutdown.is_requested() {
+                self.cancel_all();
             }
+
+            self.turn(!root.woken.load(AtomicOrdering::SeqCst));
         }
     }
 }
 
+// The guard returned by Reactor::enter.
+// If the event loop is left by a panic, e.g. from a task, the remaining 
+// tasks are cancelled, so that no task outlives the entry point. Scoped 
+// tasks rely on this to never outlive the data that they borrow.
+struct EnterGuard<'a> {
+    reactor : &'a Reactor,
+}
+
+impl Drop for EnterGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.reactor.cancel_all();
+        }
+        self.reactor.shutdown.requested.store(false, AtomicOrdering::SeqCst);
+        self.reactor.running.set(false);
+    }
+}
+
 // The reactor is stored inside a thread local storage and read-only.
 thread_local! {
     static REACTOR : Reactor = Reactor::new()
 }
 
-// The implementation of the waker, which only contains a task_id.
-struct WakerImpl {
-    task_id : usize,
+// The states of a task recorded in its header.
+// IDLE : The task is waiting for a wakeup.
+// SCHEDULED : The header of the task is in the run_queue.
+// RUNNING : The task is being polled.
+// NOTIFIED : The task is woken up while being polled, and should be 
+// scheduled again after the poll.
+// COMPLETE : The task has finished or been aborted.
+const IDLE : usize = 0;
+const SCHEDULED : usize = 1;
+const RUNNING : usize = 2;
+const NOTIFIED : usize = 3;
+const COMPLETE : usize = 4;
+
+// The reference-counted header of a task, which is shared by the task and 
+// all of its wakers.
+// id : The ID of the task, which is unique and increases in spawn order.
+// key : The key of the task in the task_slab.
+// state : The state of the task. Waking up a task that is already SCHEDULED
+// does nothing, so duplicate wakeups are coalesced into a single poll.
+// wakeups : The number of times the task is woken up. It is kept in the 
+// header, as the waker may be called while the task is being polled.
+// remote : The remote queue of the reactor running the task, used when the
+// task is woken up or aborted from another thread.
+struct TaskHeader {
+    id : u64,
+    key : usize,
+    state : AtomicUsize,
+    wakeups : AtomicU64,
+    remote : Arc<RemoteQueue>,
 }
 
-// Implementing the ArcWake trait for the WakerImpl.
-impl ArcWake for WakerImpl {
-    // When a timer is popped off from the timer_heap, it needs to
-    // wakeup the corresponding task. It does so by constructing 
-    // a new NeedRun and inserting it into the run_queue.
+// Implementing the ArcWake trait for the TaskHeader.
+impl ArcWake for TaskHeader {
+    // When a timer expires in the timer_wheel, it needs to
+    // wakeup the corresponding task. It does so by pushing the 
+    // header of the task into the run_queue. The run_queue belongs to
+    // the thread of the reactor, so a task woken up from another thread
+    // goes through the remote queue instead, and the reactor is unparked.
     fn wake_by_ref(arc_self : &Arc<Self>) {
-        // Construct a new NeedRun.
-        let next_need_run = NeedRun {
-            task_id : (**arc_self).task_id,
-            waker : futures_task::waker(arc_self.clone()),
-        };
-        // Insert the NeedRun into the run queue. 
-        // This is called in the reactor part, and acquring 
-        // a RefMut to the run_queue is safe.
-        REACTOR.with(|reactor|{
-            reactor.run_queue.borrow_mut().push_back(next_need_run);
-        });
+        arc_self.wakeups.fetch_add(1, AtomicOrdering::Relaxed);
+        let state = &arc_self.state;
+        loop {
+            let current = state.load(AtomicOrdering::SeqCst);
+            let next = match current {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                // The task is already going to be polled, or has finished.
+                _ => return,
+            };
+            if state.compare_exchange(current, next, AtomicOrdering::SeqCst, AtomicOrdering::SeqCst).is_err() {
+                continue;
+            }
+            if next == SCHEDULED && !arc_self.remote.is_local() {
+                arc_self.remote.woken.lock().unwrap().push(arc_self.clone());
+                arc_self.remote.thread.unpark();
+            } else if next == SCHEDULED {
+                // Insert the header into the run queue. 
+                // The RefMut to the run_queue is never held when polling
+                // a task, so acquiring it here is safe.
+                REACTOR.with(|reactor|{
+                    reactor.run_queue.borrow_mut().push_back((arc_self.clone(), Instant::now()));
+                });
+            }
+            return;
+        }
     }
 }
 
-// An item stored in the run_queue, indicating which task should 
-// be woken up and resumed.
-// task_id : the ID of the task which needs to be resumed.
-// waker : the waker passed in as the context when calling poll.
-struct NeedRun {
-    task_id : usize,
-    waker : Waker,
-}
-
 // The actual representation of an asynchronous task in this implementation.
-// task : A box pointing to an heap-allocated area for storing the future state machine.
+// header : The header of the task, used to mark the task as COMPLETE when
+// the task is cancelled.
+// name : The optional name of the task.
+// future : A box pointing to an heap-allocated area for storing the future 
+// state machine. It is None while the task is being polled.
+// stats : The poll statistics of the task.
 struct Task {
-    task : FutureObj<'static, ()>,
+    header : Arc<TaskHeader>,
+    name : Option<String>,
+    future : Option<LocalFutureObj<'static, ()>>,
+    stats : TaskStats,
 }
 
-impl Task {
-    // A wrapper to poll the contained FutureObj. 
-    // This wrapper prepares the arguments for calling
-    // Future::Poll
-    fn poll(&mut self, waker : Waker) -> Poll<()> {
-        let pinned = Pin::new(&mut self.task);
-        let mut ctx = Context::from_waker(&waker);
-        Future::poll(pinned, &mut ctx)    
+// The waker of the root future of block_on. Waking up the root future
+// unparks the thread of the reactor, which may be parked waiting for a timer.
+// woken : Whether the root future should be polled again.
+// thread : The thread running the reactor.
+struct RootWaker {
+    woken : AtomicBool,
+    thread : Thread,
+}
+
+impl ArcWake for RootWaker {
+    fn wake_by_ref(arc_self : &Arc<Self>) {
+        arc_self.woken.store(true, AtomicOrdering::SeqCst);
+        arc_self.thread.unpark();
+    }
+}
+
+// The lifecycle events of the tasks, reported to the event hook with the
+// ID of the task.
+// Spawned : The task is spawned.
+// Finished : The task runs to completion.
+// Aborted : The task is aborted through its JoinHandle.
+// Cancelled : The task is cancelled by a shutdown, or by block_on
+// when the root future completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskEvent {
+    Spawned(u64),
+    Finished(u64),
+    Aborted(u64),
+    Cancelled(u64),
+}
+
+// The shutdown state shared between the reactor and its ShutdownHandles.
+// requested : Whether a shutdown is requested.
+// thread : The thread running the reactor, which is unparked to handle 
+// the shutdown request right away.
+struct ShutdownState {
+    requested : AtomicBool,
+    thread : Thread,
+}
+
+impl ShutdownState {
+    fn is_requested(&self) -> bool {
+        self.requested.load(AtomicOrdering::SeqCst)
+    }
+}
+
+// A handle for shutting down the reactor of a thread. The handle is Send,
+// so the shutdown can be requested from another thread as well. The request
+// is handled at the end of the current round of the event loop, and is
+// cleared once the event loop returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state : Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    // Cancel all the tasks of the reactor in spawn order.
+    pub fn shutdown(&self) {
+        self.state.requested.store(true, AtomicOrdering::SeqCst);
+        self.state.thread.unpark();
     }
 }
 
-// Implementation of the PartialEq trait for Timer
-impl PartialEq<Timer> for Timer {
-    fn eq(&self, other: &Timer) -> bool {
-        self.wakeup_duration == other.wakeup_duration
+// The tasks woken up or aborted on other threads, which are handled by the
+// reactor in the next round of the event loop. The run_queue and the 
+// task_slab can only be accessed from the thread of the reactor.
+// woken : The headers of the tasks woken up, which are already SCHEDULED.
+// aborted : The headers of the tasks aborted through their JoinHandles.
+// thread : The thread running the reactor, which is unparked so that the
+// tasks are handled right away.
+struct RemoteQueue {
+    woken : Mutex<Vec<Arc<TaskHeader>>>,
+    aborted : Mutex<Vec<Arc<TaskHeader>>>,
+    thread : Thread,
+}
+
+impl RemoteQueue {
+    fn is_local(&self) -> bool {
+        thread::current().id() == self.thread.id()
     }
 }
 
-// Implementation of the Eq trait for Timer
-impl Eq for Timer {}
+// The state shared between a spawned task and its JoinHandle.
+// output : The output of the task, which is set when the task finishes.
+// waker : The waker of the task that waits on the JoinHandle.
+// aborted : Whether the task has been aborted through the JoinHandle.
+struct JoinState<T> {
+    output : Option<T>,
+    waker : Option<Waker>,
+    aborted : bool,
+}
 
-// Implementation of the PartialOrder trait for Timer
-impl PartialOrd<Timer> for Timer {
-    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
-        if self.wakeup_duration == other.wakeup_duration {
-            Some(Ordering::Equal)
+// The future that is actually stored in the task_slab when spawning a
+// future with an arbitrary output type. It polls the user future and
+// hands over the output to the JoinHandle when the user future finishes.
+// future : The user future, pinned on the heap so that JoinTask is Unpin.
+// It is set to None when the user future finishes.
+// state : The state shared with the JoinHandle.
+struct JoinTask<F : Future> {
+    future : Option<Pin<Box<F>>>,
+    state : Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F : Future> Future for JoinTask<F> {
+    type Output = ();
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        match self_mut.future.as_mut().unwrap().as_mut().poll(ctx) {
+            Poll::Ready(output) => {
+                self_mut.future = None;
+                // Store the output and wake up the task waiting on the 
+                // JoinHandle. The waker is called after releasing the lock.
+                let waker = {
+                    let mut state = self_mut.state.lock().unwrap();
+                    state.output = Some(output);
+                    state.waker.take()
+                };
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
         }
-        else if self.wakeup_duration > other.wakeup_duration {
-            Some(Ordering::Greater)
+    }
+}
+
+// A JoinTask dropped before the user future finishes is either aborted or
+// cancelled. Either way, the task waiting on the JoinHandle is woken up to 
+// learn that no output will ever arrive.
+impl<F : Future> Drop for JoinTask<F> {
+    fn drop(&mut self) {
+        if self.future.is_none() {
+            return;
         }
-        else {
-            Some(Ordering::Less)
+        let waker = {
+            let mut state = self.state.lock().unwrap();
+            state.aborted = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
         }
     }
 }
 
-// Implementation of the Order trait for Timer
-impl Ord for Timer {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+// The error returned by awaiting a JoinHandle, if the task is aborted 
+// or cancelled before it finishes.
+#[derive(Debug, PartialEq, Eq)]
+pub enum JoinError {
+    Aborted,
+}
+
+// A handle to a spawned task. Awaiting the handle yields the output of 
+// the task. Dropping the handle detaches the task, which keeps running
+// in the reactor.
+// header : The header of the spawned task, used to locate the task in task_slab.
+// state : The state shared with the JoinTask.
+pub struct JoinHandle<T> {
+    header : Arc<TaskHeader>,
+    state : Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    // Abort the task. The task is removed from the task_slab and its 
+    // FutureObj is dropped, so that whatever the task holds, e.g. a 
+    // Timeout, is dropped as well. Aborting a finished task has no effect.
+    // On another thread than the one of the reactor, the task is handed 
+    // over to the reactor, which drops it in its next round.
+    pub fn abort(&self) {
+        let previous = self.header.state.swap(COMPLETE, AtomicOrdering::SeqCst);
+        if previous == COMPLETE {
+            return;
+        }
+        self.state.lock().unwrap().aborted = true;
+        // A RUNNING task is aborting itself, its future is dropped by 
+        // the reactor once the poll returns.
+        if previous == RUNNING || previous == NOTIFIED {
+            return;
+        }
+        let remote = &self.header.remote;
+        if !remote.is_local() {
+            remote.aborted.lock().unwrap().push(self.header.clone());
+            remote.thread.unpark();
+            return;
+        }
+        // Drop the task after the RefMut to task_slab is released, as dropping
+        // the future may access the reactor again.
+        REACTOR.with(|reactor| {
+            let task = reactor.task_slab.borrow_mut().remove(self.header.key);
+            drop(task);
+            reactor.emit(TaskEvent::Aborted(self.header.id));
+        });
     }
 }
 
-// The item stored in the timer_heap.
-// wakeup_duration : The duration from the start time of the reactor to the 
-// time that this timer expires. If wakeup_duration is shorter than the duration
-// from the start time to the current instant, then the timer expires and should 
-// wake up the corresponding task.
-// waker : When the timer expires, the event loop calls waker.wake_by_ref
-// to insert the NeedRun into run_queue.
-struct Timer {
-    wakeup_duration : Duration,
-    waker : Waker,
+// JoinHandle is Unpin, the shared state is stored on the heap.
+impl<T> Unpin for JoinHandle<T> {}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let mut state = self.state.lock().unwrap();
+        // A task that finishes before being aborted still yields its output.
+        if let Some(output) = state.output.take() {
+            Poll::Ready(Ok(output))
+        }
+        else if state.aborted {
+            Poll::Ready(Err(JoinError::Aborted))
+        }
+        else {
+            state.waker = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }
 }
 
 // A future object that sleeps for a certain amount of time 
 // as indicated in the contained duration and resumes the execution
 // after sleep.
+// duration : The time to sleep, which is set to 0 once the timer is inserted.
+// key : The key of the timer inserted into the timer_wheel, used for 
+// checking whether the timer has expired and cancelling the timer on drop.
 struct Timeout {
     duration : Duration,
+    key : Option<TimerKey>,
 }
 
 // Timeout is Unpin, as we need to change the 
@@ -298,7 +832,8 @@ impl Unpin for Timeout {}
 impl Timeout {
     fn new(duration : Duration) -> Self {
         Timeout {
-            duration : duration,
+            duration,
+            key : None,
         }
     }
 }
@@ -306,59 +841,183 @@ impl Timeout {
 impl Future for Timeout {
     type Output = ();
 
-    // Poll the Timeout object. Depending on the content of the contained duration,
-    // the task will do the following two things:
-    // 1. If the contained duration is 0, then either the task has resumed after sleep,
-    // or the task does not need to sleep at all. A Poll::Ready(()) is returned to
-    // resume the execution of the task.
-    // 2. If the contained duration is not zero, then we insert a Timer into the timer_heap,
-    // and returns Poll::Pending to suspend the execution of the task.
+    // Poll the Timeout object. Depending on the content of the contained key,
+    // the task will do the following things:
+    // 1. If there is no key and the contained duration is 0, the task does not 
+    // need to sleep at all. A Poll::Ready(()) is returned.
+    // 2. If there is no key and the duration is not zero, then we insert a timer 
+    // into the timer_wheel, and returns Poll::Pending to suspend the execution of the task.
+    // 3. If there is a key, we check whether the timer is still in the timer_wheel.
+    // If so, the task is polled before the timer expires, e.g. by a combinator, 
+    // and Poll::Pending is returned again. Otherwise, the timer has expired and 
+    // a Poll::Ready(()) is returned to resume the execution of the task.
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.duration == Duration::new(0, 0) {
-            Poll::Ready(())
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
         }
-        else {
-            // Prepare the variables.
-            let duration = self.duration.clone();
-            self.get_mut().duration = Duration::new(0, 0);
-            let waker = ctx.waker().clone();
-            
-            REACTOR.with(|reactor|{
-                // Construct a timer.
-                let timer = Timer {
-                    wakeup_duration : (Instant::now() - reactor.start_time) + duration,
-                    waker : waker
-                };
-                // Insert the timer into the reactor.
-                reactor.timer_heap.borrow_mut().push(Reverse(timer));
-            });
+        let self_mut = self.get_mut();
+        match self_mut.key {
+            Some(key) => {
+                let pending = REACTOR.with(|reactor| {
+                    let mut timer_wheel = reactor.timer_wheel.borrow_mut();
+                    timer_wheel.update_waker(key, ctx.waker());
+                    timer_wheel.contains(key)
+                });
+                if pending {
+                    Poll::Pending
+                }
+                else {
+                    self_mut.key = None;
+                    Poll::Ready(())
+                }
+            },
+            None if self_mut.duration == Duration::new(0, 0) => Poll::Ready(()),
+            None => {
+                let duration = self_mut.duration;
+                self_mut.duration = Duration::new(0, 0);
+                let waker = ctx.waker().clone();
 
-            Poll::Pending
+                self_mut.key = Some(REACTOR.with(|reactor|{
+                    // Insert the timer into the reactor.
+                    let deadline = reactor.clock.borrow().now().saturating_add(duration);
+                    reactor.timer_wheel.borrow_mut().insert(deadline, waker)
+                }));
+
+                Poll::Pending
+            },
+        }
+    }
+}
+
+// Dropping a Timeout that has not expired removes its timer from the 
+// timer_wheel, so that the timer no longer wakes up the task.
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            // The reactor may have been destroyed if the Timeout is dropped
+            // during the destruction of the thread-local storage.
+            let _ = REACTOR.try_with(|reactor| {
+                reactor.timer_wheel.borrow_mut().cancel(key);
+            });
         }
     }
 }
 
+// Report the number of pending, fired and cancelled timers of the reactor 
+// running on the current thread.
+pub fn timer_stats() -> TimerStats {
+    REACTOR.with(|reactor| reactor.timer_stats())
+}
+
 // The entry point of the async eventloop.
-fn run<F : Future<Output = ()> + 'static + Send>(f : F) {
+fn run<F : Future<Output = ()> + 'static>(f : F) {
     REACTOR.with(|reactor| {
         reactor.run(f);
     });
 }
 
-// Spawning a new Future task inside the eventloop.
-fn spawn<F : Future<Output = ()> + 'static + Send>(f : F) {
+// Run the future on the current thread and return its output as soon as
+// it completes. The tasks spawned by the future that are still alive are
+// cancelled.
+pub fn block_on<F : Future>(f : F) -> F::Output {
     REACTOR.with(|reactor| {
-        reactor.do_spawn(f);
+        reactor.block_on(f)
+    })
+}
+
+// Take a snapshot of the live tasks of the reactor running on the current
+// thread, in spawn order.
+pub fn tasks() -> Vec<TaskInfo> {
+    REACTOR.with(|reactor| reactor.tasks())
+}
+
+// Print a table of the live tasks of the reactor running on the current 
+// thread, to diagnose tasks that never get woken up.
+pub fn dump_tasks() {
+    print!("{}", TaskTable(&tasks()));
+}
+
+// The ID of the task being polled by the reactor of the current thread, 
+// None outside of a task, e.g. in the root future of block_on.
+pub fn current_task_id() -> Option<u64> {
+    REACTOR.with(|reactor| reactor.current_task.get())
+}
+
+// Get a handle for shutting down the reactor of the current thread.
+pub fn shutdown_handle() -> ShutdownHandle {
+    REACTOR.with(|reactor| ShutdownHandle {
+        state : reactor.shutdown.clone(),
+    })
+}
+
+// Install a hook that is called on the lifecycle events of the tasks 
+// running in the reactor of the current thread, replacing the previous one.
+pub fn set_event_hook<H : Fn(TaskEvent) + 'static>(hook : H) {
+    REACTOR.with(|reactor| {
+        *reactor.event_hook.borrow_mut() = Some(Box::new(hook));
+    });
+}
+
+// Spawning a new Future task inside the eventloop.
+// The returned JoinHandle can be used to retrieve the output of the task
+// or to abort the task.
+pub fn spawn<F>(f : F) -> JoinHandle<F::Output> 
+    where F : Future + 'static + Send, F::Output : Send + 'static
+{
+    unsafe { do_spawn(None, f) }
+}
+
+// Spawning a new Future task with a name, which shows up in dump_tasks.
+pub fn spawn_named<F>(name : &str, f : F) -> JoinHandle<F::Output> 
+    where F : Future + 'static + Send, F::Output : Send + 'static
+{
+    unsafe { do_spawn(Some(name.to_string()), f) }
+}
+
+// Spawning a new Future task that is not Send, e.g. a task holding an Rc. 
+// This is fine as the task never leaves the thread of the reactor.
+pub fn spawn_local<F>(f : F) -> JoinHandle<F::Output> 
+    where F : Future + 'static, F::Output : 'static
+{
+    unsafe { do_spawn(None, f) }
+}
+
+// Wrap the future in a JoinTask and spawn it in the reactor of the current
+// thread. The lifetime of the future is erased.
+// Safety : The task must finish or be dropped before 'a ends. This holds
+// trivially if 'a is 'static.
+unsafe fn do_spawn<'a, F>(name : Option<String>, f : F) -> JoinHandle<F::Output> 
+    where F : Future + 'a
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output : None,
+        waker : None,
+        aborted : false,
+    }));
+    let join_task = JoinTask {
+        future : Some(Box::pin(f)),
+        state : state.clone(),
+    };
+    let future : LocalFutureObj<'a, ()> = LocalFutureObj::new(Box::new(join_task));
+    let future : LocalFutureObj<'static, ()> = unsafe { std::mem::transmute(future) };
+    let header = REACTOR.with(|reactor| {
+        reactor.do_spawn(name, future)
     });
+    JoinHandle {
+        header,
+        state,
+    }
 }
 
 // Desugar the async blocks
 
-#[allow(dead_code)]
+// The log lines are tagged with the ID of the task and the span.
 async fn sleep_sub_task(id : i32) {
-    println!("sleep sub-task {} is created", id);
-    Timeout::new(Duration::from_secs(10)).await;
-    println!("sleep sub-task {} finishes", id);
+    span::in_span("sleep_sub_task", async move {
+        span_println!("sleep sub-task {} is created", id);
+        Timeout::new(Duration::from_secs(10)).await;
+        span_println!("sleep sub-task {} finishes", id);
+    }).await
 }
 
 // Desugared version of sleep_sub_task.
@@ -381,7 +1040,7 @@ impl Future for SleepSubTask {
 
                     // Print the sub-task information, exactly the same as 
                     // sleep_sub_task.
-                    println!("sleep sub-task {} is created", *id);
+                    span_println!("sleep sub-task {} is created", *id);
                     // Create the Timeout and sleep for 10s, similar with 
                     // sleep_sub_task, except that the created Timeout is 
                     // assigned to the to variable.
@@ -419,7 +1078,7 @@ impl Future for SleepSubTask {
                 SleepSubTask::PostSleep(id) => {
                     // Print that the sub-task has ended sleep, exactly the same as 
                     // sleep_sub_task.
-                    println!("sleep sub-task {} finishes", *id);
+                    span_println!("sleep sub-task {} finishes", *id);
                     // Additional code that is hidden away in sleep_sub_task,
                     // which returns Poll::Ready to indicate the async task 
                     // has finished execution.
@@ -516,7 +1175,7 @@ impl Future for SleepTask {
                     // Create a new sleep sub task. This is exactly 
                     // the same as in sleep_task.
                     println!("create sleep sub task {}", *i);
-                    spawn(SleepSubTask::Entry(*i));
+                    spawn(span::in_span("sleep_sub_task", SleepSubTask::Entry(*i)));
                     // Change the state to PreSleep to continue the loop
                     *self_mut = SleepTask::PreSleep(*i + 1);
                     continue;
@@ -526,6 +1185,769 @@ impl Future for SleepTask {
     }
 }
 
+// A producer/consumer pipeline built on the channels.
+// The producer sends the numbers 1 to 10 into a channel with capacity 2,
+// so it is suspended whenever the squarer falls behind. The squarer forwards
+// the squares to the consumer, which reports the sum back through a oneshot
+// channel once the producer finishes and the channels are closed. The sum is
+// the output of the pipeline, which is returned by block_on.
+async fn producer(tx : mpsc::Sender<u64>) {
+    for i in 1..11 {
+        println!("producer sends {}", i);
+        tx.send(i).await.unwrap();
+    }
+}
+
+async fn squarer(mut rx : mpsc::Receiver<u64>, tx : mpsc::Sender<u64>) {
+    while let Some(i) = rx.recv().await {
+        // Pretend that squaring takes some time.
+        Timeout::new(Duration::from_millis(100)).await;
+        tx.send(i * i).await.unwrap();
+    }
+}
+
+async fn consumer(mut rx : mpsc::Receiver<u64>, done : oneshot::Sender<u64>) {
+    let mut sum = 0;
+    while let Some(square) = rx.recv().await {
+        println!("consumer receives {}", square);
+        sum += square;
+    }
+    let _ = done.send(sum);
+}
+
+async fn pipeline() -> u64 {
+    let (number_tx, number_rx) = mpsc::channel(2);
+    let (square_tx, square_rx) = mpsc::channel(2);
+    let (done_tx, done_rx) = oneshot::channel();
+    spawn_named("producer", producer(number_tx));
+    spawn_named("squarer", squarer(number_rx, square_tx));
+    spawn_named("consumer", consumer(square_rx, done_tx));
+    // The squarer has taken the first number and sleeps, which wakes up the
+    // producer waiting on the full channel. The consumer has never been 
+    // woken up yet.
+    dump_tasks();
+    done_rx.await.unwrap()
+}
+
+// A group of workers coordinated by the sync primitives.
+// At most 2 workers run at the same time, as limited by the semaphore. Each
+// worker adds its id to a counter guarded by the async mutex, and then waits
+// on the barrier for the others. The leader of the barrier wakes up the
+// observers waiting on the Notify, and notifies the demo itself.
+async fn worker(id : u64, pool : Arc<Semaphore>, counter : Arc<AsyncMutex<u64>>, 
+                barrier : Arc<Barrier>, done : Arc<Notify>) {
+    {
+        let _permit = pool.acquire().await;
+        println!("worker {} starts, {} permits left", id, pool.available_permits());
+        Timeout::new(Duration::from_millis(100)).await;
+        *counter.lock().await += id;
+    }
+    if barrier.wait().await.is_leader {
+        println!("worker {} releases the group", id);
+        done.notify_waiters();
+        done.notify_one();
+    }
+}
+
+async fn observer(id : u64, counter : Arc<AsyncMutex<u64>>, done : Arc<Notify>) {
+    done.notified().await;
+    println!("observer {} sees counter {}", id, *counter.lock().await);
+}
+
+async fn workers() {
+    let pool = Arc::new(Semaphore::new(2));
+    let counter = Arc::new(AsyncMutex::new(0));
+    let barrier = Arc::new(Barrier::new(4));
+    let done = Arc::new(Notify::new());
+    let observers : Vec<_> = (0..2).map(|id| spawn(observer(id, counter.clone(), done.clone()))).collect();
+    for id in 1..5 {
+        spawn(worker(id, pool.clone(), counter.clone(), barrier.clone(), done.clone()));
+    }
+    // The permit of notify_one is kept even if the leader notifies before
+    // the demo starts waiting.
+    done.notified().await;
+    for observer in observers {
+        observer.await.unwrap();
+    }
+    // All the workers have released their permits.
+    let _all = pool.acquire_many(2).await;
+    println!("all workers finish, counter is {}", *counter.lock().await);
+}
+
+// Racing and joining futures within a single task.
+// The fast Timeout wins the select, and the slow one is dropped and its timer
+// cancelled. The sleep_sub_tasks are bounded by a deadline shorter than their
+// 10s sleep, so all of them are dropped with Elapsed.
+async fn combinators() {
+    let fast = Timeout::new(Duration::from_millis(100));
+    let slow = Timeout::new(Duration::from_secs(10));
+    if let Either::Left(()) = select(fast, slow).await {
+        println!("the fast timeout wins the race");
+    }
+
+    let (_, i) = select_all((1..4).map(|i| Timeout::new(Duration::from_millis(300 - 100 * i))).collect()).await;
+    println!("timeout {} finishes first", i);
+
+    let bounded = (0..3).map(|id| with_timeout(sleep_sub_task(id), Duration::from_millis(200))).collect();
+    for res in join_all(bounded).await {
+        println!("bounded sleep sub-task: {:?}", res);
+    }
+}
+
+task_local! {
+    static REQUEST_ID : u64;
+}
+
+// A handler that reads the ID of its request from the task-local, without
+// having it passed down through every call.
+async fn handle_request() -> u64 {
+    Timeout::new(Duration::from_millis(10 * (3 - REQUEST_ID.get()))).await;
+    span_println!("request {} is handled", REQUEST_ID.get());
+    REQUEST_ID.get() * 100
+}
+
+// Each request is handled in its own task, with its own REQUEST_ID.
+async fn requests() {
+    let handles : Vec<_> = (0..3).map(|id| spawn(REQUEST_ID.scope(id, handle_request()))).collect();
+    for handle in handles {
+        println!("the response is {}", handle.await.unwrap());
+    }
+}
+
+// A CPU-bound loop that yields every 10000 iterations, so that the ticker
+// keeps ticking while the sum is being computed.
+async fn busy_sum() {
+    let ticker = spawn(async {
+        for i in 0..3 {
+            Timeout::new(Duration::from_millis(1)).await;
+            println!("tick {}", i);
+        }
+    });
+    let mut sum = 0u64;
+    for i in 0..3_000_000u64 {
+        sum += i;
+        if i % 10_000 == 0 {
+            coop::yield_now().await;
+        }
+    }
+    println!("the busy sum is {}", sum);
+    ticker.await.unwrap();
+}
+
+// Tasks sharing state through an Rc<Cell>, which is not Send, and scoped
+// tasks borrowing the local variables of the enclosing function.
+fn local_tasks() {
+    let words = ["future", "waker", "reactor"];
+    let lengths = RefCell::new(Vec::new());
+    scoped::scope(|scope| {
+        for word in words.iter() {
+            let lengths = &lengths;
+            scope.spawn(async move {
+                Timeout::new(Duration::from_millis(10 * word.len() as u64)).await;
+                lengths.borrow_mut().push(word.len());
+            });
+        }
+    });
+    // All the scoped tasks have finished once scope returns.
+    println!("word lengths in finishing order: {:?}", lengths.into_inner());
+
+    let total = Rc::new(Cell::new(0));
+    let shared = total.clone();
+    run(async move {
+        for i in 1..4 {
+            let shared = shared.clone();
+            spawn_local(async move {
+                Timeout::new(Duration::from_millis(10 * i)).await;
+                shared.set(shared.get() + i);
+            });
+        }
+    });
+    println!("the sum of the local tasks is {}", total.get());
+}
+
+// Heartbeat tasks that never finish on their own. They are cancelled in 
+// spawn order when another thread shuts down the reactor.
+async fn heartbeat(id : u64) {
+    loop {
+        Timeout::new(Duration::from_millis(500)).await;
+        println!("heartbeat {}", id);
+    }
+}
+
+fn shutdown_after(duration : Duration) {
+    let shutdown = shutdown_handle();
+    thread::spawn(move || {
+        thread::sleep(duration);
+        shutdown.shutdown();
+    });
+    run(async {
+        for id in 0..3 {
+            spawn(heartbeat(id));
+        }
+    });
+}
+
+// The SleepTask of launch and its sleep sub-tasks take 20s in the real time.
+// In the simulation, they print the same output without waiting, and the
+// virtual clock still reports the 20s.
+pub fn simulation(seed : u64) {
+    let start = Instant::now();
+    sim::simulate(seed, async {
+        let begin = sim::elapsed();
+        spawn(SleepTask::Entry).await.unwrap();
+        // The sleep sub-tasks are detached, poll until all of them finish.
+        while !tasks().is_empty() {
+            Timeout::new(Duration::from_millis(100)).await;
+        }
+        println!("the SleepTask takes {:?} in the virtual time", sim::elapsed() - begin);
+        // The clock can also be moved by hand, without any timer.
+        sim::advance(Duration::from_secs(3600));
+        println!("an hour later, the virtual clock reads {:?}", sim::elapsed() - begin);
+    });
+    println!("the simulation takes {:?} in the real time", start.elapsed());
+}
+
 pub fn launch() {
-    run(SleepTask::Entry);
-}
\ No newline at end of file
+    set_event_hook(|event| println!("{:?}", event));
+    println!("the sum of squares is {}", block_on(pipeline()));
+
+    run(async {
+        workers().await;
+        combinators().await;
+        requests().await;
+        busy_sum().await;
+
+        // A watchdog task that would keep the reactor alive for 60s.
+        // It is aborted through its JoinHandle once the SleepTask finishes.
+        let watchdog = spawn(Timeout::new(Duration::from_secs(60)));
+        spawn(SleepTask::Entry).await.unwrap();
+        watchdog.abort();
+    });
+    // The timer of the watchdog shows up as a cancelled timer.
+    println!("{:?}", timer_stats());
+
+    local_tasks();
+    shutdown_after(Duration::from_millis(1200));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::drop_flag;
+
+    #[test]
+    fn join_handle_yields_output() {
+        run(async {
+            let handle = spawn(async {
+                Timeout::new(Duration::from_millis(10)).await;
+                42
+            });
+            assert_eq!(handle.await, Ok(42));
+        });
+    }
+
+    #[test]
+    fn run_returns_when_the_root_finishes_immediately() {
+        run(async {});
+        run(async {
+            spawn(async {});
+        });
+        assert!(tasks().is_empty());
+    }
+
+    #[test]
+    fn dropped_timeout_is_cancelled() {
+        run(async {
+            // Poll a Timeout once and drop it before it expires.
+            let handle = spawn(async {
+                let mut to = Timeout::new(Duration::from_secs(10));
+                let waker = futures_task::noop_waker();
+                let mut ctx = Context::from_waker(&waker);
+                assert!(Pin::new(&mut to).poll(&mut ctx).is_pending());
+                assert_eq!(timer_stats().pending, 1);
+            });
+            handle.await.unwrap();
+            Timeout::new(Duration::from_millis(10)).await;
+        });
+        assert_eq!(timer_stats(), TimerStats { pending : 0, fired : 1, cancelled : 1 });
+    }
+
+    // A future that counts how many times it is polled, and hands its
+    // waker out on the first poll.
+    struct PollCounter {
+        polls : Arc<AtomicUsize>,
+        waker : Arc<Mutex<Option<Waker>>>,
+    }
+
+    impl Future for PollCounter {
+        type Output = ();
+
+        fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<()> {
+            if self.polls.fetch_add(1, AtomicOrdering::SeqCst) == 0 {
+                *self.waker.lock().unwrap() = Some(ctx.waker().clone());
+                Poll::Pending
+            }
+            else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_wakeups_are_coalesced() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let waker = Arc::new(Mutex::new(None));
+        let counter = PollCounter { polls : polls.clone(), waker : waker.clone() };
+
+        run(async move {
+            spawn(counter);
+            let waker = waker.lock().unwrap().take().unwrap();
+            waker.wake_by_ref();
+            waker.wake_by_ref();
+            waker.wake();
+        });
+        assert_eq!(polls.load(AtomicOrdering::SeqCst), 2);
+    }
+
+    #[test]
+    fn abort_sleeping_task() {
+        let (dropped, flag) = drop_flag();
+        let start = Instant::now();
+
+        run(async move {
+            let handle = spawn(async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+                1
+            });
+            Timeout::new(Duration::from_millis(10)).await;
+            handle.abort();
+            assert!(dropped.load(AtomicOrdering::SeqCst));
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+        });
+        // The reactor stops without waiting for the timer of the aborted task.
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn abort_from_another_thread() {
+        let (dropped, flag) = drop_flag();
+        let start = Instant::now();
+
+        run(async move {
+            let handle = spawn(async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+                1
+            });
+            let (tx, rx) = std::sync::mpsc::channel();
+            thread::spawn(move || {
+                handle.abort();
+                tx.send(handle).unwrap();
+            });
+            let handle = rx.recv().unwrap();
+            assert_eq!(handle.await, Err(JoinError::Aborted));
+            // The task is queued for the reactor, which drops it in the next 
+            // round instead of waiting for its timer.
+            assert!(!dropped.load(AtomicOrdering::SeqCst));
+            Timeout::new(Duration::from_millis(10)).await;
+            assert!(dropped.load(AtomicOrdering::SeqCst));
+        });
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(tasks().is_empty());
+    }
+
+    #[test]
+    fn wake_from_another_thread() {
+        run(async {
+            let (tx, rx) = crate::channel::oneshot::channel();
+            let handle = spawn(async move {
+                rx.await.unwrap()
+            });
+            // The task is parked on the receiver when the value is sent, so 
+            // the wakeup comes from the other thread.
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                tx.send(7).unwrap();
+            });
+            assert_eq!(handle.await, Ok(7));
+        });
+        assert!(tasks().is_empty());
+    }
+
+    #[test]
+    fn block_on_returns_as_soon_as_the_root_completes() {
+        let (dropped, flag) = drop_flag();
+        let start = Instant::now();
+        // The root future borrows a local variable.
+        let numbers = [1, 2, 3];
+        let sum = block_on(async {
+            spawn(async move {
+                let _flag = flag;
+                Timeout::new(Duration::from_secs(10)).await;
+            });
+            Timeout::new(Duration::from_millis(10)).await;
+            numbers.iter().sum::<i32>()
+        });
+        assert_eq!(sum, 6);
+        // The remaining task is cancelled without waiting for its timer.
+        assert!(dropped.load(AtomicOrdering::SeqCst));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(timer_stats().pending, 0);
+    }
+
+    #[test]
+    fn shutdown_cancels_tasks_in_spawn_order() {
+        let events = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let recorder = events.clone();
+        set_event_hook(move |event| recorder.borrow_mut().push(event));
+
+        let shutdown = shutdown_handle();
+        let remote = shutdown.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            remote.shutdown();
+        });
+        let output = block_on(async {
+            let sleepers : Vec<_> = (0..3).map(|_| spawn(Timeout::new(Duration::from_secs(10)))).collect();
+            // The root future is not cancelled, but learns about the 
+            // shutdown through the JoinHandles.
+            join_all(sleepers).await
+        });
+        handle.join().unwrap();
+        assert_eq!(output, vec![Err(JoinError::Aborted), Err(JoinError::Aborted), Err(JoinError::Aborted)]);
+        assert_eq!(*events.borrow(), vec![
+            TaskEvent::Spawned(0), TaskEvent::Spawned(1), TaskEvent::Spawned(2),
+            TaskEvent::Cancelled(0), TaskEvent::Cancelled(1), TaskEvent::Cancelled(2),
+        ]);
+    }
+
+    #[test]
+    fn task_stats_track_polls_and_wakeups() {
+        block_on(async {
+            let sleeper = spawn_named("sleeper", async {
+                Timeout::new(Duration::from_millis(10)).await;
+                Timeout::new(Duration::from_millis(20)).await;
+            });
+            let stuck = spawn_named("stuck", Timeout::new(Duration::from_secs(10)));
+            Timeout::new(Duration::from_millis(15)).await;
+
+            let snapshot = tasks();
+            assert_eq!(snapshot.len(), 2);
+            assert_eq!(snapshot[0].name.as_deref(), Some("sleeper"));
+            assert_eq!(snapshot[0].state, TaskState::Idle);
+            assert_eq!((snapshot[0].stats.polls, snapshot[0].wakeups), (2, 1));
+            // The stuck task is polled once at spawn and never woken up.
+            assert_eq!(snapshot[1].name.as_deref(), Some("stuck"));
+            assert_eq!((snapshot[1].stats.polls, snapshot[1].wakeups), (1, 0));
+
+            sleeper.await.unwrap();
+            stuck.abort();
+            assert!(tasks().is_empty());
+        });
+    }
+
+    #[test]
+    fn poll_time_excludes_nested_polls() {
+        block_on(async {
+            let parent = spawn(async {
+                let child = spawn(async {
+                    thread::sleep(Duration::from_millis(50));
+                    Timeout::new(Duration::from_secs(10)).await;
+                });
+                Timeout::new(Duration::from_secs(10)).await;
+                drop(child);
+            });
+            let snapshot = tasks();
+            assert_eq!(snapshot.len(), 2);
+            // The child is polled inside the poll of the parent.
+            assert!(snapshot[0].stats.poll_time < Duration::from_millis(50));
+            assert!(snapshot[1].stats.poll_time >= Duration::from_millis(50));
+            parent.abort();
+        });
+    }
+
+    #[test]
+    fn spawn_local_runs_futures_that_are_not_send() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let shared = log.clone();
+        run(async move {
+            let handles : Vec<_> = (0..3).map(|i| {
+                let log = shared.clone();
+                spawn_local(async move {
+                    Timeout::new(Duration::from_millis(30 - 10 * i)).await;
+                    log.borrow_mut().push(i);
+                    // The output of a local task need not be Send either.
+                    log
+                })
+            }).collect();
+            for handle in handles {
+                assert!(Rc::ptr_eq(&handle.await.unwrap(), &shared));
+            }
+        });
+        assert_eq!(*log.borrow(), vec![2, 1, 0]);
+    }
+
+    // A value that borrows a log, and records its own drop in the log.
+    struct Borrower<'a> {
+        log : &'a RefCell<Vec<&'static str>>,
+    }
+
+    impl Drop for Borrower<'_> {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push("dropped");
+        }
+    }
+
+    #[test]
+    fn scoped_tasks_do_not_outlive_the_scope() {
+        let log = RefCell::new(Vec::new());
+        let count = scoped::scope(|scope| {
+            let log = &log;
+            for i in 0..3 {
+                scope.spawn(async move {
+                    let _borrower = Borrower { log };
+                    Timeout::new(Duration::from_millis(10 * i)).await;
+                    // A scoped task may spawn more scoped tasks.
+                    scope.spawn(async move {
+                        Timeout::new(Duration::from_millis(10)).await;
+                        log.borrow_mut().push("nested");
+                    });
+                });
+            }
+            3
+        });
+        // Every task has finished and dropped its borrow when scope returns.
+        let log = log.into_inner();
+        assert_eq!(count, 3);
+        assert_eq!(log.iter().filter(|entry| **entry == "dropped").count(), 3);
+        assert_eq!(log.iter().filter(|entry| **entry == "nested").count(), 3);
+    }
+
+    #[test]
+    fn empty_scope_returns() {
+        assert_eq!(scoped::scope(|_| 3), 3);
+    }
+
+    #[test]
+    fn scoped_tasks_are_cancelled_on_panic() {
+        let log = RefCell::new(Vec::new());
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            scoped::scope(|scope| {
+                let log = &log;
+                scope.spawn(async move {
+                    let _borrower = Borrower { log };
+                    Timeout::new(Duration::from_secs(10)).await;
+                });
+                scope.spawn(async {
+                    Timeout::new(Duration::from_millis(10)).await;
+                    panic!("a scoped task panics");
+                });
+            });
+        }));
+        assert!(res.is_err());
+        // The sleeping task is dropped before the borrowed log.
+        assert_eq!(log.into_inner(), vec!["dropped"]);
+        assert!(tasks().is_empty());
+    }
+
+    #[test]
+    fn simulated_timers_fire_at_their_virtual_deadlines() {
+        let start = Instant::now();
+        let log = sim::simulate(0, async {
+            let begin = sim::elapsed();
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let sleepers : Vec<_> = [30, 10, 20].iter().map(|secs| {
+                let log = log.clone();
+                spawn_local(async move {
+                    Timeout::new(Duration::from_secs(*secs)).await;
+                    log.borrow_mut().push((*secs, sim::elapsed() - begin));
+                })
+            }).collect();
+            join_all(sleepers).await;
+
+            // Moving the clock by hand fires the timers that expire meanwhile.
+            let sleeper = spawn(Timeout::new(Duration::from_secs(3600)));
+            sim::advance(Duration::from_secs(7200));
+            sleeper.await.unwrap();
+            assert_eq!(sim::elapsed() - begin, Duration::from_secs(7230));
+            log.take()
+        });
+        assert_eq!(log, vec![
+            (10, Duration::from_secs(10)), (20, Duration::from_secs(20)), (30, Duration::from_secs(30)),
+        ]);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn very_long_timeouts_do_not_panic_the_reactor() {
+        let three_years = Duration::from_secs(3 * 365 * 24 * 3600);
+        let waited = sim::simulate(0, async move {
+            Timeout::new(Duration::from_millis(5)).await;
+            let begin = sim::elapsed();
+            Timeout::new(three_years).await;
+            sim::elapsed() - begin
+        });
+        assert_eq!(waited, three_years);
+    }
+
+    // Spawn tasks that wake up in the same round, and record the order in 
+    // which they are polled.
+    fn simulated_order(seed : u64) -> Vec<u32> {
+        sim::simulate(seed, async {
+            let order = Rc::new(RefCell::new(Vec::new()));
+            let tasks : Vec<_> = (0..8).map(|id| {
+                let order = order.clone();
+                spawn_local(async move {
+                    Timeout::new(Duration::from_millis(10)).await;
+                    order.borrow_mut().push(id);
+                })
+            }).collect();
+            join_all(tasks).await;
+            order.take()
+        })
+    }
+
+    #[test]
+    fn seeded_scheduler_is_deterministic() {
+        let order = simulated_order(7);
+        assert_eq!(simulated_order(7), order);
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+        // Different seeds explore different interleavings.
+        assert!((0..8).any(|seed| simulated_order(seed) != order));
+    }
+
+    task_local! {
+        static NUMBER : u32;
+    }
+
+    #[test]
+    fn task_locals_are_scoped_to_their_futures() {
+        block_on(async {
+            assert_eq!(NUMBER.try_with(|n| *n), None);
+            let outer = NUMBER.scope(1, async {
+                // A task spawned inside the scope does not inherit the value.
+                let child = spawn(async { NUMBER.try_with(|n| *n) });
+                // The values survive the await points, and a nested scope
+                // shadows the value until it finishes.
+                Timeout::new(Duration::from_millis(10)).await;
+                let inner = NUMBER.scope(2, async {
+                    Timeout::new(Duration::from_millis(10)).await;
+                    NUMBER.get()
+                }).await;
+                (child.await.unwrap(), inner, NUMBER.get())
+            });
+            let other = spawn(NUMBER.scope(3, async {
+                Timeout::new(Duration::from_millis(15)).await;
+                NUMBER.get()
+            }));
+            assert_eq!(outer.await, (None, 2, 1));
+            assert_eq!(other.await, Ok(3));
+            assert_eq!(NUMBER.try_with(|n| *n), None);
+        });
+    }
+
+    #[test]
+    fn spans_tag_the_task_and_nest() {
+        block_on(async {
+            assert_eq!(span::prefix(), "");
+            let handle = spawn(span::in_span("outer", async {
+                let id = current_task_id().unwrap();
+                let inner = span::in_span("inner", async { span::prefix() }).await;
+                (id, span::prefix(), inner)
+            }));
+            let (id, outer, inner) = handle.await.unwrap();
+            assert_eq!(outer, format!("[task {}] [outer] ", id));
+            assert_eq!(inner, format!("[task {}] [outer/inner] ", id));
+        });
+    }
+
+    #[test]
+    fn busy_task_does_not_starve_timers() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let busy_stop = stop.clone();
+        let start = Instant::now();
+        let (busy_ops, ticks) = block_on(async {
+            // The busy task only awaits futures that are always ready.
+            let busy = spawn(async move {
+                let mut ops = 0u64;
+                while !busy_stop.load(AtomicOrdering::SeqCst) {
+                    Timeout::new(Duration::new(0, 0)).await;
+                    ops += 1;
+                }
+                ops
+            });
+            let ticker = spawn(async {
+                for _ in 0..5 {
+                    Timeout::new(Duration::from_millis(10)).await;
+                }
+                5
+            });
+            let ticks = ticker.await.unwrap();
+            stop.store(true, AtomicOrdering::SeqCst);
+            (busy.await.unwrap(), ticks)
+        });
+        assert_eq!(ticks, 5);
+        assert!(busy_ops > u64::from(coop::BUDGET));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn busy_channel_does_not_starve_timers_or_tasks() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let busy_stop = stop.clone();
+        let start = Instant::now();
+        let (busy_ops, ticks, yields) = block_on(async {
+            // The busy task passes values through a channel that always has
+            // room for the next value, so neither end ever waits.
+            let busy = spawn(async move {
+                let (tx, mut rx) = crate::channel::mpsc::channel(1);
+                let mut ops = 0u64;
+                while !busy_stop.load(AtomicOrdering::SeqCst) {
+                    tx.send(ops).await.unwrap();
+                    ops = rx.recv().await.unwrap() + 1;
+                }
+                ops
+            });
+            let ticker = spawn(async {
+                for _ in 0..5 {
+                    Timeout::new(Duration::from_millis(10)).await;
+                }
+                5
+            });
+            let yielder = spawn(async {
+                for _ in 0..5 {
+                    coop::yield_now().await;
+                }
+                5
+            });
+            let ticks = ticker.await.unwrap();
+            let yields = yielder.await.unwrap();
+            stop.store(true, AtomicOrdering::SeqCst);
+            (busy.await.unwrap(), ticks, yields)
+        });
+        assert_eq!((ticks, yields), (5, 5));
+        assert!(busy_ops > u64::from(coop::BUDGET));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn yield_now_interleaves_tasks() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let shared = log.clone();
+        run(async move {
+            for name in ["a", "b"].iter() {
+                let log = shared.clone();
+                spawn_local(async move {
+                    for _ in 0..3 {
+                        log.borrow_mut().push(*name);
+                        coop::yield_now().await;
+                    }
+                });
+            }
+        });
+        assert_eq!(*log.borrow(), vec!["a", "b", "a", "b", "a", "b"]);
+    }
+}
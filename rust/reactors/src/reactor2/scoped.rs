@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use super::{REACTOR, JoinHandle, do_spawn};
+
+// Scoped tasks, which can borrow data from the enclosing scope.
+
+// A task spawned by spawn must be 'static, as the task_slab may keep the
+// task alive for arbitrarily long. Similar to std::thread::scope, scope
+// runs the event loop until all the tasks finish before returning, so the
+// tasks spawned through the Scope may borrow any data that outlives the
+// call to scope. A scoped task never outlives scope:
+// 1. scope does not return until the task_slab is empty. The tasks either
+// finish, are aborted, or are cancelled by a shutdown, and their futures are
+// dropped in all three cases.
+// 2. If scope is left by a panic, the EnterGuard cancels the remaining tasks
+// during unwinding, before the borrowed data is destroyed.
+// 3. scope runs the event loop itself, and the event loop can not be nested,
+// so scope can not be called from a task that may be leaked or cancelled.
+
+// The scope passed to the closure of scope.
+// 'scope : The lifetime of the scope, which scoped tasks may capture in
+// order to spawn more scoped tasks.
+// 'env : The lifetime of the data borrowed by the scoped tasks.
+// Both lifetimes are invariant, as in std::thread::Scope.
+pub struct Scope<'scope, 'env : 'scope> {
+    scope : PhantomData<&'scope mut &'scope ()>,
+    env : PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    // Spawn a task that may borrow data living for 'scope.
+    pub fn spawn<F>(&'scope self, f : F) -> JoinHandle<F::Output>
+        where F : Future + 'scope, F::Output : 'scope
+    {
+        // The task finishes or is dropped before scope returns, which
+        // is before 'scope ends.
+        unsafe { do_spawn(None, f) }
+    }
+}
+
+// Run the closure to spawn scoped tasks, and then run the event loop until
+// all the tasks finish. The output of the closure is returned.
+pub fn scope<'env, F, T>(f : F) -> T
+    where F : for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T
+{
+    let scope = Scope {
+        scope : PhantomData,
+        env : PhantomData,
+    };
+    REACTOR.with(|reactor| {
+        let _guard = reactor.enter();
+        let output = f(&scope);
+        reactor.run_until_idle();
+        output
+    })
+}
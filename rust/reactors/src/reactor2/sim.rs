@@ -0,0 +1,147 @@
+use std::time::{Instant, Duration};
+use std::collections::VecDeque;
+use std::future::Future;
+use super::REACTOR;
+
+// A deterministic simulation mode with a virtual clock.
+
+// Outside of the simulation, the clock of the reactor follows the real time,
+// and the reactor parks the thread until the next timer expires. Inside
+// simulate, the clock is frozen, and only moves when every task is waiting:
+// the reactor then jumps the clock straight to the deadline of the next timer
+// instead of parking. So a task sleeping for an hour finishes immediately,
+// and the clock reads exactly one hour later.
+// The order in which the woken tasks are polled within a round is shuffled
+// with a random number generator seeded by the caller. Running the same
+// futures with the same seed polls the tasks in exactly the same order, so
+// a test can assert the order of the tasks, and different seeds explore
+// different interleavings.
+// The poll statistics of the tasks still measure the real time, as they
+// describe the cost of the tasks rather than their schedule.
+
+// The clock of the reactor, as a duration since the reactor is created.
+// start_time : The instant at which the reactor is created.
+// offset : How far the clock is ahead of the real time, as the virtual
+// clock may move faster than the real time. The clock never moves back
+// when a simulation ends.
+// simulation : The state of the running simulation, if any.
+pub(super) struct Clock {
+    start_time : Instant,
+    offset : Duration,
+    simulation : Option<Simulation>,
+}
+
+// now : The frozen time of the virtual clock.
+// rng : The state of the splitmix64 generator used to shuffle the tasks.
+struct Simulation {
+    now : Duration,
+    rng : u64,
+}
+
+impl Simulation {
+    fn next_u64(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Clock {
+    pub(super) fn new() -> Self {
+        Clock {
+            start_time : Instant::now(),
+            offset : Duration::new(0, 0),
+            simulation : None,
+        }
+    }
+
+    // The current time of the clock.
+    pub(super) fn now(&self) -> Duration {
+        match &self.simulation {
+            Some(simulation) => simulation.now,
+            None => self.start_time.elapsed() + self.offset,
+        }
+    }
+
+    pub(super) fn is_simulated(&self) -> bool {
+        self.simulation.is_some()
+    }
+
+    // Move the virtual clock forward to the deadline. Moving the clock
+    // backwards has no effect.
+    pub(super) fn advance_to(&mut self, deadline : Duration) {
+        if let Some(simulation) = &mut self.simulation {
+            simulation.now = simulation.now.max(deadline);
+        }
+    }
+
+    // Shuffle the run_queue with the Fisher-Yates algorithm in the
+    // simulation mode. The run_queue is left untouched otherwise.
+    pub(super) fn shuffle<T>(&mut self, run_queue : &mut VecDeque<T>) {
+        if let Some(simulation) = &mut self.simulation {
+            let queue = run_queue.make_contiguous();
+            for i in (1..queue.len()).rev() {
+                let j = (simulation.next_u64() % (i as u64 + 1)) as usize;
+                queue.swap(i, j);
+            }
+        }
+    }
+
+    // The virtual clock starts at the next tick of the timer_wheel, so that
+    // a timer fires exactly at its deadline.
+    fn start(&mut self, seed : u64) {
+        let now = self.now().as_nanos().div_ceil(1_000_000) as u64;
+        self.simulation = Some(Simulation {
+            now : Duration::from_millis(now),
+            rng : seed,
+        });
+    }
+
+    // Resume following the real time from the time of the virtual clock.
+    fn stop(&mut self) {
+        if let Some(simulation) = self.simulation.take() {
+            self.offset = simulation.now.saturating_sub(self.start_time.elapsed());
+        }
+    }
+}
+
+// The guard that ends the simulation, even if the future panics.
+struct SimulationGuard;
+
+impl Drop for SimulationGuard {
+    fn drop(&mut self) {
+        let _ = REACTOR.try_with(|reactor| reactor.clock.borrow_mut().stop());
+    }
+}
+
+// Run the future to completion like block_on, with a virtual clock and a
+// scheduler seeded by seed. The simulation must be deterministic: if every
+// task waits for an event other than a timer, e.g. a wakeup from another
+// thread, the simulation panics instead of waiting.
+pub fn simulate<F : Future>(seed : u64, f : F) -> F::Output {
+    REACTOR.with(|reactor| {
+        assert!(!reactor.running.get(), "the reactor is already running on this thread");
+        reactor.clock.borrow_mut().start(seed);
+    });
+    let _guard = SimulationGuard;
+    super::block_on(f)
+}
+
+// Move the virtual clock forward by the duration. The timers expiring in
+// the meantime fire in the next round of the event loop.
+pub fn advance(duration : Duration) {
+    REACTOR.with(|reactor| {
+        let mut clock = reactor.clock.borrow_mut();
+        assert!(clock.is_simulated(), "advance is called outside of a simulation");
+        let now = clock.now();
+        clock.advance_to(now + duration);
+    });
+}
+
+// The time elapsed since the reactor of the current thread is created,
+// according to the clock of the reactor.
+pub fn elapsed() -> Duration {
+    REACTOR.with(|reactor| reactor.clock.borrow().now())
+}
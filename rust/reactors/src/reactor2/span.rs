@@ -0,0 +1,49 @@
+use std::future::Future;
+use super::current_task_id;
+use super::task_local::TaskLocalFuture;
+
+// Lightweight spans for tagging log lines with the context of the task.
+
+// The log lines of the tasks running concurrently are interleaved, so a
+// line on its own does not tell which task prints it. span_println prefixes
+// each line with the ID of the task being polled, and with the path of the
+// spans entered by the future being polled, e.g.
+// [task 3] [sleep_task/sleep_sub_task] sleep sub-task 2 finishes
+// A span is a task-local value, so it is entered by binding it to a future
+// with in_span, and stays entered across the await points of the future.
+
+task_local! {
+    static SPAN : Span;
+}
+
+// path : The names of the nested spans, joined by '/'.
+pub struct Span {
+    path : String,
+}
+
+// Enter a span named name while polling the future. The span is nested
+// in the span that is entered when in_span is called, if any.
+pub fn in_span<F : Future>(name : &str, f : F) -> TaskLocalFuture<Span, F> {
+    let path = match SPAN.try_with(|parent| format!("{}/{}", parent.path, name)) {
+        Some(path) => path,
+        None => name.to_string(),
+    };
+    SPAN.scope(Span { path }, f)
+}
+
+// The prefix of a log line printed by the future being polled.
+pub fn prefix() -> String {
+    let mut prefix = String::new();
+    if let Some(id) = current_task_id() {
+        prefix.push_str(&format!("[task {}] ", id));
+    }
+    SPAN.try_with(|span| prefix.push_str(&format!("[{}] ", span.path)));
+    prefix
+}
+
+// println, with the line prefixed by the current task and span.
+macro_rules! span_println {
+    ($($arg:tt)*) => {
+        println!("{}{}", $crate::reactor2::span::prefix(), format_args!($($arg)*))
+    };
+}
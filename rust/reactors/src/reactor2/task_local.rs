@@ -0,0 +1,165 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use super::REACTOR;
+
+// Task-local storage, whose values follow a task across its await points.
+
+// A thread-local variable is shared by all the tasks on the thread, so it can
+// not carry the context of a single task, e.g. the ID of the request handled
+// by the task. A task-local value is instead bound to a future with
+// LocalKey::scope, and is only visible while that future is being polled.
+// The values visible to the future being polled are kept in the locals of
+// the reactor, a map from the address of each LocalKey to its value. On each
+// poll, a TaskLocalFuture moves its value into the locals, and moves the value
+// back out after the poll, restoring whatever value it has shadowed.
+// A spawned task is polled right away inside the poll of the spawning task,
+// so the reactor clears the locals before polling a task and restores them
+// afterwards. This keeps the values of a task from leaking into the tasks
+// that it spawns.
+
+pub(super) type LocalMap = HashMap<usize, Box<dyn Any>>;
+
+// Declare task-local keys, similar to thread_local.
+// task_local! {
+//     static REQUEST_ID : u64;
+// }
+macro_rules! task_local {
+    ($($vis:vis static $name:ident : $t:ty;)+) => {
+        $(
+            $vis static $name : $crate::reactor2::task_local::LocalKey<$t> =
+                $crate::reactor2::task_local::LocalKey::new(stringify!($name));
+        )+
+    };
+}
+
+// A key for task-local values, declared with task_local. The name of the key
+// shows up in the panic message of with, and makes each key a distinct
+// non-zero-sized static, whose address identifies the key.
+pub struct LocalKey<T : 'static> {
+    name : &'static str,
+    value : PhantomData<fn() -> T>,
+}
+
+impl<T : 'static> LocalKey<T> {
+    pub const fn new(name : &'static str) -> Self {
+        LocalKey {
+            name,
+            value : PhantomData,
+        }
+    }
+
+    fn id(&'static self) -> usize {
+        self as *const Self as usize
+    }
+
+    // Bind the value to the future. The value is visible to the future,
+    // and to nothing else, whenever the future is polled or dropped.
+    pub fn scope<F : Future>(&'static self, value : T, f : F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture {
+            key : self,
+            slot : Some(Box::new(value)),
+            future : Some(Box::pin(f)),
+        }
+    }
+
+    // Access the value bound to the future being polled, or None if no value
+    // is bound. The closure must not poll a TaskLocalFuture.
+    pub fn try_with<R, G : FnOnce(&T) -> R>(&'static self, f : G) -> Option<R> {
+        REACTOR.with(|reactor| {
+            let locals = reactor.locals.borrow();
+            locals.get(&self.id()).map(|value| f(value.downcast_ref::<T>().unwrap()))
+        })
+    }
+
+    // Access the value bound to the future being polled. Panics if no value
+    // is bound.
+    pub fn with<R, G : FnOnce(&T) -> R>(&'static self, f : G) -> R {
+        match self.try_with(f) {
+            Some(res) => res,
+            None => panic!("the task-local {} is not set", self.name),
+        }
+    }
+
+    // Get a copy of the value bound to the future being polled.
+    pub fn get(&'static self) -> T where T : Clone {
+        self.with(|value| value.clone())
+    }
+
+    // Run the closure with the value in the slot moved into the locals. The
+    // value is moved back even if the closure panics.
+    fn enter<R, G : FnOnce() -> R>(&'static self, slot : &mut Option<Box<T>>, f : G) -> R {
+        let shadowed = REACTOR.with(|reactor| {
+            let value : Box<dyn Any> = slot.take().unwrap();
+            reactor.locals.borrow_mut().insert(self.id(), value)
+        });
+        let _restore = Restore {
+            id : self.id(),
+            slot,
+            shadowed,
+        };
+        f()
+    }
+}
+
+// Move the value of a TaskLocalFuture back out of the locals after a poll,
+// and restore the shadowed value, if any.
+struct Restore<'a, T : 'static> {
+    id : usize,
+    slot : &'a mut Option<Box<T>>,
+    shadowed : Option<Box<dyn Any>>,
+}
+
+impl<T : 'static> Drop for Restore<'_, T> {
+    fn drop(&mut self) {
+        let _ = REACTOR.try_with(|reactor| {
+            let mut locals = reactor.locals.borrow_mut();
+            *self.slot = locals.remove(&self.id).map(|value| value.downcast::<T>().unwrap());
+            if let Some(shadowed) = self.shadowed.take() {
+                locals.insert(self.id, shadowed);
+            }
+        });
+    }
+}
+
+// The future returned by LocalKey::scope.
+// key : The key that the value is bound to.
+// slot : The value, which is None while the future is polled. It is kept
+// as a Box<T> rather than a Box<dyn Any>, so that TaskLocalFuture is Send
+// if both T and the future are Send.
+// future : The future, pinned on the heap so that TaskLocalFuture is Unpin.
+// It is set to None when the future finishes.
+pub struct TaskLocalFuture<T : 'static, F : Future> {
+    key : &'static LocalKey<T>,
+    slot : Option<Box<T>>,
+    future : Option<Pin<Box<F>>>,
+}
+
+impl<T : 'static, F : Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        let future = self_mut.future.as_mut().expect("TaskLocalFuture polled after completion");
+        let res = self_mut.key.enter(&mut self_mut.slot, || future.as_mut().poll(ctx));
+        if res.is_ready() {
+            self_mut.future = None;
+        }
+        res
+    }
+}
+
+// The value stays visible while an unfinished future is dropped, e.g. when
+// the task is aborted, as the destructors of the future may access it.
+impl<T : 'static, F : Future> Drop for TaskLocalFuture<T, F> {
+    fn drop(&mut self) {
+        if let Some(future) = self.future.take() {
+            if REACTOR.try_with(|_| ()).is_ok() {
+                self.key.enter(&mut self.slot, || drop(future));
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+use std::fmt;
+
+// Instrumentation of the tasks running in the reactor.
+
+// Each task records how much work it does and how long it waits. The
+// counters are meant for diagnosing tasks that hog the event loop, tasks
+// that are starved in the run_queue, and tasks that are never woken up.
+// polls : The number of times the task is polled.
+// poll_time : The total time spent polling the task.
+// queue_time : The total time the task spends in the run_queue, from
+// being woken up to being polled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    pub polls : u64,
+    pub poll_time : Duration,
+    pub queue_time : Duration,
+}
+
+// The state of a live task, as recorded in its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Idle,
+    Scheduled,
+    Running,
+    Notified,
+}
+
+// A snapshot of a live task.
+// id : The ID of the task.
+// name : The name given to the task at spawn, if any.
+// state : The state of the task.
+// wakeups : The number of times the waker of the task is called, including
+// the wakeups that are coalesced.
+// stats : The poll statistics of the task.
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id : u64,
+    pub name : Option<String>,
+    pub state : TaskState,
+    pub wakeups : u64,
+    pub stats : TaskStats,
+}
+
+// Print a table of tasks, one row per task. A task that is Idle with zero
+// wakeups has never been woken up since it was spawned.
+pub struct TaskTable<'a>(pub &'a [TaskInfo]);
+
+impl fmt::Display for TaskTable<'_> {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>6} {:<16} {:<10} {:>8} {:>8} {:>12} {:>12}",
+                 "id", "name", "state", "polls", "wakeups", "poll time", "queue time")?;
+        for task in self.0 {
+            writeln!(f, "{:>6} {:<16} {:<10} {:>8} {:>8} {:>12} {:>12}",
+                     task.id,
+                     task.name.as_deref().unwrap_or("-"),
+                     format!("{:?}", task.state),
+                     task.stats.polls,
+                     task.wakeups,
+                     format!("{:?}", task.stats.poll_time),
+                     format!("{:?}", task.stats.queue_time))?;
+        }
+        Ok(())
+    }
+}
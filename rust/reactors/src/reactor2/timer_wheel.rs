@@ -0,0 +1,357 @@
+use std::time::Duration;
+use std::task::Waker;
+use std::convert::TryFrom;
+
+// A hierarchical timing wheel that replaces the BinaryHeap timer_heap.
+
+// The wheel advances in ticks of 1ms. It has LEVELS levels of 64 slots.
+// A slot at level 0 covers a single tick, a slot at level 1 covers 64 ticks,
+// a slot at level 2 covers 64 * 64 ticks, and so on. A timer is stored in
+// the level determined by the most significant bit in which its expire tick
+// differs from the current tick, so inserting a timer is O(1). When the
+// current tick enters a slot at a higher level, the timers in that slot
+// are cascaded down to the lower levels, until they reach level 0 and fire.
+
+// The timers live in a slab, and the timers in the same slot form a doubly
+// linked list through the slab indices. This makes cancelling a timer O(1):
+// the timer is simply unlinked from its slot.
+
+const SLOT_BITS : u32 = 6;
+const SLOTS : usize = 1 << SLOT_BITS;
+const SLOT_MASK : u64 = (SLOTS - 1) as u64;
+// 64^6 ms is roughly 2 years. A longer timer stays in the highest level,
+// whose slots wrap around, until the current tick comes close enough.
+const LEVELS : usize = 6;
+const TICK_NANOS : u128 = 1_000_000;
+
+// A handle to a timer stored in the wheel, returned on insertion.
+// The generation guards against cancelling a timer that has already fired,
+// whose slab entry is reused by a new timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerKey {
+    index : usize,
+    generation : u64,
+}
+
+// Counters reported by Reactor::timer_stats.
+// pending : The number of timers waiting in the wheel.
+// fired : The number of timers that have expired and woken up their tasks.
+// cancelled : The number of timers removed before expiring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerStats {
+    pub pending : usize,
+    pub fired : u64,
+    pub cancelled : u64,
+}
+
+// The item stored in the slab.
+// expire : The tick at which the timer expires.
+// waker : The waker of the task sleeping on the timer, None if the slab
+// entry is free.
+// generation : Incremented each time the slab entry is reused.
+// level, slot : The position of the timer in the wheel.
+// prev, next : The neighbours of the timer in the list of the slot. For
+// a free entry, next links to the next free entry.
+struct Entry {
+    expire : u64,
+    waker : Option<Waker>,
+    generation : u64,
+    level : usize,
+    slot : usize,
+    prev : Option<usize>,
+    next : Option<usize>,
+}
+
+pub struct TimerWheel {
+    current_tick : u64,
+    heads : [[Option<usize>; SLOTS]; LEVELS],
+    entries : Vec<Entry>,
+    free_head : Option<usize>,
+    stats : TimerStats,
+}
+
+// Convert a duration since the start of the reactor to a tick, rounding up
+// so that a timer never fires before its deadline. A deadline too far
+// to be represented never expires.
+fn duration_to_tick(duration : Duration) -> u64 {
+    u64::try_from(duration.as_nanos().div_ceil(TICK_NANOS)).unwrap_or(u64::MAX)
+}
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            current_tick : 0,
+            heads : [[None; SLOTS]; LEVELS],
+            entries : Vec::new(),
+            free_head : None,
+            stats : TimerStats::default(),
+        }
+    }
+
+    // Insert a timer expiring at the given duration since the start of the
+    // reactor. A timer that has already expired fires at the next tick.
+    pub fn insert(&mut self, deadline : Duration, waker : Waker) -> TimerKey {
+        let expire = duration_to_tick(deadline).max(self.current_tick + 1);
+
+        // Take an entry from the free list, or grow the slab.
+        let index = match self.free_head {
+            Some(index) => {
+                self.free_head = self.entries[index].next;
+                let entry = &mut self.entries[index];
+                entry.generation += 1;
+                entry.expire = expire;
+                entry.waker = Some(waker);
+                index
+            },
+            None => {
+                self.entries.push(Entry {
+                    expire,
+                    waker : Some(waker),
+                    generation : 0,
+                    level : 0,
+                    slot : 0,
+                    prev : None,
+                    next : None,
+                });
+                self.entries.len() - 1
+            },
+        };
+
+        self.link(index);
+        self.stats.pending += 1;
+        TimerKey { index, generation : self.entries[index].generation }
+    }
+
+    // Remove a timer from the wheel before it expires.
+    // Returns false if the timer has already fired or been cancelled.
+    pub fn cancel(&mut self, key : TimerKey) -> bool {
+        if !self.contains(key) {
+            return false;
+        }
+        self.unlink(key.index);
+        self.release(key.index);
+        self.stats.pending -= 1;
+        self.stats.cancelled += 1;
+        true
+    }
+
+    // Whether the timer is still waiting in the wheel.
+    pub fn contains(&self, key : TimerKey) -> bool {
+        self.entries.get(key.index).is_some_and(|entry| {
+            entry.generation == key.generation && entry.waker.is_some()
+        })
+    }
+
+    // Replace the waker of a pending timer, e.g. when the Timeout is
+    // polled again by a different task.
+    pub fn update_waker(&mut self, key : TimerKey, waker : &Waker) {
+        if self.contains(key) {
+            let entry = &mut self.entries[key.index];
+            if !entry.waker.as_ref().unwrap().will_wake(waker) {
+                entry.waker = Some(waker.clone());
+            }
+        }
+    }
+
+    // A lower bound of the deadline of the next timer, as a duration since
+    // the start of the reactor. The timers in a slot at a higher level expire
+    // no earlier than the first tick of the slot, at which point they are
+    // cascaded down and the next deadline becomes more precise.
+    pub fn next_expire(&self) -> Option<Duration> {
+        self.next_tick().map(|tick| Duration::from_nanos((tick as u128 * TICK_NANOS) as u64))
+    }
+
+    // The first tick of the earliest slot holding a timer, which is the next
+    // tick at which a timer either fires or is cascaded down.
+    fn next_tick(&self) -> Option<u64> {
+        if self.stats.pending == 0 {
+            return None;
+        }
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let current_slot = (self.current_tick >> shift) & SLOT_MASK;
+            // The slots after the current one in this rotation of the level.
+            // The timers of the highest level may also belong to the next 
+            // rotation, in which case they are in the slots up to the current
+            // one, so the scan of the highest level wraps around.
+            let offsets = if level == LEVELS - 1 {
+                SLOTS as u64
+            }
+            else {
+                SLOT_MASK - current_slot
+            };
+            for offset in 1..=offsets {
+                let slot = ((current_slot + offset) & SLOT_MASK) as usize;
+                if self.heads[level][slot].is_some() {
+                    return Some(((self.current_tick >> shift) + offset) << shift);
+                }
+            }
+        }
+        unreachable!("pending timers are not found in the wheel")
+    }
+
+    // Advance the wheel to the given duration since the start of the
+    // reactor, and collect the wakers of all the expired timers.
+    pub fn advance(&mut self, now : Duration) -> Vec<Waker> {
+        let target = (now.as_nanos() / TICK_NANOS) as u64;
+        let mut wakers = Vec::new();
+        while self.current_tick < target {
+            // Nothing happens until the next slot holding a timer is entered,
+            // so the wheel jumps straight to it, or to the target tick if it
+            // is further away or the wheel is empty.
+            match self.next_tick() {
+                Some(tick) if tick <= target => self.current_tick = tick,
+                _ => {
+                    self.current_tick = target;
+                    break;
+                },
+            }
+
+            // Cascade the timers in the slots that the current tick enters,
+            // starting from the highest level, so that the timers cascaded
+            // into a lower level are handled in the same tick.
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if self.current_tick & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = ((self.current_tick >> shift) & SLOT_MASK) as usize;
+                let mut next = self.heads[level][slot].take();
+                while let Some(index) = next {
+                    next = self.entries[index].next;
+                    self.link(index);
+                }
+            }
+
+            // All the timers in the current slot of level 0 expire now.
+            let slot = (self.current_tick & SLOT_MASK) as usize;
+            let mut next = self.heads[0][slot].take();
+            while let Some(index) = next {
+                next = self.entries[index].next;
+                wakers.push(self.release(index));
+                self.stats.pending -= 1;
+                self.stats.fired += 1;
+            }
+        }
+        wakers
+    }
+
+    pub fn stats(&self) -> TimerStats {
+        self.stats
+    }
+
+    // Push the entry at the front of the slot determined by its expire tick.
+    fn link(&mut self, index : usize) {
+        let expire = self.entries[index].expire;
+        let masked = (self.current_tick ^ expire) | SLOT_MASK;
+        let significant = 63 - masked.leading_zeros();
+        // A timer crossing the boundary of the highest level stays in the
+        // highest level, and is cascaded down when its slot is reached. If it
+        // is still too far by then, it is put back into the same slot for
+        // another rotation.
+        let level = ((significant / SLOT_BITS) as usize).min(LEVELS - 1);
+        let slot = ((expire >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+
+        let head = self.heads[level][slot];
+        if let Some(head) = head {
+            self.entries[head].prev = Some(index);
+        }
+        let entry = &mut self.entries[index];
+        entry.level = level;
+        entry.slot = slot;
+        entry.prev = None;
+        entry.next = head;
+        self.heads[level][slot] = Some(index);
+    }
+
+    // Remove the entry from the list of its slot.
+    fn unlink(&mut self, index : usize) {
+        let Entry { level, slot, prev, next, .. } = self.entries[index];
+        match prev {
+            Some(prev) => self.entries[prev].next = next,
+            None => self.heads[level][slot] = next,
+        }
+        if let Some(next) = next {
+            self.entries[next].prev = prev;
+        }
+    }
+
+    // Put the entry back to the free list and return its waker.
+    fn release(&mut self, index : usize) -> Waker {
+        let entry = &mut self.entries[index];
+        entry.prev = None;
+        entry.next = self.free_head;
+        self.free_head = Some(index);
+        entry.waker.take().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_task::noop_waker;
+
+    fn ms(ms : u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn fires_across_levels_in_order() {
+        let mut wheel = TimerWheel::new();
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 300_000];
+        let keys : Vec<_> = deadlines.iter().map(|d| wheel.insert(ms(*d), noop_waker())).collect();
+
+        let mut previous = 0;
+        for (deadline, key) in deadlines.iter().zip(keys) {
+            assert_eq!(wheel.advance(ms(deadline - 1)).len(), 0);
+            assert!(wheel.contains(key));
+            assert!(wheel.next_expire().unwrap() <= ms(*deadline));
+            assert!(wheel.next_expire().unwrap() > ms(previous));
+            assert_eq!(wheel.advance(ms(*deadline)).len(), 1);
+            assert!(!wheel.contains(key));
+            previous = *deadline;
+        }
+        assert_eq!(wheel.next_expire(), None);
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let mut wheel = TimerWheel::new();
+        let cancelled = wheel.insert(ms(10), noop_waker());
+        let kept = wheel.insert(ms(10), noop_waker());
+        assert!(wheel.cancel(cancelled));
+        assert!(!wheel.cancel(cancelled));
+
+        assert_eq!(wheel.advance(ms(20)).len(), 1);
+        assert!(!wheel.cancel(kept));
+        assert_eq!(wheel.stats(), TimerStats { pending : 0, fired : 1, cancelled : 1 });
+
+        // The slab entry of the fired timer is reused by a new timer, which
+        // must not be cancelled through the stale key.
+        let reused = wheel.insert(ms(30), noop_waker());
+        assert!(!wheel.cancel(kept));
+        assert!(wheel.contains(reused));
+    }
+
+    #[test]
+    fn very_long_timeouts_fire_at_their_deadline() {
+        let mut wheel = TimerWheel::new();
+        wheel.advance(ms(5));
+        // Three years is beyond a rotation of the highest level, and the
+        // timer lands in a slot before the current one.
+        let three_years = ms(3 * 365 * 24 * 3600 * 1000);
+        let key = wheel.insert(three_years, noop_waker());
+        assert!(wheel.next_expire().unwrap() <= three_years);
+
+        assert_eq!(wheel.advance(three_years - ms(1)).len(), 0);
+        assert!(wheel.contains(key));
+        assert_eq!(wheel.next_expire(), Some(three_years));
+        assert_eq!(wheel.advance(three_years).len(), 1);
+
+        // A deadline that does not fit in a tick never fires.
+        let key = wheel.insert(Duration::MAX, noop_waker());
+        assert!(wheel.next_expire().is_some());
+        assert_eq!(wheel.advance(three_years * 4).len(), 0);
+        assert!(wheel.contains(key));
+    }
+}
@@ -0,0 +1,725 @@
+use std::time::{Instant, Duration};
+use std::task::{Waker, Context, Poll};
+use std::collections::{VecDeque, HashMap};
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use futures_task::{ArcWake, FutureObj};
+
+// A multi-threaded reactor that only supports timeout.
+
+// Reactor1/reactor2 store the reactor inside a thread-local storage, so
+// all the tasks run on a single core, even though do_spawn already
+// requires the future to be Send. This reactor runs the tasks on N worker
+// threads, while keeping the same run/spawn/Timeout interface.
+// The data structures of the reactor are shared by all the workers.
+// injector : A global queue for tasks that are scheduled from outside of
+// the worker threads, e.g. the initial task passed to run.
+// local_queues : One run queue for each worker. A worker pushes the tasks
+// that it spawns or wakes up into its own queue. An idle worker steals
+// half of the tasks from the queue of another worker.
+// timer_wheel : A timer wheel shared by all the workers. Each worker fires
+// the expired timers before looking for the next task, so that the timers
+// also fire while all the workers are busy.
+// task_count : The number of tasks that are alive. The reactor stops
+// when this number drops to zero.
+// idle_lock, idle_cond : Used by idle workers to sleep until new tasks
+// are scheduled, or the next timer expires.
+// shutdown : Whether the reactor is stopping.
+// panic : The payload of the first task that panics. A panicking task stops
+// the reactor, and the panic is resumed by run once the workers have exited.
+struct Reactor {
+    injector : Mutex<VecDeque<Arc<Task>>>,
+    local_queues : Vec<Mutex<VecDeque<Arc<Task>>>>,
+    timer_wheel : Mutex<TimerWheel>,
+    task_count : AtomicUsize,
+    idle_lock : Mutex<()>,
+    idle_cond : Condvar,
+    shutdown : AtomicBool,
+    panic : Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+// Each worker thread records the reactor that it belongs to and its index,
+// so that spawn and wake can push the task into the local queue.
+thread_local! {
+    static WORKER : RefCell<Option<(Arc<Reactor>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Reactor {
+    fn new(workers : usize) -> Self {
+        Self {
+            injector : Mutex::new(VecDeque::new()),
+            local_queues : (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            timer_wheel : Mutex::new(TimerWheel::new(Instant::now())),
+            task_count : AtomicUsize::new(0),
+            idle_lock : Mutex::new(()),
+            idle_cond : Condvar::new(),
+            shutdown : AtomicBool::new(false),
+            panic : Mutex::new(None),
+        }
+    }
+
+    // Spawn a new task based on a new Future trait object.
+    // Unlike reactor2, the new task is not polled immediately. It is
+    // pushed into a run queue and polled by one of the workers.
+    fn do_spawn<F : Future<Output = ()> + 'static + Send>(self : &Arc<Self>, f : F) {
+        self.task_count.fetch_add(1, Ordering::SeqCst);
+        let task = Arc::new(Task {
+            reactor : self.clone(),
+            future : Mutex::new(Some(FutureObj::new(Box::new(f)))),
+            state : AtomicUsize::new(SCHEDULED),
+        });
+        self.schedule(task);
+    }
+
+    // Push a task into a run queue and notify an idle worker.
+    // If the current thread is a worker of this reactor, the task goes
+    // into the local queue of the worker, otherwise it goes into the injector.
+    fn schedule(self : &Arc<Self>, task : Arc<Task>) {
+        let task = WORKER.with(|worker| {
+            match &*worker.borrow() {
+                Some((reactor, index)) if Arc::ptr_eq(reactor, self) => {
+                    self.local_queues[*index].lock().unwrap().push_back(task);
+                    None
+                },
+                _ => Some(task),
+            }
+        });
+        if let Some(task) = task {
+            self.injector.lock().unwrap().push_back(task);
+        }
+
+        // Acquire the idle_lock before notifying, so that the notification
+        // can not get lost between an idle worker checking the run queues
+        // and waiting on the idle_cond.
+        let _guard = self.idle_lock.lock().unwrap();
+        self.idle_cond.notify_one();
+    }
+
+    // Find the next task to run for the worker at index.
+    // The local queue is checked first, then the injector, and finally
+    // the worker tries to steal from the other workers.
+    fn next_task(&self, index : usize) -> Option<Arc<Task>> {
+        if let Some(task) = self.local_queues[index].lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        if let Some(task) = self.injector.lock().unwrap().pop_front() {
+            return Some(task);
+        }
+        self.steal(index)
+    }
+
+    // Steal half of the tasks from the back of the first non-empty
+    // local queue of the other workers. One of the stolen tasks is
+    // returned and the rest are pushed into the local queue of the thief.
+    // Two queues are never locked at the same time to avoid deadlocks.
+    fn steal(&self, index : usize) -> Option<Arc<Task>> {
+        let workers = self.local_queues.len();
+        for offset in 1..workers {
+            let victim = (index + offset) % workers;
+            let mut stolen = {
+                let mut queue = self.local_queues[victim].lock().unwrap();
+                let len = queue.len();
+                queue.split_off(len - len.div_ceil(2))
+            };
+            if let Some(task) = stolen.pop_front() {
+                self.local_queues[index].lock().unwrap().extend(stolen);
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    // Whether any run queue contains a task.
+    fn has_task(&self) -> bool {
+        !self.injector.lock().unwrap().is_empty() ||
+            self.local_queues.iter().any(|queue| !queue.lock().unwrap().is_empty())
+    }
+
+    // Fire the expired timers and return the instant of the next timer.
+    // Waking up the tasks pushes them into the local queue of this worker.
+    // The wakers are only used after the lock is released, as dropping a
+    // waker may drop a task, whose Timeout cancels its timer.
+    fn fire_timers(&self) -> Option<Instant> {
+        let (wakers, next_expire) = {
+            let mut timer_wheel = self.timer_wheel.lock().unwrap();
+            let wakers = timer_wheel.advance(Instant::now());
+            (wakers, timer_wheel.next_expire())
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+        next_expire
+    }
+
+    // Called by a worker that finds no task to run. The worker sleeps until
+    // a new task is scheduled or the next timer expires.
+    fn park(&self, next_expire : Option<Instant>) {
+        let guard = self.idle_lock.lock().unwrap();
+        if self.shutdown.load(Ordering::SeqCst) || self.has_task() {
+            return;
+        }
+        match next_expire {
+            Some(expire) => {
+                let timeout = expire.saturating_duration_since(Instant::now());
+                drop(self.idle_cond.wait_timeout(guard, timeout).unwrap());
+            },
+            None => {
+                drop(self.idle_cond.wait(guard).unwrap());
+            },
+        }
+    }
+
+    // Called when a task finishes. The last finished task stops the reactor.
+    fn finish_task(&self) {
+        if self.task_count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.stop();
+        }
+    }
+
+    // Called when a task panics. The first panic is kept for run, and the
+    // reactor stops, as the other tasks may wait for the panicking one.
+    fn panic_task(&self, payload : Box<dyn Any + Send>) {
+        self.panic.lock().unwrap().get_or_insert(payload);
+        self.stop();
+    }
+
+    // Make every worker exit its event loop.
+    fn stop(&self) {
+        let _guard = self.idle_lock.lock().unwrap();
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.idle_cond.notify_all();
+    }
+
+    // The event loop of each worker thread.
+    fn run_worker(self : Arc<Self>, index : usize) {
+        WORKER.with(|worker| {
+            *worker.borrow_mut() = Some((self.clone(), index));
+        });
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            let next_expire = self.fire_timers();
+            match self.next_task(index) {
+                Some(task) => task.run(),
+                None => self.park(next_expire),
+            }
+        }
+
+        WORKER.with(|worker| {
+            worker.borrow_mut().take();
+        });
+    }
+
+    // The entry point of the reactor. Spawn the initial task, start
+    // the worker threads and wait for all the tasks to finish.
+    fn run<F : Future<Output = ()> + 'static + Send>(self : Arc<Self>, f : F) {
+        self.do_spawn(f);
+
+        let handles : Vec<_> = (0..self.local_queues.len()).map(|index| {
+            let reactor = self.clone();
+            thread::Builder::new()
+                .name(format!("reactor3-worker-{}", index))
+                .spawn(move || reactor.run_worker(index))
+                .unwrap()
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // The timers and the run queues may still hold tasks, which hold
+        // the reactor. Clear them to break the reference cycle. Tasks are 
+        // only left unfinished if one of them panics. The wakers of the 
+        // timers are dropped after the lock of the timer_wheel is released, 
+        // as the Timeouts of the dropped tasks cancel their timers.
+        let timers = self.timer_wheel.lock().unwrap().clear();
+        drop(timers);
+        self.injector.lock().unwrap().clear();
+        self.local_queues.iter().for_each(|queue| queue.lock().unwrap().clear());
+
+        if let Some(payload) = self.panic.lock().unwrap().take() {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+// The states of a task, the same as reactor2.
+// IDLE : The task is waiting for a wakeup.
+// SCHEDULED : The task is in a run queue.
+// RUNNING : The task is being polled by a worker.
+// NOTIFIED : The task is woken up while being polled, and is scheduled
+// again after the poll.
+// COMPLETE : The task has finished.
+const IDLE : usize = 0;
+const SCHEDULED : usize = 1;
+const RUNNING : usize = 2;
+const NOTIFIED : usize = 3;
+const COMPLETE : usize = 4;
+
+// The actual representation of an asynchronous task in this implementation.
+// reactor : The reactor that the task belongs to.
+// future : The future object of the task, which becomes None when the task
+// finishes. The task is in at most one run queue and never in a run queue 
+// while being polled, so the Mutex is never contended.
+// state : The state of the task. This coalesces multiple wakeups of the 
+// task into a single poll.
+struct Task {
+    reactor : Arc<Reactor>,
+    future : Mutex<Option<FutureObj<'static, ()>>>,
+    state : AtomicUsize,
+}
+
+impl Task {
+    // Poll the task on the current worker. A panic of the task is caught,
+    // so that the worker survives and stops the other workers.
+    fn run(self : Arc<Self>) {
+        self.state.store(RUNNING, Ordering::SeqCst);
+        let mut future = self.future.lock().unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let waker = futures_task::waker_ref(&self);
+            let mut ctx = Context::from_waker(&waker);
+            Pin::new(future.as_mut().unwrap()).poll(&mut ctx)
+        }));
+        match res {
+            Ok(Poll::Pending) => {
+                drop(future);
+                if self.state.compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                    // The task is woken up during the poll, schedule it again.
+                    self.state.store(SCHEDULED, Ordering::SeqCst);
+                    self.reactor.clone().schedule(self);
+                }
+            },
+            Ok(Poll::Ready(())) => {
+                self.state.store(COMPLETE, Ordering::SeqCst);
+                *future = None;
+                drop(future);
+                self.reactor.finish_task();
+            },
+            Err(payload) => {
+                self.state.store(COMPLETE, Ordering::SeqCst);
+                *future = None;
+                drop(future);
+                self.reactor.panic_task(payload);
+            },
+        }
+    }
+}
+
+impl ArcWake for Task {
+    fn wake_by_ref(arc_self : &Arc<Self>) {
+        let state = &arc_self.state;
+        loop {
+            let current = state.load(Ordering::SeqCst);
+            let next = match current {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                // The task is already going to be polled, or has finished.
+                _ => return,
+            };
+            if state.compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                continue;
+            }
+            if next == SCHEDULED {
+                arc_self.reactor.schedule(arc_self.clone());
+            }
+            return;
+        }
+    }
+}
+
+// The number of slots in the timer wheel.
+const WHEEL_SLOTS : usize = 512;
+// The time span covered by each slot.
+const WHEEL_TICK : Duration = Duration::from_millis(1);
+
+// A hashed timer wheel.
+// A timer expiring at tick t is stored in slot t % WHEEL_SLOTS. Advancing
+// the wheel visits the slots between the current tick and the target tick,
+// and fires the timers whose expire tick has been reached. Timers expiring
+// more than WHEEL_SLOTS ticks later stay in their slot for multiple rounds.
+// start_time : The instant corresponding to tick 0.
+// current_tick : All the timers expiring at or before this tick have fired.
+// slots : The timers stored in each slot, indexed by their IDs.
+// len : The total number of timers in the wheel.
+// next_id : The ID of the next timer. IDs are never reused, so the key of
+// a timer that has fired never refers to another timer.
+struct TimerWheel {
+    start_time : Instant,
+    current_tick : u64,
+    slots : Vec<HashMap<u64, Timer>>,
+    len : usize,
+    next_id : u64,
+}
+
+// The key of a timer in the wheel, used to update or cancel the timer.
+// slot : The slot storing the timer.
+// id : The ID of the timer.
+#[derive(Clone, Copy)]
+struct TimerKey {
+    slot : usize,
+    id : u64,
+}
+
+// The item stored in the timer wheel.
+// expire_tick : The tick at which the timer expires.
+// waker : The waker of the task sleeping on the timer.
+struct Timer {
+    expire_tick : u64,
+    waker : Waker,
+}
+
+impl TimerWheel {
+    fn new(start_time : Instant) -> Self {
+        Self {
+            start_time,
+            current_tick : 0,
+            slots : (0..WHEEL_SLOTS).map(|_| HashMap::new()).collect(),
+            len : 0,
+            next_id : 0,
+        }
+    }
+
+    // Convert an instant to a tick, rounding down.
+    fn tick_of(&self, instant : Instant) -> u64 {
+        (instant.saturating_duration_since(self.start_time).as_nanos() / WHEEL_TICK.as_nanos()) as u64
+    }
+
+    // Insert a timer expiring at the given instant. The expire tick is
+    // rounded up so that the timer never fires early. If the timer has
+    // already expired, nothing is inserted and None is returned.
+    fn insert(&mut self, expire : Instant, waker : &Waker) -> Option<TimerKey> {
+        let duration = expire.saturating_duration_since(self.start_time);
+        let expire_tick = duration.as_nanos().div_ceil(WHEEL_TICK.as_nanos()) as u64;
+        if expire_tick <= self.current_tick {
+            return None;
+        }
+        let key = TimerKey {
+            slot : (expire_tick % WHEEL_SLOTS as u64) as usize,
+            id : self.next_id,
+        };
+        self.next_id += 1;
+        self.slots[key.slot].insert(key.id, Timer { expire_tick, waker : waker.clone() });
+        self.len += 1;
+        Some(key)
+    }
+
+    // The waker of a timer that has not fired yet.
+    fn waker_mut(&mut self, key : TimerKey) -> Option<&mut Waker> {
+        self.slots[key.slot].get_mut(&key.id).map(|timer| &mut timer.waker)
+    }
+
+    // Remove a timer that has not fired yet, and hand its waker back to
+    // the caller, to be dropped after the lock of the wheel is released.
+    fn cancel(&mut self, key : TimerKey) -> Option<Waker> {
+        let timer = self.slots[key.slot].remove(&key.id)?;
+        self.len -= 1;
+        Some(timer.waker)
+    }
+
+    // Advance the wheel to the given instant and collect the wakers of
+    // the expired timers.
+    fn advance(&mut self, now : Instant) -> Vec<Waker> {
+        let target_tick = self.tick_of(now);
+        let mut wakers = Vec::new();
+        if target_tick <= self.current_tick || self.len == 0 {
+            self.current_tick = self.current_tick.max(target_tick);
+            return wakers;
+        }
+
+        // If the wheel has been left behind for a full round, every slot
+        // needs to be visited exactly once.
+        let ticks = (target_tick - self.current_tick).min(WHEEL_SLOTS as u64);
+        for tick in self.current_tick + 1..=self.current_tick + ticks {
+            let slot = &mut self.slots[(tick % WHEEL_SLOTS as u64) as usize];
+            let expired : Vec<u64> = slot.iter()
+                .filter(|(_, timer)| timer.expire_tick <= target_tick)
+                .map(|(id, _)| *id)
+                .collect();
+            wakers.extend(expired.iter().map(|id| slot.remove(id).unwrap().waker));
+        }
+        self.len -= wakers.len();
+        self.current_tick = target_tick;
+        wakers
+    }
+
+    // The instant of the next non-empty slot. The timers in that slot may
+    // belong to a later round, in which case the worker wakes up early and
+    // simply computes the next expiration again.
+    fn next_expire(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        (1..=WHEEL_SLOTS as u64).map(|offset| self.current_tick + offset).find(|tick| {
+            !self.slots[(tick % WHEEL_SLOTS as u64) as usize].is_empty()
+        }).map(|tick| self.start_time + Duration::from_nanos(WHEEL_TICK.as_nanos() as u64 * tick))
+    }
+
+    // Remove all the timers and hand their wakers back to the caller.
+    fn clear(&mut self) -> Vec<Waker> {
+        self.len = 0;
+        self.slots.iter_mut().flat_map(|slot| slot.drain().map(|(_, timer)| timer.waker)).collect()
+    }
+}
+
+// A future object that sleeps for a certain amount of time
+// as indicated in the contained duration and resumes the execution
+// after sleep, the same as reactor_epoll.
+// duration : The time to sleep.
+// expire : The instant at which the timer expires, set when the timer is
+// first inserted.
+// timer : The reactor and the key of the timer, while the timer is in the
+// wheel. A poll before the timer fires only replaces the waker of the timer,
+// so polling a Timeout many times keeps a single timer. Dropping the Timeout
+// cancels the timer. The reactor is kept, as the Timeout may be dropped 
+// outside of the worker threads, e.g. when run drops the unfinished tasks.
+pub struct Timeout {
+    duration : Duration,
+    expire : Option<Instant>,
+    timer : Option<(Arc<Reactor>, TimerKey)>,
+}
+
+impl Unpin for Timeout {}
+
+impl Timeout {
+    pub fn new(duration : Duration) -> Self {
+        Timeout {
+            duration,
+            expire : None,
+            timer : None,
+        }
+    }
+}
+
+impl Future for Timeout {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some((reactor, key)) = &this.timer {
+            // The replaced waker is dropped after the lock is released.
+            let mut replaced = None;
+            let mut timer_wheel = reactor.timer_wheel.lock().unwrap();
+            let pending = match timer_wheel.waker_mut(*key) {
+                Some(stored) => {
+                    if !stored.will_wake(ctx.waker()) {
+                        replaced = Some(std::mem::replace(stored, ctx.waker().clone()));
+                    }
+                    true
+                },
+                None => false,
+            };
+            drop(timer_wheel);
+            drop(replaced);
+            if pending {
+                return Poll::Pending;
+            }
+            // The timer has fired.
+            this.timer = None;
+            return Poll::Ready(());
+        }
+
+        let expire = match this.expire {
+            Some(expire) => expire,
+            None if this.duration == Duration::new(0, 0) => return Poll::Ready(()),
+            None => {
+                let expire = Instant::now() + this.duration;
+                this.expire = Some(expire);
+                expire
+            },
+        };
+        let reactor = current_reactor();
+        let key = reactor.timer_wheel.lock().unwrap().insert(expire, ctx.waker());
+        match key {
+            Some(key) => {
+                this.timer = Some((reactor, key));
+                Poll::Pending
+            },
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+impl Drop for Timeout {
+    fn drop(&mut self) {
+        if let Some((reactor, key)) = self.timer.take() {
+            let waker = reactor.timer_wheel.lock().unwrap().cancel(key);
+            drop(waker);
+        }
+    }
+}
+
+// Retrieve the reactor of the current worker thread.
+fn current_reactor() -> Arc<Reactor> {
+    WORKER.with(|worker| {
+        match &*worker.borrow() {
+            Some((reactor, _)) => reactor.clone(),
+            None => panic!("must be called from a worker thread of reactor3"),
+        }
+    })
+}
+
+// The entry point of the async eventloop, running on one worker thread
+// per CPU core.
+pub fn run<F : Future<Output = ()> + 'static + Send>(f : F) {
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    run_with_workers(workers, f);
+}
+
+// The entry point of the async eventloop, running on the given number
+// of worker threads.
+pub fn run_with_workers<F : Future<Output = ()> + 'static + Send>(workers : usize, f : F) {
+    assert!(workers > 0, "reactor3 needs at least one worker");
+    Arc::new(Reactor::new(workers)).run(f);
+}
+
+// Spawning a new Future task inside the eventloop.
+pub fn spawn<F : Future<Output = ()> + 'static + Send>(f : F) {
+    current_reactor().do_spawn(f);
+}
+
+async fn sleep_sub_task(id : i32) {
+    println!("sleep sub-task {} is created", id);
+    Timeout::new(Duration::from_secs(10)).await;
+    println!("sleep sub-task {} finishes", id);
+}
+
+async fn sleep_task() {
+    for i in 1..11 {
+        println!("main task sleep for 1s");
+        Timeout::new(Duration::from_secs(1)).await;
+        println!("create sleep sub task {}", i);
+        spawn(sleep_sub_task(i));
+    }
+}
+
+pub fn launch() {
+    run(sleep_task());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_sleeping_tasks_finish() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        let counter = finished.clone();
+        run_with_workers(4, async move {
+            for i in 0..100 {
+                let counter = counter.clone();
+                spawn(async move {
+                    Timeout::new(Duration::from_millis(i % 20)).await;
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(finished.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn blocked_worker_tasks_are_stolen() {
+        // Every task blocks its worker until all the tasks are running. The
+        // tasks are spawned into the local queue of a single worker, so they 
+        // can only all run at the same time if the other workers steal them.
+        // The timeout only bounds a failing test.
+        let running = Arc::new((Mutex::new(0), Condvar::new()));
+        let together = Arc::new(AtomicUsize::new(0));
+        let (running_, together_) = (running.clone(), together.clone());
+        run_with_workers(4, async move {
+            for _ in 0..4 {
+                let (running, together) = (running_.clone(), together_.clone());
+                spawn(async move {
+                    let (count, cond) = &*running;
+                    let mut count = count.lock().unwrap();
+                    *count += 1;
+                    cond.notify_all();
+                    let (count, _) = cond.wait_timeout_while(count, Duration::from_secs(10), |count| *count < 4).unwrap();
+                    if *count == 4 {
+                        together.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(together.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn spurious_polls_do_not_end_a_timeout_early() {
+        run_with_workers(2, async {
+            let start = Instant::now();
+            let mut timeout = Timeout::new(Duration::from_millis(50));
+            {
+                let waker = futures_task::noop_waker();
+                let mut ctx = Context::from_waker(&waker);
+                assert!(Pin::new(&mut timeout).poll(&mut ctx).is_pending());
+                assert!(Pin::new(&mut timeout).poll(&mut ctx).is_pending());
+            }
+            timeout.await;
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        });
+    }
+
+    #[test]
+    fn panicking_task_stops_the_reactor() {
+        let res = panic::catch_unwind(|| {
+            run_with_workers(2, async {
+                // A task that would keep the reactor alive for 60s.
+                spawn(Timeout::new(Duration::from_secs(60)));
+                spawn(async {
+                    Timeout::new(Duration::from_millis(10)).await;
+                    panic!("task failed");
+                });
+            });
+        });
+        let payload = res.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"task failed"));
+    }
+
+    #[test]
+    fn polling_a_timeout_keeps_a_single_timer() {
+        run_with_workers(1, async {
+            let reactor = current_reactor();
+            let mut timeout = Timeout::new(Duration::from_secs(60));
+            {
+                let waker = futures_task::noop_waker();
+                let mut ctx = Context::from_waker(&waker);
+                for _ in 0..10 {
+                    assert!(Pin::new(&mut timeout).poll(&mut ctx).is_pending());
+                }
+            }
+            assert_eq!(reactor.timer_wheel.lock().unwrap().len, 1);
+            drop(timeout);
+            assert_eq!(reactor.timer_wheel.lock().unwrap().len, 0);
+        });
+    }
+
+    #[test]
+    fn timers_fire_while_every_worker_is_busy() {
+        // The only worker keeps polling a task that wakes itself up, so the
+        // worker never runs out of tasks. The deadline only bounds a failing
+        // test.
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_ = fired.clone();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        run_with_workers(1, async move {
+            let timer_fired = fired_.clone();
+            spawn(async move {
+                Timeout::new(Duration::from_millis(10)).await;
+                timer_fired.store(true, Ordering::SeqCst);
+            });
+            spawn(std::future::poll_fn(move |ctx| {
+                if fired_.load(Ordering::SeqCst) || Instant::now() > deadline {
+                    return Poll::Ready(());
+                }
+                ctx.waker().wake_by_ref();
+                Poll::Pending
+            }));
+        });
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(Instant::now() < deadline);
+    }
+}
@@ -0,0 +1,283 @@
+use std::task::{Waker, Context, Poll};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Condvar};
+use std::os::unix::io::RawFd;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use super::REACTOR;
+
+// Offloading blocking work to a thread pool.
+
+// A blocking call, e.g. reading a file, stalls the single event loop
+// together with all the tasks on it. spawn_blocking runs the blocking
+// closure on a bounded pool of threads instead. The pool threads can not
+// touch the thread-local reactor, and the wakers of this reactor only work on
+// the thread of the reactor. So when a closure finishes, the pool thread hands
+// the waker of the waiting task over to the Remote of the reactor, and writes
+// to an eventfd registered with epoll. The reactor then wakes up the task on
+// its own thread.
+
+// The maximum number of threads in a blocking pool. When all the threads are
+// busy, the closures wait in the queue of the pool.
+pub const MAX_BLOCKING_THREADS : usize = 4;
+
+// The channel through which the pool threads wake up the tasks of a reactor.
+// fd : An eventfd registered with the epoll instance of the reactor.
+// wakers : The wakers handed over by the pool threads.
+pub(super) struct Remote {
+    fd : RawFd,
+    wakers : Mutex<Vec<Waker>>,
+}
+
+impl Remote {
+    pub(super) fn new() -> Self {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            panic!("fail to create eventfd: {}", io::Error::last_os_error());
+        }
+        Remote {
+            fd,
+            wakers : Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    // Called from a pool thread. The waker is queued before writing to the
+    // eventfd, so the reactor always finds it after reading the eventfd.
+    fn wake(&self, waker : Waker) {
+        self.wakers.lock().unwrap().push(waker);
+        let one : u64 = 1;
+        unsafe { libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8) };
+    }
+
+    // Called by the reactor when the eventfd becomes readable. Reading the
+    // eventfd resets its counter, so the next write triggers a new edge.
+    pub(super) fn wake_all(&self) {
+        let mut counter : u64 = 0;
+        unsafe { libc::read(self.fd, &mut counter as *mut u64 as *mut libc::c_void, 8) };
+        let wakers = std::mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// The state shared by a blocking pool and its threads.
+// queue : The closures waiting for a free thread.
+// threads : The number of threads in the pool.
+// idle : The number of threads waiting for a closure.
+// notified : The number of idle threads that have been notified of a closure
+// but have not woken up yet. These threads are still counted in idle, but a
+// closure arriving in the meantime needs another thread.
+// shutdown : Whether the pool is dropped, in which case the threads exit
+// once the queue is empty.
+struct PoolState {
+    queue : VecDeque<Job>,
+    threads : usize,
+    idle : usize,
+    notified : usize,
+    shutdown : bool,
+}
+
+// How a closure pushed into the queue gets a thread.
+// Notify : An idle thread is notified.
+// Spawn : A new thread is spawned.
+// Queue : Every thread is busy and the pool is full, the closure waits for
+// a thread to finish its current closure.
+#[derive(Debug, PartialEq, Eq)]
+enum Dispatch {
+    Notify,
+    Spawn,
+    Queue,
+}
+
+impl PoolState {
+    // Decide how a new closure gets a thread. The counters are updated right
+    // away under the lock, so that the closures arriving before a notified
+    // thread wakes up are not all handed to that single thread.
+    fn dispatch(&mut self) -> Dispatch {
+        if self.idle > self.notified {
+            self.notified += 1;
+            Dispatch::Notify
+        }
+        else if self.threads < MAX_BLOCKING_THREADS {
+            self.threads += 1;
+            Dispatch::Spawn
+        }
+        else {
+            Dispatch::Queue
+        }
+    }
+}
+
+struct PoolShared {
+    state : Mutex<PoolState>,
+    cond : Condvar,
+}
+
+// A bounded thread pool for running blocking closures. The threads are
+// spawned lazily, when a closure arrives and no thread is idle.
+pub(super) struct BlockingPool {
+    shared : Arc<PoolShared>,
+}
+
+impl BlockingPool {
+    pub(super) fn new() -> Self {
+        BlockingPool {
+            shared : Arc::new(PoolShared {
+                state : Mutex::new(PoolState {
+                    queue : VecDeque::new(),
+                    threads : 0,
+                    idle : 0,
+                    notified : 0,
+                    shutdown : false,
+                }),
+                cond : Condvar::new(),
+            }),
+        }
+    }
+
+    fn execute(&self, job : Job) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.push_back(job);
+        match state.dispatch() {
+            Dispatch::Notify => self.shared.cond.notify_one(),
+            Dispatch::Spawn => {
+                let shared = self.shared.clone();
+                thread::Builder::new()
+                    .name("blocking".to_string())
+                    .spawn(move || worker(shared))
+                    .expect("fail to spawn a blocking thread");
+            },
+            Dispatch::Queue => {},
+        }
+    }
+}
+
+impl Drop for BlockingPool {
+    // The threads are not joined, as they may be stuck in a blocking call.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.cond.notify_all();
+    }
+}
+
+// The main loop of a pool thread.
+fn worker(shared : Arc<PoolShared>) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if let Some(job) = state.queue.pop_front() {
+            drop(state);
+            job();
+            state = shared.state.lock().unwrap();
+            continue;
+        }
+        if state.shutdown {
+            state.threads -= 1;
+            return;
+        }
+        state.idle += 1;
+        state = shared.cond.wait(state).unwrap();
+        state.idle -= 1;
+        // The thread may also wake up spuriously or on shutdown.
+        state.notified = state.notified.saturating_sub(1);
+    }
+}
+
+// The state shared between a blocking closure and its BlockingHandle.
+// output : The output of the closure, or the payload of its panic.
+// waker : The waker of the task waiting on the BlockingHandle.
+struct BlockingState<T> {
+    output : Option<thread::Result<T>>,
+    waker : Option<Waker>,
+}
+
+// The future returned by spawn_blocking, which resolves to the output of
+// the closure. If the closure panics, the panic is resumed in the task
+// awaiting the handle. Dropping the handle does not stop the closure.
+pub struct BlockingHandle<T> {
+    state : Arc<Mutex<BlockingState<T>>>,
+}
+
+impl<T> Future for BlockingHandle<T> {
+    type Output = T;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.output.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                state.waker = Some(ctx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+// Run the blocking closure on the blocking pool of the reactor running on
+// the current thread.
+pub fn spawn_blocking<F, T>(f : F) -> BlockingHandle<T>
+    where F : FnOnce() -> T + Send + 'static, T : Send + 'static
+{
+    let state = Arc::new(Mutex::new(BlockingState {
+        output : None,
+        waker : None,
+    }));
+    REACTOR.with(|reactor| {
+        let remote = reactor.remote.clone();
+        let job_state = state.clone();
+        reactor.blocking_pool.execute(Box::new(move || {
+            let output = panic::catch_unwind(AssertUnwindSafe(f));
+            // The waker is only set if the task has polled the handle
+            // before the closure finishes.
+            let waker = {
+                let mut state = job_state.lock().unwrap();
+                state.output = Some(output);
+                state.waker.take()
+            };
+            if let Some(waker) = waker {
+                remote.wake(waker);
+            }
+        }));
+    });
+    BlockingHandle {
+        state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closures_arriving_before_the_notified_thread_wakes_up_get_new_threads() {
+        let mut state = PoolState {
+            queue : VecDeque::new(),
+            threads : 1,
+            idle : 1,
+            notified : 0,
+            shutdown : false,
+        };
+        assert_eq!(state.dispatch(), Dispatch::Notify);
+        for _ in 1..MAX_BLOCKING_THREADS {
+            assert_eq!(state.dispatch(), Dispatch::Spawn);
+        }
+        assert_eq!(state.dispatch(), Dispatch::Queue);
+        assert_eq!(state.threads, MAX_BLOCKING_THREADS);
+    }
+}
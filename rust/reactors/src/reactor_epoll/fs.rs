@@ -0,0 +1,54 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use super::blocking::spawn_blocking;
+
+// Async file I/O on top of spawn_blocking.
+
+// Regular files are always readable and writable as far as epoll is
+// concerned, so they can not be driven by the reactor like the sockets.
+// Every operation on a File runs as a blocking closure on the blocking pool
+// instead. The std file is shared with the closure through an Arc, as the
+// closure must be 'static. If a future returned by a File is dropped, the
+// closure still runs to completion in the background, but its result is
+// discarded, and the buffer passed to the future is left untouched.
+pub struct File {
+    inner : Arc<fs::File>,
+}
+
+impl File {
+    // Open a file in read-only mode.
+    pub async fn open<P : AsRef<Path>>(path : P) -> io::Result<File> {
+        let path = path.as_ref().to_owned();
+        let inner = spawn_blocking(move || fs::File::open(path)).await?;
+        Ok(File { inner : Arc::new(inner) })
+    }
+
+    // Open a file in write-only mode, creating the file if it does not
+    // exist and truncating it if it does.
+    pub async fn create<P : AsRef<Path>>(path : P) -> io::Result<File> {
+        let path = path.as_ref().to_owned();
+        let inner = spawn_blocking(move || fs::File::create(path)).await?;
+        Ok(File { inner : Arc::new(inner) })
+    }
+
+    // Read all the bytes until EOF and append them to buf. The number of
+    // bytes read is returned.
+    pub async fn read_to_end(&mut self, buf : &mut Vec<u8>) -> io::Result<usize> {
+        let inner = self.inner.clone();
+        let data = spawn_blocking(move || {
+            let mut data = Vec::new();
+            (&*inner).read_to_end(&mut data).map(|_| data)
+        }).await?;
+        buf.extend_from_slice(&data);
+        Ok(data.len())
+    }
+
+    // Write all the bytes in buf into the file.
+    pub async fn write_all(&mut self, buf : &[u8]) -> io::Result<()> {
+        let inner = self.inner.clone();
+        let data = buf.to_vec();
+        spawn_blocking(move || (&*inner).write_all(&data)).await
+    }
+}
@@ -0,0 +1,871 @@
+use std::time::{Instant, Duration};
+use std::task::{Waker, Context, Poll};
+use std::collections::{VecDeque, BTreeMap, BinaryHeap, HashMap};
+use std::cell::{RefCell, Cell};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::Arc;
+use std::cmp::{PartialEq, Eq, Ord, PartialOrd, Ordering, Reverse};
+use std::io;
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use futures_task::{ArcWake, FutureObj};
+use slab::Slab;
+
+pub mod blocking;
+pub mod fs;
+pub mod signal;
+use blocking::{Remote, BlockingPool, spawn_blocking};
+use fs::File;
+use signal::{signal, SignalKind};
+use crate::reactor2::combinator::{select, Either};
+
+// A single-threaded reactor that supports timeout and TCP networking.
+
+// The executor part of this reactor is the same as reactor1/reactor2.
+// The difference lies in the reactor part: instead of sleeping with
+// std::thread::sleep until the next timer expires, the event loop blocks
+// in epoll_wait, using the deadline of the next timer as the timeout.
+// Whenever a registered file descriptor becomes readable or writable,
+// the tasks waiting on that file descriptor are woken up.
+// The reactor contains 10 core data structures.
+// start_time : The instant at which the thread-local reactor instance is created.
+// timer_heap : A min-heap for storing different timers.
+// run_queue : A queue for storing tasks that are about to be waken up.
+// task_map : A tree-map for maintaining tasks alive.
+// id_counter : A counter that is used to generate unique IDs for tasks.
+// epoll_fd : The file descriptor of the epoll instance.
+// io_map : A hash-map that maps each registered file descriptor to the
+// wakers of the tasks that wait for the file descriptor to become ready.
+// remote : The channel through which the threads of the blocking_pool wake up
+// the tasks, whose eventfd is registered with the epoll instance.
+// blocking_pool : A bounded thread pool for running blocking closures.
+// signal_wakers : A hash-map that maps the eventfd of each kind of signal
+// listened for to the wakers of the tasks waiting for the signal. Each
+// waiting Signal owns a key in the slab, which is removed when it stops
+// waiting.
+struct Reactor {
+    start_time : Instant,
+    timer_heap : RefCell<BinaryHeap<Reverse<Timer>>>,
+    run_queue : RefCell<VecDeque<NeedRun>>,
+    task_map : RefCell<BTreeMap<usize, Task>>,
+    id_counter : Cell<usize>,
+    epoll_fd : RawFd,
+    io_map : RefCell<HashMap<RawFd, IoWakers>>,
+    remote : Arc<Remote>,
+    blocking_pool : BlockingPool,
+    signal_wakers : RefCell<HashMap<RawFd, Slab<Waker>>>,
+}
+
+// The maximum number of events returned by a single call to epoll_wait.
+const MAX_EVENTS : usize = 1024;
+
+impl Reactor {
+    // Create a new reactor together with an epoll instance.
+    // Failing to create the epoll instance leaves the reactor unusable,
+    // so we just panic.
+    fn new() -> Self {
+        let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll_fd < 0 {
+            panic!("fail to create epoll instance: {}", io::Error::last_os_error());
+        }
+
+        let reactor = Self {
+            start_time : Instant::now(),
+            timer_heap : RefCell::new(BinaryHeap::default()),
+            run_queue : RefCell::new(VecDeque::default()),
+            task_map : RefCell::new(BTreeMap::default()),
+            id_counter : Cell::new(1),
+            epoll_fd,
+            io_map : RefCell::new(HashMap::default()),
+            remote : Arc::new(Remote::new()),
+            blocking_pool : BlockingPool::new(),
+            signal_wakers : RefCell::new(HashMap::default()),
+        };
+        if let Err(e) = reactor.register(reactor.remote.fd()) {
+            panic!("fail to register eventfd: {}", e);
+        }
+        reactor
+    }
+
+    // Spawn a new task based on a new Future trait object.
+    // This is exactly the same as reactor2.
+    fn do_spawn<F : Future<Output = ()> + 'static + Send>(&self, f : F) {
+        let task_id = self.next_task_id();
+        let waker = futures_task::waker(Arc::new(WakerImpl{task_id}));
+        let mut task = Task{task : FutureObj::new(Box::new(f))};
+
+        if task.poll(waker).is_pending() {
+            // The task waits for a timer or an I/O event, keep it alive.
+            self.task_map.borrow_mut().insert(task_id, task);
+        }
+    }
+
+    // Acquire the next unique ID for a new task.
+    fn next_task_id(&self) -> usize {
+        let current_id = self.id_counter.get();
+        self.id_counter.set(current_id + 1);
+        current_id
+    }
+
+    // Register a file descriptor to the epoll instance.
+    // The file descriptor is registered in edge-triggered mode for both
+    // read and write readiness, so that we only need to register it once.
+    // A task only needs to wait for the next edge after the non-blocking
+    // I/O operation returns WouldBlock.
+    fn register(&self, fd : RawFd) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events : (libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLRDHUP | libc::EPOLLET) as u32,
+            u64 : fd as u64,
+        };
+        let res = unsafe { libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.io_map.borrow_mut().insert(fd, IoWakers::default());
+        Ok(())
+    }
+
+    // Remove a file descriptor from the epoll instance. The wakers
+    // stored for the file descriptor are dropped.
+    fn deregister(&self, fd : RawFd) {
+        unsafe { libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+        self.io_map.borrow_mut().remove(&fd);
+    }
+
+    // Store the waker of the task that waits for the file descriptor
+    // to become ready for the given interest.
+    fn set_waker(&self, fd : RawFd, interest : Interest, waker : Waker) {
+        let mut io_map = self.io_map.borrow_mut();
+        let wakers = io_map.get_mut(&fd).expect("file descriptor is not registered");
+        match interest {
+            Interest::Read => wakers.reader = Some(waker),
+            Interest::Write => wakers.writer = Some(waker),
+        }
+    }
+
+    // Register the eventfd of a kind of signal, if it is the first time
+    // that the signal is listened for in this reactor. The eventfd is shared
+    // by all the reactors, so it is never deregistered. Unlike register, only
+    // the read readiness is of interest, as an eventfd is always writable.
+    fn listen_signal(&self, fd : RawFd) -> io::Result<()> {
+        if self.signal_wakers.borrow().contains_key(&fd) {
+            return Ok(());
+        }
+        let mut event = libc::epoll_event {
+            events : (libc::EPOLLIN | libc::EPOLLET) as u32,
+            u64 : fd as u64,
+        };
+        let res = unsafe { libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.signal_wakers.borrow_mut().insert(fd, Slab::new());
+        Ok(())
+    }
+
+    // Store the waker of the task that waits for the signal. Unlike an
+    // I/O resource, a signal may be waited for by many tasks at once.
+    // A Signal polled again passes in its key, and only its own waker is
+    // replaced, so that a pending poll costs O(1).
+    fn add_signal_waker(&self, fd : RawFd, key : Option<usize>, waker : &Waker) -> usize {
+        let mut signal_wakers = self.signal_wakers.borrow_mut();
+        let wakers = signal_wakers.get_mut(&fd).expect("signal is not listened for");
+        match key.and_then(|key| wakers.get_mut(key).map(|stored| (key, stored))) {
+            Some((key, stored)) => {
+                if !stored.will_wake(waker) {
+                    *stored = waker.clone();
+                }
+                key
+            },
+            None => wakers.insert(waker.clone()),
+        }
+    }
+
+    // Remove the waker of a Signal that no longer waits for the signal.
+    fn remove_signal_waker(&self, fd : RawFd, key : usize) {
+        if let Some(wakers) = self.signal_wakers.borrow_mut().get_mut(&fd) {
+            wakers.try_remove(key);
+        }
+    }
+
+    // Block in epoll_wait for at most timeout, and wake up the tasks
+    // waiting for the file descriptors that become ready.
+    // A timeout of None blocks until an I/O event arrives.
+    fn poll_io(&self, timeout : Option<Duration>) {
+        // epoll_wait only has millisecond resolution, round up the
+        // timeout so that we do not wake up before the next timer expires.
+        let timeout_ms = match timeout {
+            Some(timeout) => {
+                let ms = timeout.as_nanos().div_ceil(1_000_000);
+                ms.min(i32::MAX as u128) as i32
+            },
+            None => -1,
+        };
+
+        let mut events : Vec<libc::epoll_event> = Vec::with_capacity(MAX_EVENTS);
+        let n = unsafe {
+            libc::epoll_wait(self.epoll_fd, events.as_mut_ptr(), MAX_EVENTS as i32, timeout_ms)
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return;
+            }
+            panic!("epoll_wait fails: {}", err);
+        }
+        unsafe { events.set_len(n as usize) };
+
+        // Waking up a task only pushes a NeedRun into the run_queue,
+        // so it is safe to hold the RefMut to io_map here.
+        let mut io_map = self.io_map.borrow_mut();
+        for event in events.iter() {
+            // epoll_event is packed, copy the fields out before using them.
+            let fd = event.u64 as RawFd;
+            let flags = event.events as i32;
+            // Some closures of the blocking_pool have finished.
+            if fd == self.remote.fd() {
+                self.remote.wake_all();
+                continue;
+            }
+            // A signal is delivered. The wakers stay in place, as they are
+            // owned by the keys of the waiting Signals.
+            if let Some(wakers) = self.signal_wakers.borrow().get(&fd) {
+                for (_, waker) in wakers.iter() {
+                    waker.wake_by_ref();
+                }
+                continue;
+            }
+            let wakers = match io_map.get_mut(&fd) {
+                Some(wakers) => wakers,
+                None => continue,
+            };
+            let hup = flags & (libc::EPOLLHUP | libc::EPOLLERR) != 0;
+            if hup || flags & (libc::EPOLLIN | libc::EPOLLRDHUP) != 0 {
+                if let Some(waker) = wakers.reader.take() {
+                    waker.wake();
+                }
+            }
+            if hup || flags & libc::EPOLLOUT != 0 {
+                if let Some(waker) = wakers.writer.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    // The actual event loop that keeps everything running.
+    fn run<F : Future<Output = ()> + 'static + Send>(&self, f:F) {
+        // Spawn the initial task.
+        self.do_spawn(f);
+
+        loop {
+            // Check whether there are pending tasks.
+            // If not, stops the eventloop. Note that this must be
+            // checked before blocking in epoll_wait, otherwise we
+            // may block forever.
+            if self.task_map.borrow().is_empty() {
+                break;
+            }
+
+            // 1: The reactor part.
+            // Calculate how long we can block in epoll_wait. If there are
+            // tasks in the run_queue, we should not block at all. Otherwise,
+            // we block until the first timer expires, or forever if there is
+            // no timer.
+            let expire = Instant::now() - self.start_time;
+            let timeout = if !self.run_queue.borrow().is_empty() {
+                Some(Duration::new(0, 0))
+            }
+            else {
+                self.timer_heap.borrow().peek().map(|next_timer| {
+                    next_timer.0.wakeup_duration.checked_sub(expire).unwrap_or_default()
+                })
+            };
+            self.poll_io(timeout);
+
+            // Iterate through all the expired timers. The current time
+            // is retrieved again since we may have blocked in epoll_wait.
+            let expire = Instant::now() - self.start_time;
+            let mut timer_heap = self.timer_heap.borrow_mut();
+            while timer_heap.peek().is_some_and(|next_timer| {
+                expire >= next_timer.0.wakeup_duration
+            }) {
+                let timer = timer_heap.pop().unwrap();
+                timer.0.waker.wake_by_ref();
+            }
+            drop(timer_heap);
+
+            // 2. The executor part.
+            // Unlike reactor2, the RefMut to the run_queue is not held while
+            // polling the task, as an I/O future may be dropped during the poll
+            // and deregister itself from the reactor.
+            let len = self.run_queue.borrow().len();
+            for _ in 0..len {
+                let needrun = self.run_queue.borrow_mut().pop_front().unwrap();
+                // A task may be woken up by both a timer and an I/O event,
+                // in which case it may have finished in an earlier poll.
+                let mut task = match self.task_map.borrow_mut().remove(&needrun.task_id) {
+                    Some(task) => task,
+                    None => continue,
+                };
+                if task.poll(needrun.waker).is_pending() {
+                    self.task_map.borrow_mut().insert(needrun.task_id, task);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epoll_fd) };
+    }
+}
+
+// The reactor is stored inside a thread local storage and read-only.
+thread_local! {
+    static REACTOR : Reactor = Reactor::new()
+}
+
+// The implementation of the waker, which only contains a task_id.
+struct WakerImpl {
+    task_id : usize,
+}
+
+impl ArcWake for WakerImpl {
+    fn wake_by_ref(arc_self : &Arc<Self>) {
+        let next_need_run = NeedRun {
+            task_id : arc_self.task_id,
+            waker : futures_task::waker(arc_self.clone()),
+        };
+        REACTOR.with(|reactor|{
+            reactor.run_queue.borrow_mut().push_back(next_need_run);
+        });
+    }
+}
+
+// An item stored in the run_queue, indicating which task should
+// be woken up and resumed.
+struct NeedRun {
+    task_id : usize,
+    waker : Waker,
+}
+
+// The actual representation of an asynchronous task in this implementation.
+struct Task {
+    task : FutureObj<'static, ()>,
+}
+
+impl Task {
+    fn poll(&mut self, waker : Waker) -> Poll<()> {
+        let pinned = Pin::new(&mut self.task);
+        let mut ctx = Context::from_waker(&waker);
+        Future::poll(pinned, &mut ctx)
+    }
+}
+
+// The item stored in the io_map.
+// reader : The waker of the task waiting for the file descriptor to become readable.
+// writer : The waker of the task waiting for the file descriptor to become writable.
+#[derive(Default)]
+struct IoWakers {
+    reader : Option<Waker>,
+    writer : Option<Waker>,
+}
+
+// The kind of readiness that a task is waiting for.
+enum Interest {
+    Read,
+    Write,
+}
+
+impl PartialEq<Timer> for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.wakeup_duration == other.wakeup_duration
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd<Timer> for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.wakeup_duration.cmp(&other.wakeup_duration)
+    }
+}
+
+// The item stored in the timer_heap, the same as reactor2.
+struct Timer {
+    wakeup_duration : Duration,
+    waker : Waker,
+}
+
+// A future object that sleeps for a certain amount of time,
+// the same as reactor2.
+// deadline : The wakeup duration of the inserted timer. A Timeout may be
+// polled before its timer expires, e.g. by select when the other future is
+// woken up, in which case it must keep waiting.
+pub struct Timeout {
+    duration : Duration,
+    deadline : Option<Duration>,
+}
+
+impl Unpin for Timeout {}
+
+impl Timeout {
+    pub fn new(duration : Duration) -> Self {
+        Timeout {
+            duration,
+            deadline : None,
+        }
+    }
+}
+
+impl Future for Timeout {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(deadline) = self.deadline {
+            let expire = REACTOR.with(|reactor| Instant::now() - reactor.start_time);
+            if expire >= deadline {
+                Poll::Ready(())
+            }
+            else {
+                Poll::Pending
+            }
+        }
+        else if self.duration == Duration::new(0, 0) {
+            Poll::Ready(())
+        }
+        else {
+            let duration = self.duration;
+            let self_mut = self.get_mut();
+            self_mut.duration = Duration::new(0, 0);
+            let waker = ctx.waker().clone();
+
+            REACTOR.with(|reactor|{
+                let timer = Timer {
+                    wakeup_duration : (Instant::now() - reactor.start_time) + duration,
+                    waker
+                };
+                self_mut.deadline = Some(timer.wakeup_duration);
+                reactor.timer_heap.borrow_mut().push(Reverse(timer));
+            });
+
+            Poll::Pending
+        }
+    }
+}
+
+// A non-blocking TCP listener registered with the reactor.
+pub struct TcpListener {
+    inner : net::TcpListener,
+}
+
+impl TcpListener {
+    // Bind a new listener to the given address and register it with
+    // the thread-local reactor.
+    pub fn bind<A : ToSocketAddrs>(addr : A) -> io::Result<Self> {
+        let inner = net::TcpListener::bind(addr)?;
+        inner.set_nonblocking(true)?;
+        REACTOR.with(|reactor| reactor.register(inner.as_raw_fd()))?;
+        Ok(TcpListener { inner })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    // Accept a new incoming connection.
+    pub fn accept(&self) -> Accept<'_> {
+        Accept { listener : self }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        REACTOR.with(|reactor| reactor.deregister(self.inner.as_raw_fd()));
+    }
+}
+
+// A non-blocking TCP stream registered with the reactor.
+pub struct TcpStream {
+    inner : net::TcpStream,
+}
+
+impl TcpStream {
+    // Wrap an accepted std TcpStream and register it with the
+    // thread-local reactor.
+    fn new(inner : net::TcpStream) -> io::Result<Self> {
+        inner.set_nonblocking(true)?;
+        REACTOR.with(|reactor| reactor.register(inner.as_raw_fd()))?;
+        Ok(TcpStream { inner })
+    }
+
+    // Read some bytes from the stream into buf.
+    // The future resolves to the number of bytes read, and 0 indicates EOF.
+    pub fn read<'a>(&'a mut self, buf : &'a mut [u8]) -> Read<'a> {
+        Read { stream : self, buf }
+    }
+
+    // Write some bytes in buf into the stream.
+    // The future resolves to the number of bytes written, which may be
+    // smaller than the length of buf.
+    pub fn write<'a>(&'a mut self, buf : &'a [u8]) -> Write<'a> {
+        Write { stream : self, buf }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        REACTOR.with(|reactor| reactor.deregister(self.inner.as_raw_fd()));
+    }
+}
+
+// Try a non-blocking I/O operation. If the operation would block, the
+// waker of the current task is registered with the reactor for the given
+// interest, and Poll::Pending is returned.
+fn poll_io_op<T, F>(fd : RawFd, interest : Interest, ctx : &mut Context<'_>, mut op : F) -> Poll<io::Result<T>>
+    where F : FnMut() -> io::Result<T>
+{
+    loop {
+        match op() {
+            Ok(res) => return Poll::Ready(Ok(res)),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // The file descriptor is registered in edge-triggered mode,
+                // the task will be woken up when the next edge arrives. Since
+                // the reactor is single-threaded, no edge can arrive between
+                // the failed operation and the registration of the waker.
+                REACTOR.with(|reactor| {
+                    reactor.set_waker(fd, interest, ctx.waker().clone());
+                });
+                return Poll::Pending;
+            },
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+    }
+}
+
+// The future returned by TcpListener::accept.
+pub struct Accept<'a> {
+    listener : &'a TcpListener,
+}
+
+impl Future for Accept<'_> {
+    type Output = io::Result<(TcpStream, SocketAddr)>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let listener = &self.listener.inner;
+        match poll_io_op(listener.as_raw_fd(), Interest::Read, ctx, || listener.accept()) {
+            Poll::Ready(Ok((stream, addr))) => Poll::Ready(TcpStream::new(stream).map(|stream| (stream, addr))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// The future returned by TcpStream::read.
+pub struct Read<'a> {
+    stream : &'a mut TcpStream,
+    buf : &'a mut [u8],
+}
+
+impl Future for Read<'_> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        let fd = self_mut.stream.inner.as_raw_fd();
+        let inner = &mut self_mut.stream.inner;
+        let buf = &mut *self_mut.buf;
+        poll_io_op(fd, Interest::Read, ctx, || io::Read::read(inner, buf))
+    }
+}
+
+// The future returned by TcpStream::write.
+pub struct Write<'a> {
+    stream : &'a mut TcpStream,
+    buf : &'a [u8],
+}
+
+impl Future for Write<'_> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        let fd = self_mut.stream.inner.as_raw_fd();
+        let inner = &mut self_mut.stream.inner;
+        let buf = self_mut.buf;
+        poll_io_op(fd, Interest::Write, ctx, || io::Write::write(inner, buf))
+    }
+}
+
+// The entry point of the async eventloop.
+pub fn run<F : Future<Output = ()> + 'static + Send>(f : F) {
+    REACTOR.with(|reactor| {
+        reactor.run(f);
+    });
+}
+
+// Spawning a new Future task inside the eventloop.
+pub fn spawn<F : Future<Output = ()> + 'static + Send>(f : F) {
+    REACTOR.with(|reactor| {
+        reactor.do_spawn(f);
+    });
+}
+
+// Echo everything received from the stream back to the peer, until
+// the peer closes the connection.
+pub(crate) async fn echo(mut stream : TcpStream) {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        // A single write may not send all the bytes out.
+        let mut written = 0;
+        while written < n {
+            match stream.write(&buf[written..n]).await {
+                Ok(m) => written += m,
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+// Serve the echo clients until SIGINT arrives. On SIGINT, the server stops
+// accepting, and every task closes its connection and returns, so that the
+// event loop stops gracefully.
+async fn echo_server(addr : &'static str) {
+    let listener = TcpListener::bind(addr).unwrap();
+    println!("echo server listens on {}", listener.local_addr().unwrap());
+    let mut sigint = signal(SignalKind::Interrupt).unwrap();
+    loop {
+        match select(listener.accept(), sigint.recv()).await {
+            Either::Left(Ok((stream, peer))) => {
+                println!("accept a new connection from {}", peer);
+                spawn(async move {
+                    let mut sigint = signal(SignalKind::Interrupt).unwrap();
+                    select(echo(stream), sigint.recv()).await;
+                });
+            },
+            Either::Left(Err(e)) => println!("fail to accept: {}", e),
+            Either::Right(()) => {
+                println!("SIGINT received, the echo server shuts down");
+                return;
+            },
+        }
+    }
+}
+
+async fn heartbeat_task() {
+    let mut sigint = signal(SignalKind::Interrupt).unwrap();
+    loop {
+        if let Either::Right(()) = select(Timeout::new(Duration::from_secs(5)), sigint.recv()).await {
+            return;
+        }
+        println!("the echo server is alive");
+    }
+}
+
+// Write a banner into a file and read it back, without blocking the
+// event loop.
+async fn load_banner() -> io::Result<Vec<u8>> {
+    let path = std::env::temp_dir().join("reactor_epoll_banner.txt");
+    File::create(&path).await?.write_all(b"welcome to the echo server").await?;
+    let mut banner = Vec::new();
+    File::open(&path).await?.read_to_end(&mut banner).await?;
+    spawn_blocking(move || std::fs::remove_file(path)).await?;
+    Ok(banner)
+}
+
+pub fn launch() {
+    run(async {
+        let banner = load_banner().await.unwrap();
+        println!("{}", String::from_utf8_lossy(&banner));
+        spawn(heartbeat_task());
+        echo_server("127.0.0.1:10240").await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read as _, Write as _};
+    use std::thread;
+
+    #[test]
+    fn echo_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            let payload = vec![7u8; 256 * 1024];
+            stream.write_all(&payload).unwrap();
+            stream.shutdown(net::Shutdown::Write).unwrap();
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).unwrap();
+            echoed == payload
+        });
+
+        run(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            echo(stream).await;
+        });
+        assert!(client.join().unwrap());
+    }
+
+    #[test]
+    fn timer_fires_while_waiting_for_io() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let start = Instant::now();
+
+        run(async move {
+            spawn(async move {
+                Timeout::new(Duration::from_millis(50)).await;
+                net::TcpStream::connect(addr).unwrap();
+            });
+            listener.accept().await.unwrap();
+        });
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn blocking_closure_does_not_stall_the_reactor() {
+        let start = Instant::now();
+        let timer_done = Arc::new(std::sync::Mutex::new(None));
+        let recorder = timer_done.clone();
+
+        run(async move {
+            spawn(async move {
+                Timeout::new(Duration::from_millis(10)).await;
+                *recorder.lock().unwrap() = Some(start.elapsed());
+            });
+            let output = spawn_blocking(|| {
+                thread::sleep(Duration::from_millis(100));
+                42
+            }).await;
+            assert_eq!(output, 42);
+        });
+        // The timer fires while the blocking closure sleeps.
+        assert!(timer_done.lock().unwrap().unwrap() < Duration::from_millis(100));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn blocking_pool_is_bounded() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        let (running_, max_running_, finished_) = (running.clone(), max_running.clone(), finished.clone());
+
+        run(async move {
+            for _ in 0..(2 * blocking::MAX_BLOCKING_THREADS) {
+                let (running, max_running, finished) = (running_.clone(), max_running_.clone(), finished_.clone());
+                spawn(async move {
+                    spawn_blocking(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(20));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    }).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(finished.load(Ordering::SeqCst), 2 * blocking::MAX_BLOCKING_THREADS);
+        assert!(max_running.load(Ordering::SeqCst) <= blocking::MAX_BLOCKING_THREADS);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("reactor_epoll_file_{}", std::process::id()));
+        let payload : Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let expected = payload.clone();
+
+        run(async move {
+            File::create(&path).await.unwrap().write_all(&payload).await.unwrap();
+            let mut buf = b"head".to_vec();
+            let n = File::open(&path).await.unwrap().read_to_end(&mut buf).await.unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(n, expected.len());
+            assert_eq!(&buf[..4], b"head");
+            assert_eq!(&buf[4..], &expected[..]);
+        });
+    }
+
+    #[test]
+    fn signal_wakes_up_every_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            // The connection is closed by the server on the signal.
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+        });
+
+        run(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            spawn(async move {
+                let mut sigusr1 = signal(SignalKind::User1).unwrap();
+                select(echo(stream), sigusr1.recv()).await;
+            });
+            let mut sigusr1 = signal(SignalKind::User1).unwrap();
+            spawn(async {
+                Timeout::new(Duration::from_millis(10)).await;
+                unsafe { libc::raise(libc::SIGUSR1) };
+                unsafe { libc::raise(libc::SIGUSR1) };
+            });
+            sigusr1.recv().await;
+            // The two deliveries are coalesced into one.
+            let res = select(Timeout::new(Duration::from_millis(10)), sigusr1.recv()).await;
+            assert!(matches!(res, Either::Left(())));
+        });
+        client.join().unwrap();
+    }
+
+    // The number of signal wakers stored in the reactor of this thread.
+    fn signal_waker_count() -> usize {
+        REACTOR.with(|reactor| reactor.signal_wakers.borrow().values().map(Slab::len).sum())
+    }
+
+    #[test]
+    fn signal_stream_yields_every_delivery() {
+        use futures_core::Stream;
+
+        run(async {
+            let mut sighup = signal(SignalKind::Hangup).unwrap();
+            spawn(async {
+                for _ in 0..2 {
+                    Timeout::new(Duration::from_millis(10)).await;
+                    unsafe { libc::raise(libc::SIGHUP) };
+                }
+            });
+            for _ in 0..2 {
+                let item = std::future::poll_fn(|ctx| Pin::new(&mut sighup).poll_next(ctx)).await;
+                assert_eq!(item, Some(()));
+                assert_eq!(signal_waker_count(), 0);
+            }
+
+            // A Recv losing a select removes its waker, however many times
+            // it has been polled.
+            for _ in 0..3 {
+                let res = select(Timeout::new(Duration::from_millis(1)), sighup.recv()).await;
+                assert!(matches!(res, Either::Left(())));
+                assert_eq!(signal_waker_count(), 0);
+            }
+        });
+    }
+}
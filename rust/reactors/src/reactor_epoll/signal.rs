@@ -0,0 +1,222 @@
+use std::task::{Context, Poll};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::Once;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::os::unix::io::RawFd;
+use std::io;
+use futures_core::Stream;
+use super::REACTOR;
+
+// Receiving Unix signals, e.g. SIGINT on Ctrl-C, as asynchronous events.
+
+// A signal handler may interrupt the thread at any point, so it can only do
+// async-signal-safe work: it can not touch the thread-local reactor, lock a
+// mutex or allocate. Each kind of signal therefore has a global counter and
+// a global eventfd. The handler increments the counter and writes to the
+// eventfd, which is the classic self-pipe trick with an eventfd as the pipe.
+// The eventfd is registered with every reactor that listens for the signal,
+// and the reactor wakes up the tasks waiting for the signal whenever an edge
+// arrives. The eventfd is never read, so that every reactor sees the edge,
+// and a Signal compares the counter with the last value it has seen instead.
+// The handler is installed the first time a kind of signal is listened for,
+// and stays installed until the process exits, so the default action of the
+// signal, e.g. terminating the process on SIGINT, no longer happens.
+
+// The kinds of signals that can be listened for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalKind {
+    Interrupt,
+    Terminate,
+    Hangup,
+    User1,
+}
+
+const SIGNAL_KINDS : usize = 4;
+
+impl SignalKind {
+    fn index(self) -> usize {
+        match self {
+            SignalKind::Interrupt => 0,
+            SignalKind::Terminate => 1,
+            SignalKind::Hangup => 2,
+            SignalKind::User1 => 3,
+        }
+    }
+
+    fn number(self) -> libc::c_int {
+        match self {
+            SignalKind::Interrupt => libc::SIGINT,
+            SignalKind::Terminate => libc::SIGTERM,
+            SignalKind::Hangup => libc::SIGHUP,
+            SignalKind::User1 => libc::SIGUSR1,
+        }
+    }
+
+    fn from_number(signum : libc::c_int) -> Option<Self> {
+        match signum {
+            libc::SIGINT => Some(SignalKind::Interrupt),
+            libc::SIGTERM => Some(SignalKind::Terminate),
+            libc::SIGHUP => Some(SignalKind::Hangup),
+            libc::SIGUSR1 => Some(SignalKind::User1),
+            _ => None,
+        }
+    }
+}
+
+// The global state of a kind of signal, shared with the signal handler.
+// fd : The eventfd written by the handler, -1 before the handler is installed.
+// count : The number of times the signal is delivered.
+struct Globals {
+    fd : AtomicI32,
+    count : AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const GLOBALS_INIT : Globals = Globals {
+    fd : AtomicI32::new(-1),
+    count : AtomicU64::new(0),
+};
+
+static GLOBALS : [Globals; SIGNAL_KINDS] = [GLOBALS_INIT; SIGNAL_KINDS];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ONCE_INIT : Once = Once::new();
+
+static INSTALL : [Once; SIGNAL_KINDS] = [ONCE_INIT; SIGNAL_KINDS];
+
+// The signal handler. errno is saved and restored, as the write may
+// overwrite the errno observed by the interrupted code.
+extern "C" fn handler(signum : libc::c_int) {
+    let kind = match SignalKind::from_number(signum) {
+        Some(kind) => kind,
+        None => return,
+    };
+    let globals = &GLOBALS[kind.index()];
+    globals.count.fetch_add(1, Ordering::SeqCst);
+    let fd = globals.fd.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe {
+            let errno = *libc::__errno_location();
+            let one : u64 = 1;
+            libc::write(fd, &one as *const u64 as *const libc::c_void, 8);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+// Create the eventfd of the signal and install the handler, only once.
+// Failing to do so leaves the signal unusable, so we just panic.
+fn install(kind : SignalKind) -> RawFd {
+    INSTALL[kind.index()].call_once(|| {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            panic!("fail to create eventfd: {}", io::Error::last_os_error());
+        }
+        GLOBALS[kind.index()].fd.store(fd, Ordering::SeqCst);
+
+        let res = unsafe {
+            let mut action : libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(kind.number(), &action, std::ptr::null_mut())
+        };
+        if res < 0 {
+            panic!("fail to install the handler of {:?}: {}", kind, io::Error::last_os_error());
+        }
+    });
+    GLOBALS[kind.index()].fd.load(Ordering::SeqCst)
+}
+
+// A stream of the deliveries of a kind of signal. Each call to recv, or to
+// poll_next of the Stream trait, yields the next delivery. The deliveries
+// arriving between two calls are coalesced into one.
+// kind : The kind of signal.
+// seen : The value of the global counter when the last delivery is yielded.
+// key : The key of the waker stored in the reactor while the Signal waits
+// for a delivery, which is removed as soon as it stops waiting.
+pub struct Signal {
+    kind : SignalKind,
+    seen : u64,
+    key : Option<usize>,
+}
+
+impl Signal {
+    // Wait for the next delivery of the signal.
+    pub fn recv(&mut self) -> Recv<'_> {
+        Recv { signal : self }
+    }
+
+    // The reactor is single-threaded, so the edge of a delivery arriving
+    // after the counter is loaded is handled after the waker is stored.
+    fn poll_recv(&mut self, ctx : &mut Context<'_>) -> Poll<()> {
+        let globals = &GLOBALS[self.kind.index()];
+        let count = globals.count.load(Ordering::SeqCst);
+        if count != self.seen {
+            self.seen = count;
+            self.stop_waiting();
+            return Poll::Ready(());
+        }
+        let fd = globals.fd.load(Ordering::SeqCst);
+        let key = self.key;
+        self.key = Some(REACTOR.with(|reactor| reactor.add_signal_waker(fd, key, ctx.waker())));
+        Poll::Pending
+    }
+
+    // Remove the waker stored in the reactor, if any.
+    fn stop_waiting(&mut self) {
+        if let Some(key) = self.key.take() {
+            let fd = GLOBALS[self.kind.index()].fd.load(Ordering::SeqCst);
+            REACTOR.with(|reactor| reactor.remove_signal_waker(fd, key));
+        }
+    }
+}
+
+impl Stream for Signal {
+    type Item = ();
+
+    // A signal never ends, so the stream never yields None.
+    fn poll_next(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Option<()>> {
+        self.get_mut().poll_recv(ctx).map(Some)
+    }
+}
+
+impl Drop for Signal {
+    fn drop(&mut self) {
+        self.stop_waiting();
+    }
+}
+
+// The future returned by Signal::recv.
+pub struct Recv<'a> {
+    signal : &'a mut Signal,
+}
+
+impl Future for Recv<'_> {
+    type Output = ();
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().signal.poll_recv(ctx)
+    }
+}
+
+// A Recv dropped before the delivery, e.g. the losing side of a select,
+// does not leave its waker behind in the reactor.
+impl Drop for Recv<'_> {
+    fn drop(&mut self) {
+        self.signal.stop_waiting();
+    }
+}
+
+// Listen for a kind of signal in the reactor running on the current thread.
+// Only the deliveries after the call are yielded.
+pub fn signal(kind : SignalKind) -> io::Result<Signal> {
+    let fd = install(kind);
+    REACTOR.with(|reactor| reactor.listen_signal(fd))?;
+    Ok(Signal {
+        kind,
+        seen : GLOBALS[kind.index()].count.load(Ordering::SeqCst),
+        key : None,
+    })
+}
@@ -0,0 +1,96 @@
+use std::io::{Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::reactor_epoll;
+
+// A benchmark comparing the echo throughput of reactor_epoll and reactor_uring.
+
+// For each reactor, a server thread runs the reactor with an echo server
+// that accepts conns connections. Each client thread connects to the server,
+// writes bytes_per_conn bytes in chunks, and reads the echoed bytes back on a
+// separate reader thread, so that the client never stops the server by
+// leaving the echoed bytes unread. The benchmark reports the total time and
+// throughput of echoing all the bytes. Run it in release mode with
+// `cargo run --release --features uring -- uring-bench`.
+pub fn echo_throughput(conns : usize, bytes_per_conn : usize) {
+    let elapsed = run_clients(conns, bytes_per_conn, epoll_server(conns));
+    report("epoll", conns * bytes_per_conn, elapsed);
+    let elapsed = run_clients(conns, bytes_per_conn, uring_server(conns));
+    report("io_uring", conns * bytes_per_conn, elapsed);
+}
+
+fn report(name : &str, total_bytes : usize, elapsed : Duration) {
+    let mb = total_bytes as f64 / (1024.0 * 1024.0);
+    println!("{:>8}: echo {:.0}MB in {:?}, {:.1}MB/s", name, mb, elapsed, mb / elapsed.as_secs_f64());
+}
+
+// Start an epoll echo server on its own thread, and return its address.
+// The reactor stops once all the connections are closed.
+fn epoll_server(conns : usize) -> SocketAddr {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let listener = reactor_epoll::TcpListener::bind("127.0.0.1:0").unwrap();
+        sender.send(listener.local_addr().unwrap()).unwrap();
+        reactor_epoll::run(async move {
+            for _ in 0..conns {
+                let (stream, _) = listener.accept().await.unwrap();
+                reactor_epoll::spawn(reactor_epoll::echo(stream));
+            }
+        });
+    });
+    receiver.recv().unwrap()
+}
+
+// Start an io_uring echo server on its own thread, and return its address.
+fn uring_server(conns : usize) -> SocketAddr {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let listener = super::TcpListener::bind("127.0.0.1:0").unwrap();
+        sender.send(listener.local_addr().unwrap()).unwrap();
+        super::run(async move {
+            for _ in 0..conns {
+                let (stream, _) = listener.accept().await.unwrap();
+                super::spawn(super::echo(stream));
+            }
+        });
+    });
+    receiver.recv().unwrap()
+}
+
+// Run conns clients against the server at addr, and return the time until
+// all the echoed bytes are received.
+fn run_clients(conns : usize, bytes_per_conn : usize, addr : SocketAddr) -> Duration {
+    let start = Instant::now();
+    let clients : Vec<_> = (0..conns).map(|_| {
+        thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            let reader = thread::spawn(move || {
+                let mut buf = vec![0u8; 64 * 1024];
+                let mut received = 0;
+                while received < bytes_per_conn {
+                    match reader.read(&mut buf).unwrap() {
+                        0 => break,
+                        n => received += n,
+                    }
+                }
+                received
+            });
+            let chunk = vec![7u8; 64 * 1024];
+            let mut sent = 0;
+            while sent < bytes_per_conn {
+                let n = chunk.len().min(bytes_per_conn - sent);
+                stream.write_all(&chunk[..n]).unwrap();
+                sent += n;
+            }
+            let received = reader.join().unwrap();
+            assert_eq!(received, bytes_per_conn);
+        })
+    }).collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    start.elapsed()
+}
@@ -0,0 +1,636 @@
+use std::time::Duration;
+use std::task::{Waker, Context, Poll};
+use std::collections::{VecDeque, BTreeMap};
+use std::cell::{RefCell, Cell};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::Arc;
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use futures_task::{ArcWake, FutureObj};
+use io_uring::{IoUring, opcode, squeue, types};
+use slab::Slab;
+
+pub mod bench;
+
+// A single-threaded reactor built on io_uring.
+
+// The readiness model of reactor_epoll tells a task when a file descriptor
+// becomes ready, and the task then performs the non-blocking I/O operation
+// by itself. io_uring is completion-based instead: a task submits the whole
+// operation, e.g. a recv together with the buffer, and the kernel performs
+// the operation and reports its result. As the kernel writes into the buffer
+// after the submission, the buffer must stay alive and in place until the
+// operation completes, even if the future of the operation is dropped. So
+// the operations take owned buffers and hand them back on completion, instead
+// of borrowing the buffers like reactor_epoll.
+// The executor part of this reactor is the same as reactor_epoll. Timers are
+// io_uring timeout operations, so the reactor needs no timer_heap.
+// The reactor contains 5 core data structures.
+// ring : The io_uring instance. The submission queue entries pushed by the
+// tasks are submitted in a batch at the start of each round of the event loop.
+// ops : A slab of the in-flight operations. The key of an operation is passed
+// to the kernel as the user_data of the submission queue entry, and returned
+// in the completion queue entry.
+// run_queue : A queue for storing tasks that are about to be waken up.
+// task_map : A tree-map for maintaining tasks alive.
+// id_counter : A counter that is used to generate unique IDs for tasks.
+struct Reactor {
+    ring : RefCell<IoUring>,
+    ops : RefCell<Slab<Lifecycle>>,
+    run_queue : RefCell<VecDeque<NeedRun>>,
+    task_map : RefCell<BTreeMap<usize, Task>>,
+    id_counter : Cell<usize>,
+}
+
+// The number of entries of the submission queue.
+const RING_ENTRIES : u32 = 256;
+
+// The user_data of the cancel requests, whose completions are ignored.
+const CANCEL_KEY : u64 = u64::MAX;
+
+impl Reactor {
+    // Create a new reactor together with an io_uring instance.
+    // Failing to create the io_uring instance leaves the reactor unusable,
+    // so we just panic.
+    fn new() -> Self {
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(ring) => ring,
+            Err(e) => panic!("fail to create io_uring instance: {}", e),
+        };
+
+        Self {
+            ring : RefCell::new(ring),
+            ops : RefCell::new(Slab::new()),
+            run_queue : RefCell::new(VecDeque::default()),
+            task_map : RefCell::new(BTreeMap::default()),
+            id_counter : Cell::new(1),
+        }
+    }
+
+    // Spawn a new task based on a new Future trait object.
+    // This is exactly the same as reactor_epoll.
+    fn do_spawn<F : Future<Output = ()> + 'static + Send>(&self, f : F) {
+        let task_id = self.next_task_id();
+        let waker = futures_task::waker(Arc::new(WakerImpl{task_id}));
+        let mut task = Task{task : FutureObj::new(Box::new(f))};
+
+        if task.poll(waker).is_pending() {
+            // The task waits for an operation to complete, keep it alive.
+            self.task_map.borrow_mut().insert(task_id, task);
+        }
+    }
+
+    // Acquire the next unique ID for a new task.
+    fn next_task_id(&self) -> usize {
+        let current_id = self.id_counter.get();
+        self.id_counter.set(current_id + 1);
+        current_id
+    }
+
+    // Push a submission queue entry. If the submission queue is full, the
+    // queued entries are submitted to the kernel right away to make room.
+    fn push(&self, entry : squeue::Entry) {
+        let mut ring = self.ring.borrow_mut();
+        loop {
+            // The entry only points to the resources owned by the operation,
+            // which are kept alive until the operation completes.
+            if unsafe { ring.submission().push(&entry) }.is_ok() {
+                return;
+            }
+            if let Err(e) = ring.submit() {
+                panic!("io_uring submission fails: {}", e);
+            }
+        }
+    }
+
+    // Submit a new operation, and return its key in the ops slab.
+    fn submit(&self, entry : squeue::Entry) -> usize {
+        let key = self.ops.borrow_mut().insert(Lifecycle::Submitted);
+        self.push(entry.user_data(key as u64));
+        key
+    }
+
+    // Submit the queued entries, and wait for at least want completions.
+    // The completed operations are recorded in the ops slab, and the
+    // tasks waiting for them are woken up.
+    fn submit_and_reap(&self, want : usize) {
+        let completions : Vec<(u64, i32)> = {
+            let mut ring = self.ring.borrow_mut();
+            match ring.submit_and_wait(want) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => panic!("io_uring submission fails: {}", e),
+            }
+            ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect()
+        };
+
+        for (user_data, res) in completions {
+            if user_data == CANCEL_KEY {
+                continue;
+            }
+            let key = user_data as usize;
+            let mut ops = self.ops.borrow_mut();
+            match mem::replace(&mut ops[key], Lifecycle::Completed(res)) {
+                Lifecycle::Submitted => {},
+                Lifecycle::Waiting(waker) => {
+                    // Waking up a task only pushes a NeedRun into the
+                    // run_queue, so it is safe to hold the RefMut to ops.
+                    waker.wake();
+                },
+                Lifecycle::Ignored(on_complete) => {
+                    // The future of the operation has been dropped. Release
+                    // the resources of the operation after releasing the
+                    // RefMut to ops.
+                    ops.remove(key);
+                    drop(ops);
+                    on_complete(res);
+                },
+                Lifecycle::Completed(_) => unreachable!("an operation completes twice"),
+            }
+        }
+    }
+
+    // The actual event loop that keeps everything running.
+    fn run<F : Future<Output = ()> + 'static + Send>(&self, f:F) {
+        // Spawn the initial task.
+        self.do_spawn(f);
+
+        loop {
+            // Check whether there are pending tasks.
+            // If not, stops the eventloop.
+            if self.task_map.borrow().is_empty() {
+                break;
+            }
+
+            // 1: The reactor part.
+            // Submit the entries pushed by the tasks in the last round. If
+            // there are tasks in the run_queue, we should not block at all.
+            // Otherwise, we block until an operation completes.
+            let want = if self.run_queue.borrow().is_empty() { 1 } else { 0 };
+            self.submit_and_reap(want);
+
+            // 2. The executor part, the same as reactor_epoll.
+            let len = self.run_queue.borrow().len();
+            for _ in 0..len {
+                let needrun = self.run_queue.borrow_mut().pop_front().unwrap();
+                let mut task = match self.task_map.borrow_mut().remove(&needrun.task_id) {
+                    Some(task) => task,
+                    None => continue,
+                };
+                if task.poll(needrun.waker).is_pending() {
+                    self.task_map.borrow_mut().insert(needrun.task_id, task);
+                }
+            }
+        }
+
+        // The operations of the dropped futures may still be in flight.
+        // Wait for them to complete, so that the kernel never writes into
+        // a buffer after the buffer is released.
+        while !self.ops.borrow().is_empty() {
+            self.submit_and_reap(1);
+        }
+    }
+}
+
+// The reactor is stored inside a thread local storage and read-only.
+thread_local! {
+    static REACTOR : Reactor = Reactor::new()
+}
+
+// The implementation of the waker, the same as reactor_epoll.
+struct WakerImpl {
+    task_id : usize,
+}
+
+impl ArcWake for WakerImpl {
+    fn wake_by_ref(arc_self : &Arc<Self>) {
+        let next_need_run = NeedRun {
+            task_id : arc_self.task_id,
+            waker : futures_task::waker(arc_self.clone()),
+        };
+        REACTOR.with(|reactor|{
+            reactor.run_queue.borrow_mut().push_back(next_need_run);
+        });
+    }
+}
+
+// An item stored in the run_queue, indicating which task should
+// be woken up and resumed.
+struct NeedRun {
+    task_id : usize,
+    waker : Waker,
+}
+
+// The actual representation of an asynchronous task in this implementation.
+struct Task {
+    task : FutureObj<'static, ()>,
+}
+
+impl Task {
+    fn poll(&mut self, waker : Waker) -> Poll<()> {
+        let pinned = Pin::new(&mut self.task);
+        let mut ctx = Context::from_waker(&waker);
+        Future::poll(pinned, &mut ctx)
+    }
+}
+
+// The lifecycle of an in-flight operation stored in the ops slab.
+// Submitted : The operation is submitted and nobody waits for it yet.
+// Waiting : A task waits for the operation to complete.
+// Completed : The operation completes with the result, which is taken by
+// the next poll of the future.
+// Ignored : The future is dropped before the operation completes. The
+// closure owns the resources of the operation, and is called with the
+// result once the operation completes.
+enum Lifecycle {
+    Submitted,
+    Waiting(Waker),
+    Completed(i32),
+    Ignored(Box<dyn FnOnce(i32)>),
+}
+
+// An operation that can be submitted to the io_uring.
+// entry : Build the submission queue entry, which may only point to the
+// heap-allocated resources owned by the operation, so that the entry stays
+// valid when the operation is moved.
+// complete : Turn the result of the operation into the output of the future.
+// A negative result is the negated errno.
+pub trait Completable {
+    type Output;
+    fn entry(&mut self) -> squeue::Entry;
+    fn complete(self, res : i32) -> Self::Output;
+}
+
+// The future of an operation. The operation is submitted on the first poll.
+// data : The operation, which owns the resources used by the kernel.
+// key : The key of the operation in the ops slab, once it is submitted.
+pub struct Op<T : Completable + 'static> {
+    data : Option<T>,
+    key : Option<usize>,
+}
+
+impl<T : Completable + 'static> Op<T> {
+    fn submit(data : T) -> Self {
+        Op {
+            data : Some(data),
+            key : None,
+        }
+    }
+}
+
+// Op is Unpin, the resources used by the kernel are stored on the heap.
+impl<T : Completable + 'static> Unpin for Op<T> {}
+
+impl<T : Completable + 'static> Future for Op<T> {
+    type Output = T::Output;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        REACTOR.with(|reactor| {
+            let key = match self_mut.key {
+                Some(key) => key,
+                None => {
+                    let entry = self_mut.data.as_mut().expect("Op polled after completion").entry();
+                    let key = reactor.submit(entry);
+                    self_mut.key = Some(key);
+                    key
+                },
+            };
+            let mut ops = reactor.ops.borrow_mut();
+            match mem::replace(&mut ops[key], Lifecycle::Submitted) {
+                Lifecycle::Completed(res) => {
+                    ops.remove(key);
+                    self_mut.key = None;
+                    Poll::Ready(self_mut.data.take().unwrap().complete(res))
+                },
+                _ => {
+                    ops[key] = Lifecycle::Waiting(ctx.waker().clone());
+                    Poll::Pending
+                },
+            }
+        })
+    }
+}
+
+impl<T : Completable + 'static> Drop for Op<T> {
+    // Dropping an in-flight operation hands its resources over to the ops
+    // slab and asks the kernel to cancel the operation. The resources are
+    // released once the operation completes or is cancelled. An operation
+    // that completes but is never polled is completed right away, so that
+    // e.g. an accepted connection is closed.
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        let data = self.data.take().unwrap();
+        // The reactor may have been destroyed if the Op is dropped during
+        // the destruction of the thread-local storage.
+        let _ = REACTOR.try_with(|reactor| {
+            let mut ops = reactor.ops.borrow_mut();
+            match &ops[key] {
+                Lifecycle::Completed(res) => {
+                    let res = *res;
+                    ops.remove(key);
+                    drop(ops);
+                    drop(data.complete(res));
+                },
+                _ => {
+                    ops[key] = Lifecycle::Ignored(Box::new(move |res| drop(data.complete(res))));
+                    drop(ops);
+                    reactor.push(opcode::AsyncCancel::new(key as u64).build().user_data(CANCEL_KEY));
+                },
+            }
+        });
+    }
+}
+
+// Convert the result of an operation into an io::Result.
+fn into_result(res : i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    }
+    else {
+        Ok(res as usize)
+    }
+}
+
+// A future object that sleeps for a certain amount of time, backed by an
+// io_uring timeout operation.
+pub type Timeout = Op<TimeoutOp>;
+
+pub struct TimeoutOp {
+    timespec : Box<types::Timespec>,
+}
+
+impl Timeout {
+    pub fn new(duration : Duration) -> Self {
+        Op::submit(TimeoutOp {
+            timespec : Box::new(types::Timespec::from(duration)),
+        })
+    }
+}
+
+impl Completable for TimeoutOp {
+    type Output = ();
+
+    fn entry(&mut self) -> squeue::Entry {
+        opcode::Timeout::new(&*self.timespec).build()
+    }
+
+    // A timeout operation completes with ETIME when the timer expires.
+    fn complete(self, _res : i32) {}
+}
+
+// A TCP listener. The socket is shared with the in-flight operations, so
+// that the file descriptor is not closed while the kernel still uses it.
+pub struct TcpListener {
+    inner : Arc<net::TcpListener>,
+}
+
+impl TcpListener {
+    pub fn bind<A : ToSocketAddrs>(addr : A) -> io::Result<Self> {
+        let inner = net::TcpListener::bind(addr)?;
+        Ok(TcpListener { inner : Arc::new(inner) })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    // Accept a new incoming connection.
+    pub fn accept(&self) -> Op<AcceptOp> {
+        Op::submit(AcceptOp {
+            listener : self.inner.clone(),
+            addr : Box::new((unsafe { mem::zeroed() }, mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t)),
+        })
+    }
+}
+
+pub struct AcceptOp {
+    listener : Arc<net::TcpListener>,
+    addr : Box<(libc::sockaddr_storage, libc::socklen_t)>,
+}
+
+impl Completable for AcceptOp {
+    type Output = io::Result<(TcpStream, SocketAddr)>;
+
+    fn entry(&mut self) -> squeue::Entry {
+        let (storage, len) = &mut *self.addr;
+        opcode::Accept::new(types::Fd(self.listener.as_raw_fd()), storage as *mut _ as *mut libc::sockaddr, len)
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+    }
+
+    fn complete(self, res : i32) -> Self::Output {
+        let fd = into_result(res)?;
+        // Take the ownership of the accepted socket first, so that it is
+        // closed if the address can not be parsed.
+        let stream = TcpStream { inner : Arc::new(unsafe { net::TcpStream::from_raw_fd(fd as i32) }) };
+        let addr = to_socket_addr(&self.addr.0)?;
+        Ok((stream, addr))
+    }
+}
+
+// Convert the address filled in by the kernel into a SocketAddr.
+fn to_socket_addr(storage : &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported address family")),
+    }
+}
+
+// A TCP stream, whose socket is shared with the in-flight operations.
+pub struct TcpStream {
+    inner : Arc<net::TcpStream>,
+}
+
+impl TcpStream {
+    // Read some bytes from the stream into the owned buffer. The bytes
+    // are read into the whole capacity of the buffer, and the buffer is
+    // handed back with its length set to the number of bytes read. A
+    // returned length of 0 indicates EOF.
+    pub fn read(&self, buf : Vec<u8>) -> Op<ReadOp> {
+        Op::submit(ReadOp {
+            stream : self.inner.clone(),
+            buf,
+        })
+    }
+
+    // Write some bytes in the owned buffer into the stream. The future
+    // resolves to the number of bytes written, which may be smaller than
+    // the length of buf, and hands back the buffer.
+    pub fn write(&self, buf : Vec<u8>) -> Op<WriteOp> {
+        Op::submit(WriteOp {
+            stream : self.inner.clone(),
+            buf,
+        })
+    }
+}
+
+pub struct ReadOp {
+    stream : Arc<net::TcpStream>,
+    buf : Vec<u8>,
+}
+
+impl Completable for ReadOp {
+    type Output = (io::Result<usize>, Vec<u8>);
+
+    fn entry(&mut self) -> squeue::Entry {
+        let len = self.buf.capacity().min(u32::MAX as usize) as u32;
+        opcode::Recv::new(types::Fd(self.stream.as_raw_fd()), self.buf.as_mut_ptr(), len).build()
+    }
+
+    fn complete(mut self, res : i32) -> Self::Output {
+        let res = into_result(res);
+        // The kernel has initialized the first n bytes of the buffer.
+        let n = *res.as_ref().unwrap_or(&0);
+        unsafe { self.buf.set_len(n) };
+        (res, self.buf)
+    }
+}
+
+pub struct WriteOp {
+    stream : Arc<net::TcpStream>,
+    buf : Vec<u8>,
+}
+
+impl Completable for WriteOp {
+    type Output = (io::Result<usize>, Vec<u8>);
+
+    fn entry(&mut self) -> squeue::Entry {
+        let len = self.buf.len().min(u32::MAX as usize) as u32;
+        opcode::Send::new(types::Fd(self.stream.as_raw_fd()), self.buf.as_ptr(), len).build()
+    }
+
+    fn complete(self, res : i32) -> Self::Output {
+        (into_result(res), self.buf)
+    }
+}
+
+// The entry point of the async eventloop.
+pub fn run<F : Future<Output = ()> + 'static + Send>(f : F) {
+    REACTOR.with(|reactor| {
+        reactor.run(f);
+    });
+}
+
+// Spawning a new Future task inside the eventloop.
+pub fn spawn<F : Future<Output = ()> + 'static + Send>(f : F) {
+    REACTOR.with(|reactor| {
+        reactor.do_spawn(f);
+    });
+}
+
+// Echo everything received from the stream back to the peer, until
+// the peer closes the connection. The same buffer is passed back and
+// forth between the reads and the writes.
+pub(crate) async fn echo(stream : TcpStream) {
+    let mut buf = Vec::with_capacity(4096);
+    loop {
+        let (res, read_buf) = stream.read(buf).await;
+        buf = read_buf;
+        match res {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {},
+        }
+        // A single write may not send all the bytes out. The bytes
+        // written are drained, and the rest are written again.
+        while !buf.is_empty() {
+            let (res, write_buf) = stream.write(buf).await;
+            buf = write_buf;
+            match res {
+                Ok(m) => { buf.drain(..m); },
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+async fn echo_server(addr : &'static str) {
+    let listener = TcpListener::bind(addr).unwrap();
+    println!("echo server listens on {}", listener.local_addr().unwrap());
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                println!("accept a new connection from {}", peer);
+                spawn(echo(stream));
+            },
+            Err(e) => println!("fail to accept: {}", e),
+        }
+    }
+}
+
+async fn heartbeat_task() {
+    loop {
+        Timeout::new(Duration::from_secs(5)).await;
+        println!("the echo server is alive");
+    }
+}
+
+pub fn launch() {
+    run(async {
+        spawn(heartbeat_task());
+        echo_server("127.0.0.1:10241").await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read as _, Write as _};
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn echo_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(addr).unwrap();
+            let payload : Vec<u8> = (0..256 * 1024u32).map(|i| i as u8).collect();
+            stream.write_all(&payload).unwrap();
+            stream.shutdown(net::Shutdown::Write).unwrap();
+            let mut echoed = Vec::new();
+            stream.read_to_end(&mut echoed).unwrap();
+            echoed == payload
+        });
+
+        run(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            echo(stream).await;
+        });
+        assert!(client.join().unwrap());
+    }
+
+    #[test]
+    fn dropped_accept_is_cancelled() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let start = Instant::now();
+
+        run(async move {
+            // The accept is submitted, then dropped when the timeout wins.
+            let mut accept = listener.accept();
+            let waker = futures_task::noop_waker();
+            assert!(Pin::new(&mut accept).poll(&mut Context::from_waker(&waker)).is_pending());
+            drop(accept);
+            Timeout::new(Duration::from_millis(20)).await;
+        });
+        // The run returns once the cancelled accept completes.
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        REACTOR.with(|reactor| assert!(reactor.ops.borrow().is_empty()));
+    }
+}
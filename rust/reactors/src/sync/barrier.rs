@@ -0,0 +1,147 @@
+use std::task::{Waker, Context, Poll};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::future::Future;
+use std::sync;
+use crate::reactor2::coop;
+
+// A barrier that releases a group of tasks once all of them have arrived.
+
+// The barrier counts the tasks waiting in the current generation. The task
+// that brings the count to n is the leader: it starts a new generation and
+// wakes up all the other tasks. A task dropped while waiting leaves the
+// group, so the remaining tasks keep waiting for a new arrival instead of
+// being released one task short.
+
+// The state of the Barrier.
+// generation : Incremented every time the group is released.
+// waiters : The IDs and wakers of the tasks waiting in the current generation.
+// next_id : A counter that is used to generate unique waiter IDs.
+struct Inner {
+    generation : u64,
+    waiters : VecDeque<(u64, Waker)>,
+    next_id : u64,
+}
+
+pub struct Barrier {
+    n : usize,
+    inner : sync::Mutex<Inner>,
+}
+
+// The result of Barrier::wait. Exactly one task in each generation is
+// the leader.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    pub is_leader : bool,
+}
+
+impl Barrier {
+    pub fn new(n : usize) -> Self {
+        assert!(n > 0, "a barrier needs at least one task");
+        Barrier {
+            n,
+            inner : sync::Mutex::new(Inner {
+                generation : 0,
+                waiters : VecDeque::new(),
+                next_id : 0,
+            }),
+        }
+    }
+
+    // Wait until n tasks have called wait.
+    pub fn wait(&self) -> Wait<'_> {
+        Wait {
+            barrier : self,
+            waiting : None,
+        }
+    }
+}
+
+// The future returned by Barrier::wait.
+// waiting : The ID of this future and the generation that it waits in.
+pub struct Wait<'a> {
+    barrier : &'a Barrier,
+    waiting : Option<(u64, u64)>,
+}
+
+impl Future for Wait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let self_mut = self.get_mut();
+        let wakers = {
+            let mut inner = self_mut.barrier.inner.lock().unwrap();
+            if let Some((id, generation)) = self_mut.waiting {
+                if inner.generation != generation {
+                    // The group has been released by the leader.
+                    self_mut.waiting = None;
+                    return Poll::Ready(BarrierWaitResult { is_leader : false });
+                }
+                let waiter = inner.waiters.iter_mut().find(|(waiter, _)| *waiter == id).unwrap();
+                waiter.1 = ctx.waker().clone();
+                return Poll::Pending;
+            }
+
+            if inner.waiters.len() + 1 < self_mut.barrier.n {
+                let id = inner.next_id;
+                inner.next_id += 1;
+                inner.waiters.push_back((id, ctx.waker().clone()));
+                self_mut.waiting = Some((id, inner.generation));
+                return Poll::Pending;
+            }
+
+            // This task is the last one to arrive, release the group.
+            inner.generation += 1;
+            std::mem::take(&mut inner.waiters)
+        };
+        for (_, waker) in wakers {
+            waker.wake();
+        }
+        Poll::Ready(BarrierWaitResult { is_leader : true })
+    }
+}
+
+impl Drop for Wait<'_> {
+    // A task dropped before the group is released leaves the group.
+    fn drop(&mut self) {
+        if let Some((id, generation)) = self.waiting {
+            let mut inner = self.barrier.inner.lock().unwrap();
+            if inner.generation == generation {
+                inner.waiters.retain(|(waiter, _)| *waiter != id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::flag_waker;
+
+    #[test]
+    fn releases_the_group_with_one_leader() {
+        let barrier = Barrier::new(3);
+        let (flag, waker) = flag_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        let mut first = barrier.wait();
+        let mut dropped = barrier.wait();
+        assert!(Pin::new(&mut first).poll(&mut ctx).is_pending());
+        assert!(Pin::new(&mut dropped).poll(&mut ctx).is_pending());
+
+        // The dropped task leaves the group, so one more task is needed.
+        drop(dropped);
+        let mut second = barrier.wait();
+        assert!(Pin::new(&mut second).poll(&mut ctx).is_pending());
+        assert!(!flag.take());
+
+        let res = Pin::new(&mut barrier.wait()).poll(&mut ctx);
+        assert_eq!(res, Poll::Ready(BarrierWaitResult { is_leader : true }));
+        assert!(flag.take());
+        assert_eq!(Pin::new(&mut first).poll(&mut ctx), Poll::Ready(BarrierWaitResult { is_leader : false }));
+        assert_eq!(Pin::new(&mut second).poll(&mut ctx), Poll::Ready(BarrierWaitResult { is_leader : false }));
+    }
+}
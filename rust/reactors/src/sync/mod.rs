@@ -0,0 +1,21 @@
+// Async synchronization primitives for coordinating the tasks of a reactor.
+
+// Like the channels, the primitives only rely on the Waker passed in through
+// the Context, so they work with any of the reactors in this crate. Tasks
+// waiting on a primitive are queued in FIFO order. Every waiting future
+// removes itself from the queue when it is dropped, and if it has already
+// been handed a permit or a notification, the permit or the notification
+// is passed on to the next waiter, so that no waiter is left hanging.
+// Semaphore : A counting semaphore that hands permits to waiters in FIFO order.
+// Mutex : A fair async mutex built on a Semaphore with a single permit.
+// Notify : Wakes up a single waiting task or all the waiting tasks.
+// Barrier : Releases a group of tasks once all of them have arrived.
+pub mod semaphore;
+pub mod mutex;
+pub mod notify;
+pub mod barrier;
+
+pub use semaphore::Semaphore;
+pub use mutex::Mutex;
+pub use notify::Notify;
+pub use barrier::Barrier;
@@ -0,0 +1,146 @@
+use std::task::{Context, Poll};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::future::Future;
+use super::semaphore::{Semaphore, SemaphorePermit, Acquire};
+
+// A fair async mutex.
+
+// The mutex is a Semaphore with a single permit guarding the data. Since
+// the semaphore hands the permit over to the waiters in FIFO order, the
+// tasks get the lock in the order in which they start waiting. Unlike
+// std::sync::Mutex, waiting for the lock suspends the task instead of
+// blocking the thread, so the guard can be held across an .await.
+pub struct Mutex<T : ?Sized> {
+    semaphore : Semaphore,
+    data : UnsafeCell<T>,
+}
+
+// The data is only accessed through the guard, and the semaphore makes
+// sure there is at most one guard at a time.
+unsafe impl<T : ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T : ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(data : T) -> Self {
+        Mutex {
+            semaphore : Semaphore::new(1),
+            data : UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T : ?Sized> Mutex<T> {
+    // Lock the mutex. The returned future resolves to a guard once the
+    // lock is acquired.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex : self,
+            acquire : self.semaphore.acquire(),
+        }
+    }
+}
+
+// The future returned by Mutex::lock. Dropping the future while waiting
+// leaves the waiter queue of the semaphore.
+pub struct Lock<'a, T : ?Sized> {
+    mutex : &'a Mutex<T>,
+    acquire : Acquire<'a>,
+}
+
+impl<'a, T : ?Sized> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        let self_mut = self.get_mut();
+        let mutex = self_mut.mutex;
+        Pin::new(&mut self_mut.acquire).poll(ctx).map(|permit| MutexGuard {
+            mutex,
+            _permit : permit,
+        })
+    }
+}
+
+// The guard of a locked Mutex, the lock is released when the guard is dropped.
+pub struct MutexGuard<'a, T : ?Sized> {
+    mutex : &'a Mutex<T>,
+    _permit : SemaphorePermit<'a>,
+}
+
+impl<T : ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T : ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::flag_waker;
+
+    fn poll_lock<'a, T>(lock : &mut Lock<'a, T>, ctx : &mut Context<'_>) -> Option<MutexGuard<'a, T>> {
+        match Pin::new(lock).poll(ctx) {
+            Poll::Ready(guard) => Some(guard),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn lock_is_granted_in_fifo_order() {
+        let mutex = Mutex::new(Vec::new());
+        let (first_flag, first_waker) = flag_waker();
+        let (second_flag, second_waker) = flag_waker();
+        let mut first_ctx = Context::from_waker(&first_waker);
+        let mut second_ctx = Context::from_waker(&second_waker);
+
+        let mut guard = poll_lock(&mut mutex.lock(), &mut first_ctx).unwrap();
+        guard.push(0);
+
+        let mut first = mutex.lock();
+        let mut second = mutex.lock();
+        assert!(poll_lock(&mut first, &mut first_ctx).is_none());
+        assert!(poll_lock(&mut second, &mut second_ctx).is_none());
+
+        drop(guard);
+        assert!(first_flag.take());
+        assert!(!second_flag.take());
+        // A new locker can not barge in front of the waiting ones.
+        assert!(poll_lock(&mut mutex.lock(), &mut second_ctx).is_none());
+
+        poll_lock(&mut first, &mut first_ctx).unwrap().push(1);
+        assert!(second_flag.take());
+        poll_lock(&mut second, &mut second_ctx).unwrap().push(2);
+        drop(first);
+        drop(second);
+        assert_eq!(*poll_lock(&mut mutex.lock(), &mut first_ctx).unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn dropped_waiter_passes_on_the_lock() {
+        let mutex = Mutex::new(());
+        let (flag, waker) = flag_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        let guard = poll_lock(&mut mutex.lock(), &mut ctx).unwrap();
+        let mut first = mutex.lock();
+        let mut second = mutex.lock();
+        assert!(poll_lock(&mut first, &mut ctx).is_none());
+        assert!(poll_lock(&mut second, &mut ctx).is_none());
+
+        // The first waiter is granted the lock but dropped before using it.
+        drop(guard);
+        assert!(flag.take());
+        drop(first);
+        assert!(flag.take());
+        assert!(poll_lock(&mut second, &mut ctx).is_some());
+    }
+}
@@ -0,0 +1,242 @@
+use std::task::{Waker, Context, Poll};
+use std::collections::{VecDeque, HashMap};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync;
+use crate::reactor2::coop;
+
+// Notify a single waiting task, or all the waiting tasks.
+
+// notify_one wakes up the task at the front of the waiter queue. If no task
+// is waiting, a single permit is stored, and the next call to notified
+// completes immediately. notify_waiters wakes up all the waiting tasks and
+// does not store a permit.
+
+// How a waiter has been notified.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    None,
+    One,
+    All,
+}
+
+// A task waiting for a notification.
+struct Waiter {
+    waker : Option<Waker>,
+    notification : Notification,
+}
+
+// The state of the Notify.
+// permit : Whether a notify_one call has been made with no waiting task.
+// queue : The IDs of the waiters that have not been notified, in FIFO order.
+// waiters : The waiters indexed by their IDs.
+// next_id : A counter that is used to generate unique waiter IDs.
+struct Inner {
+    permit : bool,
+    queue : VecDeque<u64>,
+    waiters : HashMap<u64, Waiter>,
+    next_id : u64,
+}
+
+impl Inner {
+    // Notify the first waiting task, or store the permit if nobody is waiting.
+    fn notify_one(&mut self) -> Option<Waker> {
+        match self.queue.pop_front() {
+            Some(id) => {
+                let waiter = self.waiters.get_mut(&id).unwrap();
+                waiter.notification = Notification::One;
+                waiter.waker.take()
+            },
+            None => {
+                self.permit = true;
+                None
+            },
+        }
+    }
+}
+
+pub struct Notify {
+    inner : sync::Mutex<Inner>,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            inner : sync::Mutex::new(Inner {
+                permit : false,
+                queue : VecDeque::new(),
+                waiters : HashMap::new(),
+                next_id : 0,
+            }),
+        }
+    }
+
+    // Wait for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify : self,
+            id : None,
+        }
+    }
+
+    // Wake up the task waiting the longest, or store a permit for the
+    // next task calling notified.
+    pub fn notify_one(&self) {
+        let waker = self.inner.lock().unwrap().notify_one();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    // Wake up all the tasks that are currently waiting.
+    pub fn notify_waiters(&self) {
+        let wakers : Vec<_> = {
+            let mut inner = self.inner.lock().unwrap();
+            let queue = std::mem::take(&mut inner.queue);
+            queue.into_iter().filter_map(|id| {
+                let waiter = inner.waiters.get_mut(&id).unwrap();
+                waiter.notification = Notification::All;
+                waiter.waker.take()
+            }).collect()
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+// The future returned by Notify::notified.
+// id : The ID of this future in the waiter queue, if it is waiting.
+pub struct Notified<'a> {
+    notify : &'a Notify,
+    id : Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let self_mut = self.get_mut();
+        let mut inner = self_mut.notify.inner.lock().unwrap();
+        match self_mut.id {
+            None => {
+                // Consume the stored permit if there is one.
+                if inner.permit {
+                    inner.permit = false;
+                    return Poll::Ready(());
+                }
+                let id = inner.next_id;
+                inner.next_id += 1;
+                inner.waiters.insert(id, Waiter {
+                    waker : Some(ctx.waker().clone()),
+                    notification : Notification::None,
+                });
+                inner.queue.push_back(id);
+                self_mut.id = Some(id);
+                Poll::Pending
+            },
+            Some(id) => {
+                let waiter = inner.waiters.get_mut(&id).unwrap();
+                if waiter.notification == Notification::None {
+                    waiter.waker = Some(ctx.waker().clone());
+                    Poll::Pending
+                }
+                else {
+                    inner.waiters.remove(&id);
+                    self_mut.id = None;
+                    Poll::Ready(())
+                }
+            },
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    // A Notified future dropped while waiting leaves the queue. If it has
+    // received the notification of a notify_one call, the notification is
+    // passed on to the next waiter, so that it is not lost.
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let waker = {
+            let mut inner = self.notify.inner.lock().unwrap();
+            let waiter = inner.waiters.remove(&id).unwrap();
+            match waiter.notification {
+                Notification::None => {
+                    inner.queue.retain(|queued| *queued != id);
+                    None
+                },
+                Notification::One => inner.notify_one(),
+                Notification::All => None,
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::flag_waker;
+
+    #[test]
+    fn notify_one_wakes_in_fifo_order() {
+        let notify = Notify::new();
+        let (first_flag, first_waker) = flag_waker();
+        let (second_flag, second_waker) = flag_waker();
+        let mut first_ctx = Context::from_waker(&first_waker);
+        let mut second_ctx = Context::from_waker(&second_waker);
+
+        let mut first = notify.notified();
+        let mut second = notify.notified();
+        assert!(Pin::new(&mut first).poll(&mut first_ctx).is_pending());
+        assert!(Pin::new(&mut second).poll(&mut second_ctx).is_pending());
+
+        notify.notify_one();
+        assert!(first_flag.take());
+        assert!(!second_flag.take());
+
+        // The notified first waiter is dropped, the notification goes to
+        // the second waiter.
+        drop(first);
+        assert!(second_flag.take());
+        assert!(Pin::new(&mut second).poll(&mut second_ctx).is_ready());
+    }
+
+    #[test]
+    fn permit_and_notify_waiters() {
+        let notify = Notify::new();
+        let (flag, waker) = flag_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        // The permit of notify_one is stored for the next waiter.
+        notify.notify_one();
+        assert!(Pin::new(&mut notify.notified()).poll(&mut ctx).is_ready());
+        assert!(Pin::new(&mut notify.notified()).poll(&mut ctx).is_pending());
+
+        // notify_waiters does not store a permit.
+        let mut waiters = [notify.notified(), notify.notified()];
+        for waiter in waiters.iter_mut() {
+            assert!(Pin::new(waiter).poll(&mut ctx).is_pending());
+        }
+        notify.notify_waiters();
+        assert!(flag.take());
+        for waiter in waiters.iter_mut() {
+            assert!(Pin::new(waiter).poll(&mut ctx).is_ready());
+        }
+        assert!(Pin::new(&mut notify.notified()).poll(&mut ctx).is_pending());
+    }
+}
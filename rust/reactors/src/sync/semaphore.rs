@@ -0,0 +1,262 @@
+use std::task::{Waker, Context, Poll};
+use std::collections::{VecDeque, HashMap};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync;
+use crate::reactor2::coop;
+
+// A fair counting semaphore.
+
+// A task acquiring permits takes them right away only if nobody is waiting
+// in front of it. Otherwise it joins the back of the queue. Released permits
+// are handed over to the waiters at the front of the queue directly, instead
+// of being returned to the pool, so a newly arriving task can never barge in
+// front of a waiting one.
+
+// A task waiting for permits.
+// needed : The number of permits the task asks for.
+// waker : The waker of the waiting task.
+// granted : Whether the permits have been handed over to the task.
+struct Waiter {
+    needed : usize,
+    waker : Option<Waker>,
+    granted : bool,
+}
+
+// The state of the semaphore.
+// permits : The number of available permits.
+// queue : The IDs of the waiters, in FIFO order. A granted waiter is
+// removed from the queue, but stays in waiters until its future is polled.
+// waiters : The waiters indexed by their IDs.
+// next_id : A counter that is used to generate unique waiter IDs.
+struct Inner {
+    permits : usize,
+    queue : VecDeque<u64>,
+    waiters : HashMap<u64, Waiter>,
+    next_id : u64,
+}
+
+impl Inner {
+    // Hand over the available permits to the waiters at the front of the
+    // queue, and collect the wakers of the waiters that get their permits.
+    // A waiter asking for more permits than available blocks the waiters
+    // behind it, which keeps the semaphore fair.
+    fn assign(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        while let Some(id) = self.queue.front() {
+            let waiter = self.waiters.get_mut(id).unwrap();
+            if waiter.needed > self.permits {
+                break;
+            }
+            self.permits -= waiter.needed;
+            waiter.granted = true;
+            wakers.extend(waiter.waker.take());
+            self.queue.pop_front();
+        }
+        wakers
+    }
+}
+
+pub struct Semaphore {
+    inner : sync::Mutex<Inner>,
+}
+
+impl Semaphore {
+    pub fn new(permits : usize) -> Self {
+        Semaphore {
+            inner : sync::Mutex::new(Inner {
+                permits,
+                queue : VecDeque::new(),
+                waiters : HashMap::new(),
+                next_id : 0,
+            }),
+        }
+    }
+
+    // Acquire a single permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    // Acquire the given number of permits at once.
+    pub fn acquire_many(&self, needed : usize) -> Acquire<'_> {
+        Acquire {
+            semaphore : self,
+            needed,
+            id : None,
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.inner.lock().unwrap().permits
+    }
+
+    // Add permits to the semaphore, waking up the waiters that can be
+    // satisfied in FIFO order.
+    pub fn release(&self, permits : usize) {
+        let wakers = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.permits += permits;
+            inner.assign()
+        };
+        // Wake up the waiters after releasing the lock.
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+// The permits acquired from a Semaphore, which are released on drop.
+pub struct SemaphorePermit<'a> {
+    semaphore : &'a Semaphore,
+    permits : usize,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release(self.permits);
+    }
+}
+
+// The future returned by Semaphore::acquire.
+// semaphore : The semaphore to acquire the permits from.
+// needed : The number of permits to acquire.
+// id : The ID of this future in the waiter queue, if it is waiting.
+pub struct Acquire<'a> {
+    semaphore : &'a Semaphore,
+    needed : usize,
+    id : Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self : Pin<&mut Self>, ctx : &mut Context<'_>) -> Poll<Self::Output> {
+        if coop::poll_proceed(ctx).is_pending() {
+            return Poll::Pending;
+        }
+        let self_mut = self.get_mut();
+        let mut inner = self_mut.semaphore.inner.lock().unwrap();
+        match self_mut.id {
+            None => {
+                // Take the permits right away if nobody is waiting.
+                if inner.queue.is_empty() && inner.permits >= self_mut.needed {
+                    inner.permits -= self_mut.needed;
+                    return Poll::Ready(SemaphorePermit {
+                        semaphore : self_mut.semaphore,
+                        permits : self_mut.needed,
+                    });
+                }
+                // Otherwise join the back of the queue.
+                let id = inner.next_id;
+                inner.next_id += 1;
+                inner.waiters.insert(id, Waiter {
+                    needed : self_mut.needed,
+                    waker : Some(ctx.waker().clone()),
+                    granted : false,
+                });
+                inner.queue.push_back(id);
+                self_mut.id = Some(id);
+                Poll::Pending
+            },
+            Some(id) => {
+                let waiter = inner.waiters.get_mut(&id).unwrap();
+                if waiter.granted {
+                    inner.waiters.remove(&id);
+                    self_mut.id = None;
+                    Poll::Ready(SemaphorePermit {
+                        semaphore : self_mut.semaphore,
+                        permits : self_mut.needed,
+                    })
+                }
+                else {
+                    waiter.waker = Some(ctx.waker().clone());
+                    Poll::Pending
+                }
+            },
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    // An Acquire future dropped while waiting leaves the queue. If the
+    // permits have already been handed over to it, they are returned and
+    // handed over to the next waiters. Even if the future has not been
+    // granted, it may have blocked the waiters behind it by asking for
+    // too many permits, so the permits are assigned again in both cases.
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let wakers = {
+            let mut inner = self.semaphore.inner.lock().unwrap();
+            let waiter = inner.waiters.remove(&id).unwrap();
+            if waiter.granted {
+                inner.permits += waiter.needed;
+            }
+            else {
+                inner.queue.retain(|queued| *queued != id);
+            }
+            inner.assign()
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::flag_waker;
+
+    #[test]
+    fn permits_are_handed_over_in_fifo_order() {
+        let semaphore = Semaphore::new(2);
+        let (first_flag, first_waker) = flag_waker();
+        let (second_flag, second_waker) = flag_waker();
+        let mut first_ctx = Context::from_waker(&first_waker);
+        let mut second_ctx = Context::from_waker(&second_waker);
+
+        let mut held = semaphore.acquire_many(2);
+        let held = match Pin::new(&mut held).poll(&mut first_ctx) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("the permits should be available"),
+        };
+
+        // The first waiter asks for 2 permits and blocks the second one.
+        let mut first = semaphore.acquire_many(2);
+        let mut second = semaphore.acquire();
+        assert!(Pin::new(&mut first).poll(&mut first_ctx).is_pending());
+        assert!(Pin::new(&mut second).poll(&mut second_ctx).is_pending());
+
+        drop(held);
+        assert!(first_flag.take());
+        assert!(!second_flag.take());
+        assert_eq!(semaphore.available_permits(), 0);
+
+        // Dropping the granted first waiter returns its permits.
+        drop(first);
+        assert!(second_flag.take());
+        let permit = Pin::new(&mut second).poll(&mut second_ctx);
+        assert!(permit.is_ready());
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn dropped_waiter_unblocks_the_queue() {
+        let semaphore = Semaphore::new(1);
+        let (flag, waker) = flag_waker();
+        let mut ctx = Context::from_waker(&waker);
+
+        let mut greedy = semaphore.acquire_many(2);
+        let mut modest = semaphore.acquire();
+        assert!(Pin::new(&mut greedy).poll(&mut ctx).is_pending());
+        assert!(Pin::new(&mut modest).poll(&mut ctx).is_pending());
+
+        drop(greedy);
+        assert!(flag.take());
+        assert!(Pin::new(&mut modest).poll(&mut ctx).is_ready());
+    }
+}
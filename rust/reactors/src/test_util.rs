@@ -0,0 +1,42 @@
+// Helpers shared by the unit tests of the reactors and the async primitives.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::Waker;
+use futures_task::ArcWake;
+
+// A waker that records whether it has been woken up.
+pub struct FlagWaker(AtomicBool);
+
+impl ArcWake for FlagWaker {
+    fn wake_by_ref(arc_self : &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn flag_waker() -> (Arc<FlagWaker>, Waker) {
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = futures_task::waker(flag.clone());
+    (flag, waker)
+}
+
+impl FlagWaker {
+    // Whether the waker has been woken up since the last call.
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+// Set the flag when dropped, to check that a future or a task is dropped.
+pub struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn drop_flag() -> (Arc<AtomicBool>, DropFlag) {
+    let dropped = Arc::new(AtomicBool::new(false));
+    (dropped.clone(), DropFlag(dropped))
+}